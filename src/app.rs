@@ -1,6 +1,19 @@
 use tui_textarea::TextArea;
-use crate::network::DownloadFormat;
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::db::{IfExists, ImportControl, ImportProgress, SpatialStatus, TrackedRegion};
+use crate::files::{self, Checksum, CleanRule, FileList, LocalFile, PendingDeletion};
+use crate::network::{self, DownloadControl, DownloadEvent, DownloadFormat};
+use crate::provider::{self, Provider};
+use crate::queue::{DownloadQueue, Job, JobId, JobState};
+use crate::region_tree::RegionTree;
+use crate::result_view::ResultView;
+use crate::results::QueryResult;
+use crate::ui::format_bytes;
+use crate::worker::{TaskHandle, WorkerStatus};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 // #[derive(Debug, PartialEq, Clone, Copy)]
 // pub enum InputMode {
@@ -8,18 +21,68 @@ use std::path::PathBuf;
 //     Editing,
 // }
 
+/// What the background tasks report to the event loop.
+#[derive(Debug)]
+pub enum AppEvent {
+    Download(DownloadEvent),
+    ImportStarted(JobId),
+    ImportProgress(JobId, ImportProgress),
+    ImportFinished(JobId, String, String), // Table to preview, message
+    ImportFailed(JobId, String), // Error message
+    ImportCancelled(JobId), // Stopped by the user and rolled back
+    CatalogLoaded(Box<Catalog>),
+    CatalogFailed(String), // Error message
+    RemoteSize(String, Option<u64>), // URL, size in bytes if the server reported one
+    QueryFinished(u64, String, Result<(QueryResult, Option<usize>), String>), // Task id, SQL, first page and row count
+    PageLoaded(String, Result<QueryResult, String>), // SQL, rows around the cursor
+    Exported(PathBuf, usize),        // File written, number of rows
+    ExportFailed(PathBuf, String),   // File that was to be written, error message
+    TrackedRegions(Result<Vec<TrackedRegion>, String>), // Loaded for the refresh scheduler
+    FilesListed(Result<Vec<LocalFile>, String>), // Contents of the download directory
+    FileVerified(PathBuf, Result<Checksum, String>),
+    FileImported(PathBuf, Result<String, String>), // Message or error of a re-import
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ActiveTab {
     Download,
     Database,
+    Files,
+}
+
+impl ActiveTab {
+    /// The tab `Ctrl+b` switches to.
+    pub fn next(self) -> Self {
+        match self {
+            ActiveTab::Download => ActiveTab::Database,
+            ActiveTab::Database => ActiveTab::Files,
+            ActiveTab::Files => ActiveTab::Download,
+        }
+    }
+}
+
+/// Which pane of the Database tab takes the keys.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DatabaseFocus {
+    Editor,
+    Results,
+}
+
+/// A query from the Database tab the database worker hasn't answered yet.
+pub struct PendingQuery {
+    pub handle: TaskHandle,
+    /// Whether the results take the focus when they arrive (not for previews).
+    pub focus_results: bool,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum FocusField {
+    Tree,
     Continent,
     Country,
     Region,
     Format,
+    Queue,
 }
 
 pub struct App<'a> {
@@ -29,16 +92,53 @@ pub struct App<'a> {
     pub focus_field: FocusField,
     
     pub download_format: DownloadFormat,
-    pub download_progress: f64,
-    pub is_downloading: bool,
+    pub queue: DownloadQueue,
+    /// Cancellation handles of the jobs that are currently transferring.
+    pub download_controls: HashMap<JobId, DownloadControl>,
+    /// Cancellation handles of the jobs that are being imported.
+    pub import_controls: HashMap<JobId, ImportControl>,
     pub last_downloaded_path: Option<PathBuf>,
-    pub download_status_text: String,
+    pub download_dir: PathBuf,
+    /// What an auto-import does when its region's table already exists.
+    pub if_exists: IfExists,
+    /// Whether geometries, layers and shapefile imports are available.
+    pub spatial: SpatialStatus,
+    /// Configured extract providers; downloads fall back through their mirrors.
+    pub providers: Vec<Provider>,
+    /// Geofabrik region index; `None` until loaded (or if it failed to load).
+    pub catalog: Option<Catalog>,
+    pub region_tree: RegionTree,
+    /// Extract sizes from `HEAD` requests, keyed by URL; `None` while unknown.
+    pub remote_sizes: HashMap<String, Option<u64>>,
+    /// Regions refreshed in the background, as of the last check.
+    pub tracked: Vec<TrackedRegion>,
+    /// Whether a check of the tracked regions waits for the database worker.
+    pub checking_tracked: bool,
+    /// Refresh interval of regions tracked with `t`.
+    pub refresh_interval_hours: u32,
 
     pub active_tab: ActiveTab,
     
     // Database Terminal
     pub sql_input: TextArea<'a>,
+    /// Shown instead of a result table: errors and other messages.
     pub sql_output: String,
+    /// Result of the last query, if it succeeded.
+    pub result_view: Option<ResultView>,
+    pub database_focus: DatabaseFocus,
+    pub pending_query: Option<PendingQuery>,
+    pub query_timeout: Option<Duration>,
+    /// What the database worker is busy with, refreshed before every frame.
+    pub db_status: WorkerStatus,
+    /// File name prompt of an export of the current query, while it is open.
+    pub export_input: Option<TextArea<'a>>,
+
+    // Files tab
+    pub files: FileList,
+    /// Age and quota prompt of a clean-up, while it is open.
+    pub clean_input: Option<TextArea<'a>>,
+    /// Files to delete once the user says yes.
+    pub pending_deletion: Option<PendingDeletion>,
     #[allow(dead_code)]
     pub sql_history: Vec<String>,
     
@@ -48,7 +148,7 @@ pub struct App<'a> {
 }
 
 impl<'a> App<'a> {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         let mut continent = TextArea::default();
         continent.set_placeholder_text("e.g. Asia");
         continent.set_block(ratatui::widgets::Block::default().borders(ratatui::widgets::Borders::ALL).title("Continent"));
@@ -65,19 +165,41 @@ impl<'a> App<'a> {
         sql.set_placeholder_text("SELECT * FROM downloads;");
         sql.set_block(ratatui::widgets::Block::default().borders(ratatui::widgets::Borders::ALL).title("SQL Query"));
 
+        let mut queue = DownloadQueue::default();
+        queue.set_max_concurrent(config.downloads.max_concurrent);
+
         Self {
             input_continent: continent,
             input_country: country,
             input_region: region,
-            focus_field: FocusField::Continent,
-            download_format: DownloadFormat::Pbf,
-            download_progress: 0.0,
-            is_downloading: false,
+            focus_field: FocusField::Tree,
+            download_format: config.downloads.default_format.clone(),
+            queue,
+            download_controls: HashMap::new(),
+            import_controls: HashMap::new(),
             last_downloaded_path: None,
-            download_status_text: String::from("Ready"),
+            download_dir: config.paths.download_dir.clone(),
+            if_exists: config.imports.if_exists,
+            spatial: SpatialStatus::Unavailable("not loaded".to_string()),
+            providers: config.providers.clone(),
+            catalog: None,
+            region_tree: RegionTree::default(),
+            remote_sizes: HashMap::new(),
+            tracked: Vec::new(),
+            checking_tracked: false,
+            refresh_interval_hours: config.refresh.interval_hours,
             active_tab: ActiveTab::Download,
             sql_input: sql,
             sql_output: String::from("Ready to query."),
+            result_view: None,
+            database_focus: DatabaseFocus::Editor,
+            pending_query: None,
+            query_timeout: config.query_timeout(),
+            db_status: WorkerStatus::default(),
+            export_input: None,
+            files: FileList::default(),
+            clean_input: None,
+            pending_deletion: None,
             sql_history: vec![],
             should_quit: false,
             logs: vec![],
@@ -88,13 +210,240 @@ impl<'a> App<'a> {
 
     pub fn next_focus(&mut self) {
         self.focus_field = match self.focus_field {
+            FocusField::Tree => FocusField::Continent,
             FocusField::Continent => FocusField::Country,
             FocusField::Country => FocusField::Region,
             FocusField::Region => FocusField::Format,
-            FocusField::Format => FocusField::Continent,
+            FocusField::Format => FocusField::Queue,
+            FocusField::Queue => FocusField::Tree,
         };
     }
 
+    /// Turns the continent/country/region inputs into a download URL, using the
+    /// region index when available and falling back to guessing the URL layout of
+    /// the first provider.
+    pub fn resolve_download_url(
+        &self,
+        continent: &str,
+        country: &str,
+        region: &str,
+    ) -> anyhow::Result<String> {
+        let Some(catalog) = &self.catalog else {
+            let provider = &self.providers[0];
+            return Ok(provider.construct_url(continent, country, region, &self.download_format));
+        };
+        let path: Vec<&str> = [continent, country, region]
+            .into_iter()
+            .filter(|s| !s.trim().is_empty())
+            .collect();
+        let found = catalog.resolve(&path.join("/"))?;
+        found
+            .url(&self.download_format)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("{} has no {:?} extract", found.name, self.download_format))
+    }
+
+    /// Download URLs of the region highlighted in the browser whose size hasn't
+    /// been requested yet. They are marked as pending so each is only fetched once.
+    pub fn take_unsized_urls(&mut self) -> Vec<String> {
+        let Some(region) = self.catalog.as_ref().and_then(|c| self.region_tree.selected_region(c)) else {
+            return vec![];
+        };
+        let urls: Vec<String> = [&region.urls.pbf, &region.urls.shp]
+            .into_iter()
+            .flatten()
+            .filter(|url| !self.remote_sizes.contains_key(*url))
+            .cloned()
+            .collect();
+        for url in &urls {
+            self.remote_sizes.insert(url.clone(), None);
+        }
+        urls
+    }
+
+    /// The tracked region `job` downloads, if any.
+    pub fn tracked_region(&self, job: &Job) -> Option<&TrackedRegion> {
+        self.tracked
+            .iter()
+            .find(|t| t.region == job.name && t.format == job.format.short_name())
+    }
+
+    /// Queues a download of each tracked region that is due. Regions with a job
+    /// started within their interval are left alone, as the check may have been
+    /// answered before that download was recorded.
+    pub fn queue_due_refreshes(&mut self) {
+        let due: Vec<TrackedRegion> = self.tracked.iter().filter(|t| t.due).cloned().collect();
+        for region in due {
+            let interval = Duration::from_secs(u64::from(region.interval_hours) * 3600);
+            if self.queue.has_recent(&region.url, interval) {
+                continue;
+            }
+            let Some(format) = DownloadFormat::parse(&region.format) else {
+                continue;
+            };
+            match self.queue.push(region.region.clone(), region.url, format) {
+                Ok(id) => {
+                    if let Some(job) = self.queue.get_mut(id) {
+                        job.table = Some(region.table);
+                    }
+                    self.add_log(format!("Queued job #{}: refresh of tracked region {}", id, region.region));
+                }
+                Err(e) => self.add_log(format!("Error: {}", e)),
+            }
+        }
+    }
+
+    /// Pauses or resumes the selected job. Running jobs are stopped with their
+    /// `.part` file kept, so resuming continues where the transfer left off.
+    pub fn toggle_pause_selected_job(&mut self) {
+        match self.queue.selected_job().map(|j| (j.id, j.state.clone())) {
+            Some((id, JobState::Active)) => {
+                if let Some(control) = self.download_controls.get(&id) {
+                    control.pause();
+                }
+            }
+            _ => self.queue.toggle_pause_selected(),
+        }
+    }
+
+    /// Marks a downloaded job as importing, cancellable through `control`.
+    pub fn start_import(&mut self, id: JobId, control: ImportControl) {
+        if let Some(job) = self.queue.get_mut(id) {
+            job.state = JobState::Importing;
+            job.import = Some(Default::default());
+        }
+        self.import_controls.insert(id, control);
+    }
+
+    pub fn finish_import(&mut self, id: JobId, state: JobState) {
+        self.import_controls.remove(&id);
+        if let Some(job) = self.queue.get_mut(id) {
+            job.state = state;
+            job.import = None;
+        }
+    }
+
+    /// Cancels the selected job, stopping the transfer or import if it is running.
+    /// A paused transfer's partial files are removed.
+    pub fn cancel_selected_job(&mut self) -> bool {
+        match self.queue.selected_job().map(|j| (j.id, j.state.clone())) {
+            Some((id, JobState::Importing)) => match self.import_controls.get(&id) {
+                Some(control) => {
+                    control.cancel();
+                    true
+                }
+                None => false,
+            },
+            Some((id, JobState::Active | JobState::Verifying)) => match self.download_controls.get(&id) {
+                Some(control) => {
+                    control.cancel();
+                    true
+                }
+                None => false,
+            },
+            Some((_, JobState::Paused)) => {
+                if let Some(job) = self.queue.selected_job() {
+                    let urls = provider::mirror_urls(&self.providers, &job.url);
+                    network::discard_partial_files(&urls, &self.download_dir);
+                }
+                self.queue.cancel_selected()
+            }
+            _ => self.queue.cancel_selected(),
+        }
+    }
+
+    /// Records whether the spatial extension loaded, explaining what's disabled if not.
+    pub fn set_spatial(&mut self, spatial: SpatialStatus) {
+        if let SpatialStatus::Unavailable(reason) = &spatial {
+            self.sql_output = format!(
+                "Ready to query.\n\nThe spatial extension isn't available, so ST_* functions don't work, \
+                 PBF imports have no geometries or layers and shapefiles can't be imported.\n\
+                 Set [spatial] extension in the config to a spatial.duckdb_extension file to fix this.\n\n{}",
+                reason
+            );
+            self.add_log("Spatial extension unavailable: PBF imports only, without geometries.".to_string());
+        }
+        self.spatial = spatial;
+    }
+
+    pub fn has_spatial(&self) -> bool {
+        matches!(self.spatial, SpatialStatus::Loaded(_))
+    }
+
+    /// The query an export writes: the one whose result is shown, or else the
+    /// one in the editor.
+    pub fn export_sql(&self) -> String {
+        match &self.result_view {
+            Some(view) => view.sql.clone(),
+            None => self.sql_input.lines().join("\n"),
+        }
+    }
+
+    /// Opens the export prompt with a file name in the download directory.
+    pub fn open_export_prompt(&mut self) {
+        let mut input = TextArea::default();
+        input.insert_str(self.download_dir.join("export.csv").to_string_lossy());
+        self.export_input = Some(input);
+    }
+
+    /// Asks whether to delete the selected file.
+    pub fn confirm_delete_selected(&mut self) {
+        if let Some(file) = self.files.selected_file() {
+            self.pending_deletion = Some(PendingDeletion {
+                question: format!("Delete {} ({})?", file.name(), format_bytes(file.size)),
+                paths: vec![file.path.clone()],
+            });
+        }
+    }
+
+    pub fn open_clean_prompt(&mut self) {
+        let mut input = TextArea::default();
+        input.insert_str("30d");
+        self.clean_input = Some(input);
+    }
+
+    /// Asks whether to delete the files `rule` selects.
+    pub fn confirm_clean(&mut self, rule: CleanRule) {
+        let selected = rule.select(&self.files.files, SystemTime::now());
+        if selected.is_empty() {
+            self.add_log("Nothing to clean up.".to_string());
+            return;
+        }
+        let size = selected.iter().map(|f| f.size).sum();
+        self.pending_deletion = Some(PendingDeletion {
+            question: format!("Delete {} files ({}), oldest {}?", selected.len(), format_bytes(size), selected[0].name()),
+            paths: selected.iter().map(|f| f.path.clone()).collect(),
+        });
+    }
+
+    /// Deletes downloaded files, except those a job is still importing.
+    pub fn delete_files(&mut self, paths: Vec<PathBuf>) {
+        let mut deleted = 0;
+        for path in paths {
+            let importing = self
+                .queue
+                .jobs()
+                .iter()
+                .any(|j| j.state == JobState::Importing && j.path.as_ref() == Some(&path));
+            if importing {
+                self.add_log(format!("Not deleted, {} is being imported.", path.display()));
+                continue;
+            }
+            match files::delete(&path) {
+                Ok(()) => deleted += 1,
+                Err(e) => self.add_log(format!("Failed to delete {}: {}", path.display(), e)),
+            }
+        }
+        self.add_log(format!("Deleted {} files.", deleted));
+    }
+
+    /// Replaces the result table with a message, such as an error.
+    pub fn show_message(&mut self, msg: String) {
+        self.sql_output = msg;
+        self.result_view = None;
+        self.database_focus = DatabaseFocus::Editor;
+    }
+
     pub fn toggle_format(&mut self) {
         self.download_format = match self.download_format {
             DownloadFormat::Pbf => DownloadFormat::Shapefile,
@@ -109,3 +458,27 @@ impl<'a> App<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_paused_job_removes_partial_files() {
+        let (_, dir) = network::tests::stop_in_flight_download(true).await;
+        let part = dir.path().join("region-latest.osm.pbf.part");
+        assert!(part.exists());
+
+        let mut config = Config::default();
+        config.paths.download_dir = dir.path().to_path_buf();
+        let mut app = App::new(&config);
+        let url = "https://download.geofabrik.de/region-latest.osm.pbf".to_string();
+        let id = app.queue.push("region".to_string(), url, DownloadFormat::Pbf).unwrap();
+        app.queue.get_mut(id).unwrap().state = JobState::Paused;
+
+        assert!(app.cancel_selected_job());
+        assert_eq!(app.queue.jobs()[0].state, JobState::Cancelled);
+        assert!(!part.exists());
+        assert!(!dir.path().join("region-latest.osm.pbf.part.meta").exists());
+    }
+}
@@ -0,0 +1,470 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::network::{DownloadFormat, Downloader};
+
+/// How long a cached copy of the index is used before it is fetched again.
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One extract offered by a provider (a continent, country, subregion or city).
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub id: String,
+    pub parent: Option<String>,
+    pub name: String,
+    pub urls: RegionUrls,
+    #[allow(dead_code)]
+    pub geometry: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RegionUrls {
+    pub pbf: Option<String>,
+    pub shp: Option<String>,
+}
+
+impl Region {
+    pub fn url(&self, format: &DownloadFormat) -> Option<&str> {
+        match format {
+            DownloadFormat::Pbf => self.urls.pbf.as_deref(),
+            DownloadFormat::Shapefile => self.urls.shp.as_deref(),
+        }
+    }
+}
+
+/// The regions a provider offers (the Geofabrik `index-v1.json`, a BBBike listing or
+/// a local directory), used to turn what the user typed
+/// into an exact download URL instead of guessing the URL layout.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    regions: Vec<Region>,
+    by_id: HashMap<String, usize>,
+    /// Indices of each region's children sorted by name; top-level regions under `None`.
+    children: HashMap<Option<String>, Vec<usize>>,
+}
+
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    properties: Properties,
+    geometry: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Properties {
+    id: String,
+    parent: Option<String>,
+    name: String,
+    #[serde(default)]
+    urls: RegionUrls,
+}
+
+impl Catalog {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let collection: FeatureCollection =
+            serde_json::from_str(json).context("Invalid Geofabrik index")?;
+        let regions = collection
+            .features
+            .into_iter()
+            .map(|f| Region {
+                id: f.properties.id,
+                parent: f.properties.parent,
+                name: f.properties.name,
+                urls: f.properties.urls,
+                geometry: f.geometry,
+            })
+            .collect();
+        Ok(Self::from_regions(regions))
+    }
+
+    /// Builds a flat catalog from the HTML directory listing of BBBike's city
+    /// extracts, where every city is a `<City>/` folder below `base_url`.
+    pub fn from_bbbike_listing(html: &str, base_url: &str) -> Result<Self> {
+        let mut cities: Vec<&str> = html
+            .split("href=\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter_map(|href| href.strip_suffix('/'))
+            .filter(|city| !city.is_empty() && city.chars().all(|c| c.is_ascii_alphanumeric()))
+            .collect();
+        cities.sort_unstable();
+        cities.dedup();
+        if cities.is_empty() {
+            return Err(anyhow!("No cities found in BBBike listing"));
+        }
+        let regions = cities
+            .into_iter()
+            .map(|city| Region {
+                id: city.to_lowercase(),
+                parent: None,
+                name: city.to_string(),
+                urls: RegionUrls {
+                    pbf: Some(format!("{}/{}/{}.osm.pbf", base_url, city, city)),
+                    shp: Some(format!("{}/{}/{}.osm.shp.zip", base_url, city, city)),
+                },
+                geometry: None,
+            })
+            .collect();
+        Ok(Self::from_regions(regions))
+    }
+
+    /// Builds a catalog from a directory laid out like the Geofabrik server:
+    /// `asia-latest.osm.pbf` is a region and `asia/indonesia-latest.osm.pbf` one of
+    /// its children. URLs point below `base_url`.
+    pub fn from_directory(root: &Path, base_url: &str) -> Result<Self> {
+        // Keyed by the path without suffix, e.g. `north-america/us/georgia`.
+        let mut found: HashMap<String, Region> = HashMap::new();
+        let mut pending = vec![(root.to_path_buf(), Vec::<String>::new())];
+        while let Some((dir, segments)) = pending.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read extract directory {:?}", dir))?;
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_dir() {
+                    let mut child = segments.clone();
+                    child.push(name);
+                    pending.push((entry.path(), child));
+                    continue;
+                }
+                let (stem, format) = if let Some(stem) = name.strip_suffix(DownloadFormat::Pbf.suffix()) {
+                    (stem.to_string(), DownloadFormat::Pbf)
+                } else if let Some(stem) = name.strip_suffix(DownloadFormat::Shapefile.suffix()) {
+                    (stem.to_string(), DownloadFormat::Shapefile)
+                } else {
+                    continue;
+                };
+                let dir_path = segments.iter().map(|s| format!("{}/", s)).collect::<String>();
+                let url = format!("{}/{}{}", base_url, dir_path, name);
+                let region = found.entry(format!("{}{}", dir_path, stem)).or_insert_with(|| Region {
+                    id: stem.clone(),
+                    parent: segments.last().cloned(),
+                    name: stem,
+                    urls: RegionUrls::default(),
+                    geometry: None,
+                });
+                match format {
+                    DownloadFormat::Pbf => region.urls.pbf = Some(url),
+                    DownloadFormat::Shapefile => region.urls.shp = Some(url),
+                }
+            }
+        }
+        if found.is_empty() {
+            return Err(anyhow!("No extracts found in {:?}", root));
+        }
+
+        // Like Geofabrik's `us/georgia`, names used twice are qualified by their parent.
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for region in found.values() {
+            *seen.entry(region.id.clone()).or_default() += 1;
+        }
+        let mut regions: Vec<Region> = found.into_values().collect();
+        for region in &mut regions {
+            if let (Some(parent), true) = (&region.parent, seen[&region.id] > 1) {
+                region.id = format!("{}/{}", parent, region.id);
+            }
+        }
+        regions.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(Self::from_regions(regions))
+    }
+
+    fn from_regions(regions: Vec<Region>) -> Self {
+        let by_id = regions
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.id.clone(), i))
+            .collect();
+        let mut children: HashMap<Option<String>, Vec<usize>> = HashMap::new();
+        for (i, region) in regions.iter().enumerate() {
+            children.entry(region.parent.clone()).or_default().push(i);
+        }
+        for indices in children.values_mut() {
+            indices.sort_by(|&a, &b| regions[a].name.cmp(&regions[b].name));
+        }
+        Self { regions, by_id, children }
+    }
+
+    /// Points every download URL starting with `from` at `to` instead, for mirrors
+    /// that serve a verbatim copy of the upstream index.
+    pub fn rebase(&mut self, from: &str, to: &str) {
+        if from == to {
+            return;
+        }
+        for region in &mut self.regions {
+            for url in [&mut region.urls.pbf, &mut region.urls.shp].into_iter().flatten() {
+                if let Some(rest) = url.strip_prefix(from) {
+                    *url = format!("{}{}", to, rest);
+                }
+            }
+        }
+    }
+
+    fn from_file(path: &Path, parse: impl Fn(&str) -> Result<Self>) -> Result<Self> {
+        let body = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read region index {:?}", path))?;
+        parse(&body)
+    }
+
+    /// Loads the index from `cache_path` if it is fresh enough, otherwise downloads
+    /// it from `url` and refreshes the cache. A stale cache is still used when the
+    /// download fails, so the catalog keeps working offline. `parse` turns the
+    /// downloaded document (JSON index or HTML listing) into a catalog.
+    pub async fn load_or_fetch(
+        downloader: &Downloader,
+        url: &str,
+        cache_path: &Path,
+        parse: impl Fn(&str) -> Result<Self>,
+    ) -> Result<Self> {
+        let age = std::fs::metadata(cache_path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok());
+        if let Some(age) = age {
+            if age < CACHE_MAX_AGE {
+                if let Ok(catalog) = Self::from_file(cache_path, &parse) {
+                    return Ok(catalog);
+                }
+            }
+        }
+
+        info!("Fetching region index from {}", url);
+        match Self::fetch(downloader, url).await {
+            Ok(body) => {
+                let catalog = parse(&body)?;
+                if let Err(e) = std::fs::write(cache_path, &body) {
+                    warn!("Failed to cache region index at {:?}: {}", cache_path, e);
+                }
+                Ok(catalog)
+            }
+            Err(e) if age.is_some() => {
+                warn!("Failed to refresh region index, using stale cache: {}", e);
+                Self::from_file(cache_path, parse)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn fetch(downloader: &Downloader, url: &str) -> Result<String> {
+        let body = downloader.fetch_bytes(url).await?;
+        String::from_utf8(body).with_context(|| format!("Region index at {} isn't UTF-8", url))
+    }
+
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Region> {
+        self.by_id.get(id).map(|&i| &self.regions[i])
+    }
+
+    /// Regions directly below `parent`, or the top-level regions for `None`, by name.
+    pub fn children(&self, parent: Option<&str>) -> impl Iterator<Item = &Region> {
+        self.children
+            .get(&parent.map(str::to_string))
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.regions[i])
+    }
+
+    /// Slash-separated path from the top-level region down to `id`, e.g.
+    /// `north-america/us/california` (ids like `us/california` contribute their
+    /// last segment only).
+    pub fn path(&self, id: &str) -> String {
+        let mut segments = Vec::new();
+        let mut current = self.get(id);
+        while let Some(region) = current {
+            segments.push(region.id.rsplit('/').next().unwrap_or(&region.id));
+            current = region.parent.as_deref().and_then(|p| self.get(p));
+        }
+        segments.reverse();
+        segments.join("/")
+    }
+
+    pub fn has_children(&self, id: &str) -> bool {
+        self.children.contains_key(&Some(id.to_string()))
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    /// Resolves an id (`kalimantan`), a name (`United States`) or a slash-separated
+    /// path of either (`Asia/Indonesia/Kalimantan`) to a region. Each path segment
+    /// must be a child of the previous one.
+    pub fn resolve(&self, query: &str) -> Result<&Region> {
+        let segments: Vec<&str> = query
+            .split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        if segments.is_empty() {
+            return Err(anyhow!("No region given"));
+        }
+        // Exact ids such as `us/georgia` contain a slash themselves.
+        if let Some(region) = self.get(&normalize(query)) {
+            return Ok(region);
+        }
+
+        let mut parent: Option<&Region> = None;
+        for segment in segments {
+            let key = normalize(segment);
+            let found = self.regions.iter().find(|r| {
+                let under_parent = match parent {
+                    Some(p) => r.parent.as_deref() == Some(p.id.as_str()),
+                    None => true,
+                };
+                under_parent && (r.id == key || normalize(&r.name) == key)
+            });
+            match found {
+                Some(region) => parent = Some(region),
+                None => {
+                    let suggestions = self.suggestions(segment, parent.map(|p| p.id.as_str()));
+                    return Err(if suggestions.is_empty() {
+                        anyhow!("Unknown region '{}'", segment)
+                    } else {
+                        let names: Vec<&str> = suggestions.iter().map(|r| r.name.as_str()).collect();
+                        anyhow!("Unknown region '{}'. Did you mean: {}?", segment, names.join(", "))
+                    });
+                }
+            }
+        }
+        parent.ok_or_else(|| anyhow!("No region given"))
+    }
+
+    /// Up to five regions whose id or name is close to `query`, optionally limited
+    /// to the children of `parent`.
+    pub fn suggestions(&self, query: &str, parent: Option<&str>) -> Vec<&Region> {
+        let key = normalize(query);
+        let mut scored: Vec<(usize, &Region)> = self
+            .regions
+            .iter()
+            .filter(|r| parent.is_none() || r.parent.as_deref() == parent)
+            .filter_map(|r| {
+                let name = normalize(&r.name);
+                let distance = levenshtein(&key, &r.id).min(levenshtein(&key, &name));
+                let substring = !key.is_empty() && (name.contains(&key) || key.contains(&name));
+                let threshold = (key.chars().count() / 3).max(2);
+                if substring {
+                    Some((distance.min(1), r))
+                } else if distance <= threshold {
+                    Some((distance, r))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        scored.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        scored.into_iter().take(5).map(|(_, r)| r).collect()
+    }
+}
+
+/// Lowercases and turns spaces/underscores into dashes, the way Geofabrik ids are written.
+fn normalize(s: &str) -> String {
+    s.trim()
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    pub(crate) const INDEX: &str = r#"{
+        "type": "FeatureCollection",
+        "features": [
+            {"type": "Feature", "properties": {"id": "asia", "name": "Asia",
+                "urls": {"pbf": "https://download.geofabrik.de/asia-latest.osm.pbf"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "indonesia", "parent": "asia", "name": "Indonesia (with East Timor)",
+                "urls": {"pbf": "https://download.geofabrik.de/asia/indonesia-latest.osm.pbf",
+                         "shp": "https://download.geofabrik.de/asia/indonesia-latest-free.shp.zip"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "kalimantan", "parent": "indonesia", "name": "Kalimantan",
+                "urls": {"pbf": "https://download.geofabrik.de/asia/indonesia/kalimantan-latest.osm.pbf",
+                         "shp": "https://download.geofabrik.de/asia/indonesia/kalimantan-latest-free.shp.zip"}},
+             "geometry": {"type": "MultiPolygon", "coordinates": []}},
+            {"type": "Feature", "properties": {"id": "north-america", "name": "North America",
+                "urls": {"pbf": "https://download.geofabrik.de/north-america-latest.osm.pbf"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "us", "parent": "north-america", "name": "United States of America",
+                "urls": {"pbf": "https://download.geofabrik.de/north-america/us-latest.osm.pbf"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "us/georgia", "parent": "us", "name": "Georgia",
+                "urls": {"pbf": "https://download.geofabrik.de/north-america/us/georgia-latest.osm.pbf"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "europe", "name": "Europe",
+                "urls": {"pbf": "https://download.geofabrik.de/europe-latest.osm.pbf"}}, "geometry": null},
+            {"type": "Feature", "properties": {"id": "georgia", "parent": "europe", "name": "Georgia",
+                "urls": {"pbf": "https://download.geofabrik.de/europe/georgia-latest.osm.pbf"}}, "geometry": null}
+        ]
+    }"#;
+
+    #[test]
+    fn test_resolve_by_id_name_and_path() {
+        let catalog = Catalog::from_json(INDEX).unwrap();
+        assert_eq!(catalog.region_count(), 8);
+
+        assert_eq!(catalog.resolve("kalimantan").unwrap().id, "kalimantan");
+        assert_eq!(catalog.resolve("united states of america").unwrap().id, "us");
+        let region = catalog.resolve("Asia/Indonesia/Kalimantan").unwrap();
+        assert_eq!(
+            region.url(&DownloadFormat::Shapefile),
+            Some("https://download.geofabrik.de/asia/indonesia/kalimantan-latest-free.shp.zip")
+        );
+        // Same name in two places: the path decides.
+        assert_eq!(catalog.resolve("North America/us/Georgia").unwrap().id, "us/georgia");
+        assert_eq!(catalog.resolve("Europe/Georgia").unwrap().id, "georgia");
+        assert_eq!(catalog.resolve("us/georgia").unwrap().id, "us/georgia");
+
+        assert_eq!(catalog.path("kalimantan"), "asia/indonesia/kalimantan");
+        assert_eq!(catalog.path("us/georgia"), "north-america/us/georgia");
+    }
+
+    #[test]
+    fn test_resolve_suggests_close_matches() {
+        let catalog = Catalog::from_json(INDEX).unwrap();
+        let err = catalog.resolve("Asia/Indonesa").unwrap_err().to_string();
+        assert!(err.contains("Did you mean: Indonesia (with East Timor)"), "{}", err);
+
+        let names: Vec<&str> = catalog
+            .suggestions("United States", None)
+            .iter()
+            .map(|r| r.id.as_str())
+            .collect();
+        assert_eq!(names, vec!["us"]);
+    }
+
+    #[tokio::test]
+    async fn test_load_from_fresh_cache_without_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index-v1.json");
+        std::fs::write(&path, INDEX).unwrap();
+
+        // Nothing listens on port 9, so this only succeeds via the cache.
+        let downloader = Downloader::new(&Config::default());
+        let url = "http://127.0.0.1:9/index-v1.json";
+        let catalog = Catalog::load_or_fetch(&downloader, url, &path, Catalog::from_json).await.unwrap();
+        assert!(catalog.get("kalimantan").unwrap().geometry.is_some());
+    }
+}
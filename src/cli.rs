@@ -0,0 +1,646 @@
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::db::{
+    self, Database, DownloadOutcome, DownloadRecord, IfExists, ImportCancelled, ImportOutcome, SpatialStatus,
+    TrackedRegion,
+};
+use crate::export::{self, ExportFormat};
+use crate::network::{DownloadControl, DownloadEvent, DownloadFormat, Downloader};
+use crate::provider;
+use crate::replication;
+use crate::ui::format_bytes;
+
+/// Exit codes of the headless commands, so scripts can tell failures apart.
+pub const EXIT_FAILURE: u8 = 1;
+// 2 is used by clap for usage errors
+pub const EXIT_REGION_NOT_FOUND: u8 = 3;
+pub const EXIT_DOWNLOAD_FAILED: u8 = 4;
+pub const EXIT_IMPORT_FAILED: u8 = 5;
+pub const EXIT_QUERY_FAILED: u8 = 6;
+pub const EXIT_UPDATE_FAILED: u8 = 7;
+pub const EXIT_EXPORT_FAILED: u8 = 8;
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Parser)]
+#[command(version, about = "Download OpenStreetMap extracts and query them with DuckDB")]
+pub struct Cli {
+    /// Config file to use instead of the default `config.toml`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Runs without the TUI; the TUI starts when no command is given.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Download a region (id, name or path such as `asia/indonesia`) and import it
+    Download {
+        region: String,
+        /// Defaults to `downloads.default_format` from the config
+        #[arg(long, value_enum)]
+        format: Option<FormatArg>,
+        /// Only download, don't import into DuckDB
+        #[arg(long)]
+        no_import: bool,
+        /// Table to import into, instead of one named after the region and format
+        /// (e.g. `asia_indonesia_pbf`)
+        #[arg(long, value_parser = table_name)]
+        table: Option<String>,
+        /// What to do if the table exists; defaults to `imports.if_exists` from the config
+        #[arg(long, value_enum)]
+        if_exists: Option<IfExists>,
+    },
+    /// Import a local .osm.pbf, .shp, .zip or .geojson file into DuckDB
+    Import {
+        file: PathBuf,
+        /// Table to import into, instead of one named after the file (e.g. `monaco_pbf`)
+        #[arg(long, value_parser = table_name)]
+        table: Option<String>,
+        /// What to do if the table exists; defaults to `imports.if_exists` from the config
+        #[arg(long, value_enum)]
+        if_exists: Option<IfExists>,
+        /// Replication directory the file was cut from (e.g.
+        /// `https://download.geofabrik.de/europe/monaco-updates`), so `update` can apply its diffs
+        #[arg(long, value_name = "URL")]
+        updates: Option<String>,
+    },
+    /// Apply the replication diffs published since TABLE was imported or last updated
+    Update {
+        #[arg(long, value_parser = table_name)]
+        table: String,
+    },
+    /// Rebuild the theme layers (roads, buildings, ...) of a PBF import, e.g. after
+    /// changing `[[layers]]` in the config or for an import made without the
+    /// spatial extension
+    Layers {
+        #[arg(long, value_parser = table_name)]
+        table: String,
+    },
+    /// Run a SQL query and print the result
+    Query { sql: String },
+    /// Write a table or the result of a query to a CSV, Parquet, GeoJSON,
+    /// FlatGeobuf or GeoPackage file
+    Export {
+        /// File to write; its extension picks the format unless `--format` is given
+        output: PathBuf,
+        /// Table to export (e.g. `europe_monaco_pbf.roads`)
+        #[arg(long, conflicts_with = "sql", required_unless_present = "sql")]
+        table: Option<String>,
+        /// Query whose result to export
+        #[arg(long)]
+        sql: Option<String>,
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+    },
+    /// Track a region so `refresh` downloads and re-imports it whenever its
+    /// interval has passed
+    Track {
+        region: String,
+        /// Defaults to `downloads.default_format` from the config
+        #[arg(long, value_enum)]
+        format: Option<FormatArg>,
+        /// Defaults to `refresh.interval_hours` from the config
+        #[arg(long, value_name = "HOURS")]
+        interval_hours: Option<u32>,
+        /// Table every refresh replaces, instead of one named after the region and format
+        #[arg(long, value_parser = table_name)]
+        table: Option<String>,
+    },
+    /// Stop tracking a region
+    Untrack {
+        region: String,
+        /// Defaults to `downloads.default_format` from the config
+        #[arg(long, value_enum)]
+        format: Option<FormatArg>,
+    },
+    /// Download the tracked regions that are due and re-import those that
+    /// changed; meant to be run from cron or a systemd timer
+    Refresh {
+        /// Check every tracked region, due or not
+        #[arg(long)]
+        force: bool,
+        /// Only list the tracked regions and whether they are due
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the regions below PARENT (or the continents) from the Geofabrik index
+    List { parent: Option<String> },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FormatArg {
+    Pbf,
+    Shp,
+}
+
+impl From<FormatArg> for DownloadFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Pbf => DownloadFormat::Pbf,
+            FormatArg::Shp => DownloadFormat::Shapefile,
+        }
+    }
+}
+
+/// Accepts `--table` values that can go into SQL unquoted: ASCII letters, digits
+/// and `_`, not starting with a digit.
+fn table_name(name: &str) -> Result<String, String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name.to_string())
+    } else {
+        let suggestion = db::identifier(name);
+        Err(format!("'{}' isn't a valid table name; use letters, digits and _ (e.g. {})", name, suggestion))
+    }
+}
+
+/// A failed command together with the exit code it maps to.
+struct Failure {
+    code: u8,
+    error: anyhow::Error,
+}
+
+fn fail(code: u8) -> impl FnOnce(anyhow::Error) -> Failure {
+    move |error| Failure { code, error }
+}
+
+pub async fn run(command: Command, config: &Config, data_dir: &Path) -> ExitCode {
+    let result = match command {
+        Command::Download { region, format, no_import, table, if_exists } => {
+            let format = format.map_or_else(|| config.downloads.default_format.clone(), Into::into);
+            let if_exists = if_exists.unwrap_or(config.imports.if_exists);
+            let import_into = (!no_import).then_some((table, if_exists));
+            download(config, data_dir, &region, format, import_into).await
+        }
+        Command::Import { file, table, if_exists, updates } => {
+            let table = table.unwrap_or_else(|| db::file_table_name(&file));
+            let if_exists = if_exists.unwrap_or(config.imports.if_exists);
+            import(config, &file, &table, if_exists, updates.as_deref()).await
+        }
+        Command::Update { table } => update(config, &table).await,
+        Command::Layers { table } => build_layers(config, &table),
+        Command::Query { sql } => query(config, &sql),
+        Command::Export { output, table, sql, format } => {
+            let source = match table {
+                Some(table) => format!("SELECT * FROM {}", table),
+                None => sql.unwrap_or_default(),
+            };
+            export_rows(config, &output, &source, format)
+        }
+        Command::Track { region, format, interval_hours, table } => {
+            let format = format.map_or_else(|| config.downloads.default_format.clone(), Into::into);
+            let interval_hours = interval_hours.unwrap_or(config.refresh.interval_hours);
+            track(config, data_dir, &region, format, interval_hours, table).await
+        }
+        Command::Untrack { region, format } => {
+            let format = format.map_or_else(|| config.downloads.default_format.clone(), Into::into);
+            untrack(config, data_dir, &region, format).await
+        }
+        Command::Refresh { force, dry_run } => refresh(config, force, dry_run).await,
+        Command::List { parent } => list(config, data_dir, parent.as_deref()).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            tracing::error!("{:#}", failure.error);
+            eprintln!("Error: {:#}", failure.error);
+            ExitCode::from(failure.code)
+        }
+    }
+}
+
+fn open_db(config: &Config) -> Result<Database, Failure> {
+    if let Some(parent) = config.paths.database.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(anyhow::Error::from)
+            .map_err(fail(EXIT_FAILURE))?;
+    }
+    let mut db = Database::new(&config.paths.database).map_err(fail(EXIT_FAILURE))?;
+    db.set_layers(config.layers.clone());
+    db.load_spatial(&config.spatial);
+    Ok(db)
+}
+
+async fn load_catalog(config: &Config, data_dir: &Path) -> Result<Catalog> {
+    provider::load_catalog(&config.providers, &Downloader::new(config), data_dir).await
+}
+
+/// The download URL of `region` and its path in the catalog, guessed from the
+/// first provider when the catalog can't be loaded.
+async fn resolve_region(
+    config: &Config,
+    data_dir: &Path,
+    region: &str,
+    format: &DownloadFormat,
+) -> Result<(String, String), Failure> {
+    Ok(match load_catalog(config, data_dir).await {
+        Ok(catalog) => {
+            let found = catalog.resolve(region).map_err(fail(EXIT_REGION_NOT_FOUND))?;
+            let url = found
+                .url(format)
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("{} has no {:?} extract", found.name, format))
+                .map_err(fail(EXIT_REGION_NOT_FOUND))?;
+            (url, catalog.path(&found.id))
+        }
+        Err(e) => {
+            eprintln!("Region index unavailable ({:#}), guessing the URL.", e);
+            let parts: Vec<&str> = region.split('/').collect();
+            let part = |i: usize| parts.get(i).copied().unwrap_or("");
+            let url = config.primary_provider().construct_url(part(0), part(1), part(2), format);
+            (url, region.to_string())
+        }
+    })
+}
+
+async fn download(
+    config: &Config,
+    data_dir: &Path,
+    region: &str,
+    format: DownloadFormat,
+    import_into: Option<(Option<String>, IfExists)>,
+) -> Result<(), Failure> {
+    let (url, dataset) = resolve_region(config, data_dir, region, &format).await?;
+    download_url(config, &url, &dataset, format, import_into).await
+}
+
+/// Downloads the extract of `dataset` (a region path) from `url`, or a mirror of
+/// it, and imports it unless `import_into` is `None`.
+async fn download_url(
+    config: &Config,
+    url: &str,
+    dataset: &str,
+    format: DownloadFormat,
+    import_into: Option<(Option<String>, IfExists)>,
+) -> Result<(), Failure> {
+    let downloader = Downloader::new(config);
+    eprintln!("Downloading {}", url);
+
+    let download_dir = config.paths.download_dir.clone();
+    std::fs::create_dir_all(&download_dir)
+        .map_err(anyhow::Error::from)
+        .map_err(fail(EXIT_FAILURE))?;
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    let reporter = tokio::spawn(report_progress(rx));
+    let control = DownloadControl::default();
+
+    let urls = provider::mirror_urls(&config.providers, url);
+    let started = Instant::now();
+    let transfer = downloader.download_file(1, urls, download_dir, control.clone(), tx);
+    tokio::pin!(transfer);
+    let result = tokio::select! {
+        result = &mut transfer => result,
+        _ = tokio::signal::ctrl_c() => {
+            // Keep the .part file so the next run resumes.
+            control.pause();
+            let _ = transfer.await;
+            let _ = reporter.await;
+            return Err(Failure { code: EXIT_INTERRUPTED, error: anyhow!("Interrupted, partial download kept") });
+        }
+    };
+    let _ = reporter.await;
+    let record = match &result {
+        Ok(file) => DownloadRecord::completed(file, dataset, &format, started.elapsed()),
+        Err(e) => DownloadRecord::unfinished(
+            url,
+            dataset,
+            &format,
+            started.elapsed(),
+            DownloadOutcome::Failed,
+            Some(e.to_string()),
+        ),
+    };
+    // Failed downloads are recorded too, so not being able to is only a warning
+    let db = open_db(config);
+    let download_id = match &db {
+        Ok(db) => db.record_download(&record).map_err(|e| format!("{:#}", e)),
+        Err(failure) => Err(format!("{:#}", failure.error)),
+    }
+    .inspect_err(|e| eprintln!("Failed to record the download: {}", e))
+    .ok();
+    let file = result.map_err(fail(EXIT_DOWNLOAD_FAILED))?;
+    println!("{}", file.path.display());
+
+    if let Some((table, if_exists)) = import_into {
+        let db = db?;
+        let table = table.unwrap_or_else(|| db::dataset_table_name(dataset, format.short_name()));
+        let path_str = file.path.to_string_lossy();
+        if file.unchanged && db.holds_import_of(&table, &path_str).map_err(fail(EXIT_FAILURE))? {
+            eprintln!("Not imported again, table {} already holds this file.", table);
+            return Ok(());
+        }
+        if let Some(table) = import_file(&db, &path_str, &table, if_exists, download_id)? {
+            if let Err(e) = replication::track(&db, &downloader, &table, url, &record).await {
+                eprintln!("No replication state for {}, `update` won't work for it: {:#}", table, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Imports into `table` (or a version of it) and returns the table written to,
+/// or `None` if an existing table was kept.
+fn import_file(
+    db: &Database,
+    path: &str,
+    table: &str,
+    if_exists: IfExists,
+    download_id: Option<i64>,
+) -> Result<Option<String>, Failure> {
+    eprintln!("Importing {} into table {}...", path, table);
+    if let SpatialStatus::Unavailable(reason) = db.spatial_status() {
+        if path.ends_with(".osm.pbf") {
+            eprintln!("Spatial extension unavailable ({}), importing without geometries or layers.", reason);
+        }
+    }
+    let control = db.import_control().on_progress(|p| {
+        let mut line = format!("\r{}", p.phase.label());
+        if p.total > 0 {
+            line.push_str(&format!(" {:5.1}%", p.ratio() * 100.0));
+        }
+        line.push_str(&format!("  {} rows", p.rows));
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "{}\x1b[K", line);
+        let _ = stderr.flush();
+    });
+    // Ctrl+C interrupts DuckDB and rolls the import back.
+    let cancel = control.clone();
+    let interrupt = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    });
+    let outcome = tokio::task::block_in_place(|| db.import_dataset(path, table, if_exists, download_id, &control));
+    interrupt.abort();
+    eprintln!();
+    let outcome = match outcome {
+        Err(e) if e.is::<ImportCancelled>() => {
+            return Err(Failure { code: EXIT_INTERRUPTED, error: anyhow!("Interrupted, import rolled back") });
+        }
+        outcome => outcome.map_err(fail(EXIT_IMPORT_FAILED))?,
+    };
+    match outcome {
+        ImportOutcome::Imported { table, rows } => {
+            eprintln!("Imported into table {}.", table);
+            for (name, count) in rows {
+                eprintln!("  {:<40} {:>12} rows", name, count);
+            }
+            Ok(Some(table))
+        }
+        ImportOutcome::Kept(table) => {
+            eprintln!("Table {} already exists, kept it (use --if-exists to change this).", table);
+            Ok(None)
+        }
+    }
+}
+
+/// Prints download events to stderr.
+async fn report_progress(mut rx: tokio::sync::mpsc::Receiver<DownloadEvent>) {
+    let mut stderr = std::io::stderr();
+    while let Some(evt) = rx.recv().await {
+        match evt {
+            DownloadEvent::Progress(_, p) => {
+                let mut line = format!("\r{:5.1}% {} / {}", p.percent, format_bytes(p.downloaded), format_bytes(p.total));
+                if p.bytes_per_sec > 0.0 {
+                    line.push_str(&format!("  {}/s", format_bytes(p.bytes_per_sec as u64)));
+                }
+                if let Some(eta) = p.eta() {
+                    line.push_str(&format!("  ETA {}s", eta.as_secs()));
+                }
+                let _ = write!(stderr, "{}\x1b[K", line);
+                let _ = stderr.flush();
+            }
+            DownloadEvent::Verifying(_) => eprintln!("\nVerifying checksum..."),
+            DownloadEvent::VerificationFailed(_, e) => eprintln!("\n{}", e),
+            DownloadEvent::Retrying(_, e) => eprintln!("\n{}", e),
+            DownloadEvent::Paused(_) => eprintln!("\nPaused, partial download kept."),
+            DownloadEvent::Complete(..) => eprintln!("\nDownload complete."),
+            DownloadEvent::UpToDate(..) => eprintln!("Already up to date, nothing downloaded."),
+            _ => {}
+        }
+    }
+}
+
+async fn import(
+    config: &Config,
+    file: &Path,
+    table: &str,
+    if_exists: IfExists,
+    updates: Option<&str>,
+) -> Result<(), Failure> {
+    let db = open_db(config)?;
+    let Some(table) = import_file(&db, &file.to_string_lossy(), table, if_exists, None)? else {
+        return Ok(());
+    };
+    let table = table.as_str();
+    if let Some(updates) = updates {
+        let updates = updates.trim_end_matches('/');
+        let state = replication::fetch_state(&Downloader::new(config), updates)
+            .await
+            .map_err(fail(EXIT_FAILURE))?;
+        db.record_replication(table, updates, &state)
+            .map_err(fail(EXIT_FAILURE))?;
+        eprintln!("Tracking {} at replication sequence {}.", table, state.sequence);
+    }
+    Ok(())
+}
+
+async fn update(config: &Config, table: &str) -> Result<(), Failure> {
+    let db = open_db(config)?;
+    let summary = replication::update_table(&db, &Downloader::new(config), table)
+        .await
+        .map_err(fail(EXIT_UPDATE_FAILED))?;
+    if summary.from_sequence == summary.to_sequence {
+        eprintln!("{} is up to date (sequence {}).", table, summary.to_sequence);
+    } else {
+        eprintln!(
+            "Updated {} from sequence {} to {}: {} created, {} modified, {} deleted.",
+            table, summary.from_sequence, summary.to_sequence, summary.created, summary.modified, summary.deleted
+        );
+    }
+    Ok(())
+}
+
+async fn track(
+    config: &Config,
+    data_dir: &Path,
+    region: &str,
+    format: DownloadFormat,
+    interval_hours: u32,
+    table: Option<String>,
+) -> Result<(), Failure> {
+    let (url, region) = resolve_region(config, data_dir, region, &format).await?;
+    let table = table.unwrap_or_else(|| db::dataset_table_name(&region, format.short_name()));
+    let db = open_db(config)?;
+    let tracked = TrackedRegion {
+        region,
+        format: format.short_name().to_string(),
+        url,
+        table,
+        interval_hours,
+        last_checked: None,
+        due: true,
+    };
+    db.track_region(&tracked).map_err(fail(EXIT_FAILURE))?;
+    eprintln!(
+        "Tracking {} ({}), refreshed into table {} every {} h.",
+        tracked.region, tracked.format, tracked.table, tracked.interval_hours
+    );
+    Ok(())
+}
+
+async fn untrack(config: &Config, data_dir: &Path, region: &str, format: DownloadFormat) -> Result<(), Failure> {
+    let db = open_db(config)?;
+    let format = format.short_name();
+    let mut untracked = db.untrack_region(region, format).map_err(fail(EXIT_FAILURE))?;
+    if !untracked {
+        // Tracked regions are stored by their path in the catalog, e.g. `europe/monaco`
+        let (_, path) = resolve_region(config, data_dir, region, &DownloadFormat::Pbf).await?;
+        untracked = db.untrack_region(&path, format).map_err(fail(EXIT_FAILURE))?;
+    }
+    if !untracked {
+        return Err(Failure {
+            code: EXIT_REGION_NOT_FOUND,
+            error: anyhow!("{} ({}) isn't tracked", region, format),
+        });
+    }
+    eprintln!("No longer tracking {} ({}).", region, format);
+    Ok(())
+}
+
+/// Downloads the tracked regions that are due, one after the other, and replaces
+/// their tables with the ones that changed. A failed region doesn't stop the others.
+async fn refresh(config: &Config, force: bool, dry_run: bool) -> Result<(), Failure> {
+    // Closed before downloading, which opens the database again
+    let regions = open_db(config)?.tracked_regions().map_err(fail(EXIT_FAILURE))?;
+    if regions.is_empty() {
+        eprintln!("No regions are tracked; add some with `track`.");
+        return Ok(());
+    }
+    if dry_run {
+        for region in &regions {
+            println!(
+                "{:<32} {:<4} {:<32} every {:>4} h, checked {:<16} {}",
+                region.region,
+                region.format,
+                region.table,
+                region.interval_hours,
+                region.last_checked.as_deref().unwrap_or("never"),
+                if region.due { "due" } else { "" }
+            );
+        }
+        return Ok(());
+    }
+
+    let tracked = regions.len();
+    let due: Vec<TrackedRegion> = regions.into_iter().filter(|region| force || region.due).collect();
+    let mut failures = Vec::new();
+    for region in &due {
+        eprintln!("Refreshing {} ({})...", region.region, region.format);
+        let Some(format) = DownloadFormat::parse(&region.format) else {
+            eprintln!("Error: {} has an unknown format '{}'", region.region, region.format);
+            failures.push(EXIT_FAILURE);
+            continue;
+        };
+        let import_into = Some((Some(region.table.clone()), IfExists::Replace));
+        match download_url(config, &region.url, &region.region, format, import_into).await {
+            Ok(()) => {}
+            Err(failure) if failure.code == EXIT_INTERRUPTED => return Err(failure),
+            Err(failure) => {
+                tracing::error!("Refresh of {} failed: {:#}", region.region, failure.error);
+                eprintln!("Error: {:#}", failure.error);
+                failures.push(failure.code);
+            }
+        }
+    }
+    match failures.last() {
+        None => {
+            eprintln!("Refreshed {} of {} tracked regions, the others aren't due yet.", due.len(), tracked);
+            Ok(())
+        }
+        Some(&code) => Err(Failure {
+            code,
+            error: anyhow!("{} of {} regions failed to refresh", failures.len(), due.len()),
+        }),
+    }
+}
+
+fn build_layers(config: &Config, table: &str) -> Result<(), Failure> {
+    let db = open_db(config)?;
+    let is_osm = db.is_osm_dataset(table).map_err(fail(EXIT_FAILURE))?;
+    if !is_osm {
+        return Err(Failure {
+            code: EXIT_FAILURE,
+            error: anyhow!("{} is not a PBF import", table),
+        });
+    }
+    db.require_spatial("Theme layers").map_err(fail(EXIT_FAILURE))?;
+    let layers = db.build_layers(table).map_err(fail(EXIT_IMPORT_FAILED))?;
+    for layer in layers {
+        println!("{}.{}", table, layer);
+    }
+    Ok(())
+}
+
+fn query(config: &Config, sql: &str) -> Result<(), Failure> {
+    let db = open_db(config)?;
+    let output = db.query(sql).map_err(fail(EXIT_QUERY_FAILED))?;
+    print!("{}", output);
+    Ok(())
+}
+
+fn export_rows(config: &Config, output: &Path, sql: &str, format: Option<ExportFormat>) -> Result<(), Failure> {
+    let Some(format) = format.or_else(|| ExportFormat::from_path(output)) else {
+        return Err(Failure {
+            code: EXIT_FAILURE,
+            error: anyhow!("Can't tell the format of {} from its extension, use --format", output.display()),
+        });
+    };
+    let db = open_db(config)?;
+    let rows = export::export(&db, sql, output, format).map_err(fail(EXIT_EXPORT_FAILED))?;
+    eprintln!("Exported {} rows to {} ({}).", rows, output.display(), format.label());
+    Ok(())
+}
+
+async fn list(config: &Config, data_dir: &Path, parent: Option<&str>) -> Result<(), Failure> {
+    let catalog = load_catalog(config, data_dir).await.map_err(fail(EXIT_FAILURE))?;
+    let parent_id = match parent {
+        Some(p) => Some(catalog.resolve(p).map_err(fail(EXIT_REGION_NOT_FOUND))?.id.clone()),
+        None => None,
+    };
+    for region in catalog.children(parent_id.as_deref()) {
+        let mut formats = Vec::new();
+        if region.urls.pbf.is_some() {
+            formats.push("pbf");
+        }
+        if region.urls.shp.is_some() {
+            formats.push("shp");
+        }
+        let more = if catalog.has_children(&region.id) { " +" } else { "" };
+        println!("{:<32} {:<40} {}{}", region.id, region.name, formats.join(","), more);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_names_are_validated() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["osm-downloader"].iter().chain(args));
+        assert!(parse(&["import", "monaco.osm.pbf", "--table", "Monaco_2024"]).is_ok());
+        assert!(parse(&["import", "monaco.osm.pbf", "--table", "x; DROP TABLE downloads"]).is_err());
+        assert!(parse(&["download", "monaco", "--table", "2024_monaco"]).is_err());
+        assert!(parse(&["update", "--table", "europe.monaco"]).is_err());
+    }
+}
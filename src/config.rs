@@ -0,0 +1,475 @@
+use anyhow::{anyhow, bail, Context, Result};
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::db::IfExists;
+use crate::layers::{self, Layer};
+use crate::network::DownloadFormat;
+use crate::provider::{Provider, GEOFABRIK_URL};
+use crate::queue::{DEFAULT_MAX_CONCURRENT, MAX_CONCURRENT};
+
+pub const CONFIG_ENV: &str = "OSM_DOWNLOADER_CONFIG";
+const ENV_PREFIX: &str = "OSM_DOWNLOADER_";
+
+/// Settings from `config.toml`, with environment overrides applied.
+///
+/// ```toml
+/// [paths]
+/// download_dir = "/srv/osm/downloads"
+/// database = "/srv/osm/osm.duckdb"
+///
+/// [[providers]]                  # tried in order
+/// kind = "geofabrik"              # geofabrik, bbbike or directory
+/// url = "https://download.geofabrik.de"
+///
+/// [http]
+/// timeout_secs = 300
+/// connect_timeout_secs = 30
+///
+/// [retry]
+/// max_retries = 3
+/// delay_secs = 2
+///
+/// [downloads]
+/// max_concurrent = 2
+/// default_format = "pbf"
+///
+/// [imports]
+/// if_exists = "replace"           # keep, replace or version
+///
+/// [spatial]
+/// extension = "/opt/duckdb/spatial.duckdb_extension"
+/// install = true                  # fall back to `INSTALL spatial` over the network
+///
+/// [queries]
+/// timeout_secs = 300              # 0 lets queries in the TUI run as long as they take
+///
+/// [refresh]
+/// interval_hours = 24             # how often tracked regions are downloaded again
+///
+/// [[layers]]                     # replaces the default layers; see `Layer`
+/// name = "roads"
+/// geometry = "line"
+/// filter = { highway = [] }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub paths: PathsConfig,
+    pub providers: Vec<Provider>,
+    pub http: HttpConfig,
+    pub retry: RetryConfig,
+    pub downloads: DownloadsConfig,
+    pub imports: ImportsConfig,
+    pub spatial: SpatialConfig,
+    pub queries: QueriesConfig,
+    pub refresh: RefreshConfig,
+    /// Theme layers built from PBF imports.
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub download_dir: PathBuf,
+    pub database: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub delay_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadsConfig {
+    pub max_concurrent: usize,
+    pub default_format: DownloadFormat,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportsConfig {
+    /// What happens when a region is imported again.
+    pub if_exists: IfExists,
+}
+
+/// Where the DuckDB spatial extension is loaded from. Tried in order: `extension`,
+/// `extensions/spatial.duckdb_extension` next to the binary, an extension already
+/// installed for DuckDB, and finally `INSTALL spatial` if `install` is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpatialConfig {
+    pub extension: Option<PathBuf>,
+    pub install: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueriesConfig {
+    /// How long a query from the Database tab may run before it is interrupted.
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    /// Refresh interval of regions tracked without `--interval-hours`.
+    pub interval_hours: u32,
+}
+
+/// The per-user data directory holding the database, downloads, logs and caches.
+pub fn data_dir() -> PathBuf {
+    project_dirs().data_dir().to_path_buf()
+}
+
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("com", "osm-downloader", "osm-downloader").unwrap()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            paths: PathsConfig::default(),
+            providers: vec![Provider::geofabrik(GEOFABRIK_URL)],
+            http: HttpConfig::default(),
+            retry: RetryConfig::default(),
+            downloads: DownloadsConfig::default(),
+            imports: ImportsConfig::default(),
+            spatial: SpatialConfig::default(),
+            queries: QueriesConfig::default(),
+            refresh: RefreshConfig::default(),
+            layers: layers::default_layers(),
+        }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        let data_dir = data_dir();
+        Self {
+            download_dir: data_dir.join("downloads"),
+            database: data_dir.join("osm.duckdb"),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 300,
+            connect_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            delay_secs: 2,
+        }
+    }
+}
+
+impl Default for SpatialConfig {
+    fn default() -> Self {
+        Self {
+            extension: None,
+            install: true,
+        }
+    }
+}
+
+impl Default for QueriesConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 300 }
+    }
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        Self { interval_hours: 24 }
+    }
+}
+
+impl Default for DownloadsConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            default_format: DownloadFormat::Pbf,
+        }
+    }
+}
+
+impl Config {
+    /// `config.toml` in the user's config directory (e.g. `~/.config/osm-downloader/`).
+    pub fn default_path() -> PathBuf {
+        project_dirs().config_dir().join("config.toml")
+    }
+
+    /// Loads the config from `explicit` (`--config`), `$OSM_DOWNLOADER_CONFIG` or the
+    /// default location, applies `OSM_DOWNLOADER_*` overrides and validates it.
+    /// Only the default location may be missing, in which case defaults are used.
+    pub fn load(explicit: Option<&Path>) -> Result<Self> {
+        let env = |key: &str| std::env::var(key).ok();
+        let (path, required) = match (explicit, env(CONFIG_ENV)) {
+            (Some(path), _) => (path.to_path_buf(), true),
+            (None, Some(path)) => (PathBuf::from(path), true),
+            (None, None) => (Self::default_path(), false),
+        };
+
+        let mut config = if path.exists() || required {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {:?}", path))?;
+            Self::from_toml(&text).with_context(|| format!("Invalid config file {:?}", path))?
+        } else {
+            Self::default()
+        };
+        config.apply_env(env)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Applies `OSM_DOWNLOADER_<KEY>` overrides, using `get` to look variables up.
+    pub fn apply_env(&mut self, get: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let var = |name: &'static str| get(&format!("{}{}", ENV_PREFIX, name)).map(|v| (name, v));
+        fn parse<T: std::str::FromStr>((name, value): (&str, String)) -> Result<T> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("{}{}: invalid value '{}'", ENV_PREFIX, name, value))
+        }
+
+        if let Some((_, v)) = var("DOWNLOAD_DIR") {
+            self.paths.download_dir = PathBuf::from(v);
+        }
+        if let Some((_, v)) = var("DATABASE") {
+            self.paths.database = PathBuf::from(v);
+        }
+        if let Some((_, v)) = var("MIRROR_URL") {
+            // Tried before the configured providers.
+            self.providers.insert(0, Provider::geofabrik(&v));
+        }
+        if let Some(v) = var("TIMEOUT_SECS") {
+            self.http.timeout_secs = parse(v)?;
+        }
+        if let Some(v) = var("CONNECT_TIMEOUT_SECS") {
+            self.http.connect_timeout_secs = parse(v)?;
+        }
+        if let Some(v) = var("MAX_RETRIES") {
+            self.retry.max_retries = parse(v)?;
+        }
+        if let Some(v) = var("RETRY_DELAY_SECS") {
+            self.retry.delay_secs = parse(v)?;
+        }
+        if let Some(v) = var("MAX_CONCURRENT") {
+            self.downloads.max_concurrent = parse(v)?;
+        }
+        if let Some((name, v)) = var("DEFAULT_FORMAT") {
+            self.downloads.default_format = DownloadFormat::parse(&v)
+                .ok_or_else(|| anyhow!("{}{}: expected 'pbf' or 'shp', got '{}'", ENV_PREFIX, name, v))?;
+        }
+        if let Some((_, v)) = var("SPATIAL_EXTENSION") {
+            self.spatial.extension = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("SPATIAL_INSTALL") {
+            self.spatial.install = parse(v)?;
+        }
+        if let Some(v) = var("QUERY_TIMEOUT_SECS") {
+            self.queries.timeout_secs = parse(v)?;
+        }
+        if let Some(v) = var("REFRESH_INTERVAL_HOURS") {
+            self.refresh.interval_hours = parse(v)?;
+        }
+        if let Some((name, v)) = var("IMPORT_IF_EXISTS") {
+            self.imports.if_exists = clap::ValueEnum::from_str(v.trim(), true).map_err(|_| {
+                anyhow!("{}{}: expected 'keep', 'replace' or 'version', got '{}'", ENV_PREFIX, name, v)
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.paths.download_dir.as_os_str().is_empty() {
+            bail!("paths.download_dir must not be empty");
+        }
+        if self.paths.database.as_os_str().is_empty() {
+            bail!("paths.database must not be empty");
+        }
+        if self.providers.is_empty() {
+            bail!("at least one entry in providers is required");
+        }
+        for (i, provider) in self.providers.iter().enumerate() {
+            provider
+                .validate()
+                .with_context(|| format!("providers[{}]", i))?;
+        }
+        if self.http.timeout_secs == 0 {
+            bail!("http.timeout_secs must be greater than 0");
+        }
+        if self.http.connect_timeout_secs == 0 {
+            bail!("http.connect_timeout_secs must be greater than 0");
+        }
+        if self.retry.max_retries == 0 {
+            bail!("retry.max_retries must be at least 1");
+        }
+        if !(1..=MAX_CONCURRENT).contains(&self.downloads.max_concurrent) {
+            bail!(
+                "downloads.max_concurrent must be between 1 and {}, got {}",
+                MAX_CONCURRENT,
+                self.downloads.max_concurrent
+            );
+        }
+        if self.refresh.interval_hours == 0 {
+            bail!("refresh.interval_hours must be greater than 0");
+        }
+        layers::validate(&self.layers).context("layers")?;
+        Ok(())
+    }
+
+    /// The provider used to guess URLs when no catalog could be loaded.
+    pub fn primary_provider(&self) -> &Provider {
+        &self.providers[0]
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.http.timeout_secs)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.http.connect_timeout_secs)
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry.delay_secs)
+    }
+
+    pub fn query_timeout(&self) -> Option<Duration> {
+        (self.queries.timeout_secs > 0).then(|| Duration::from_secs(self.queries.timeout_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [[providers]]
+            kind = "geofabrik"
+            url = "https://osm.example.com/geofabrik/"
+
+            [[providers]]
+            kind = "directory"
+            url = "file:///srv/osm"
+
+            [downloads]
+            default_format = "shp"
+
+            [imports]
+            if_exists = "keep"
+
+            [spatial]
+            install = false
+
+            [queries]
+            timeout_secs = 0
+
+            [refresh]
+            interval_hours = 168
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.providers.len(), 2);
+        assert_eq!(config.primary_provider().base_url(), "https://osm.example.com/geofabrik");
+        assert!(matches!(config.downloads.default_format, DownloadFormat::Shapefile));
+        assert_eq!(config.http.timeout_secs, 300);
+        assert_eq!(config.retry.max_retries, 3);
+        assert_eq!(config.imports.if_exists, IfExists::Keep);
+        assert_eq!((config.spatial.extension.as_deref(), config.spatial.install), (None, false));
+        assert_eq!(config.query_timeout(), None);
+        assert_eq!(config.refresh.interval_hours, 168);
+        assert_eq!(config.layers.len(), layers::default_layers().len());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars: HashMap<&str, &str> = HashMap::from([
+            ("OSM_DOWNLOADER_DATABASE", "/tmp/other.duckdb"),
+            ("OSM_DOWNLOADER_MIRROR_URL", "http://mirror.local"),
+            ("OSM_DOWNLOADER_MAX_CONCURRENT", "4"),
+            ("OSM_DOWNLOADER_RETRY_DELAY_SECS", "10"),
+            ("OSM_DOWNLOADER_IMPORT_IF_EXISTS", "Version"),
+            ("OSM_DOWNLOADER_SPATIAL_EXTENSION", "/opt/spatial.duckdb_extension"),
+            ("OSM_DOWNLOADER_QUERY_TIMEOUT_SECS", "30"),
+            ("OSM_DOWNLOADER_REFRESH_INTERVAL_HOURS", "6"),
+        ]);
+        let mut config = Config::default();
+        config
+            .apply_env(|k| vars.get(k).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.paths.database, PathBuf::from("/tmp/other.duckdb"));
+        assert_eq!(config.downloads.max_concurrent, 4);
+        let urls: Vec<&str> = config.providers.iter().map(|p| p.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://mirror.local", GEOFABRIK_URL]);
+        assert_eq!(config.retry_delay(), Duration::from_secs(10));
+        assert_eq!(config.imports.if_exists, IfExists::Version);
+        assert_eq!(config.spatial.extension, Some(PathBuf::from("/opt/spatial.duckdb_extension")));
+        assert_eq!(config.query_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(config.refresh.interval_hours, 6);
+
+        let err = Config::default()
+            .apply_env(|k| (k == "OSM_DOWNLOADER_MAX_RETRIES").then(|| "lots".to_string()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "OSM_DOWNLOADER_MAX_RETRIES: invalid value 'lots'");
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        assert!(Config::from_toml("[http]\ntimeout = 5").is_err(), "unknown keys are rejected");
+
+        let config = Config::from_toml("[http]\ntimeout_secs = 0").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "http.timeout_secs must be greater than 0"
+        );
+        let config =
+            Config::from_toml("[[providers]]\nkind = \"geofabrik\"\nurl = \"ftp://example.com\"").unwrap();
+        assert!(config.validate().is_err());
+        let config =
+            Config::from_toml("[[providers]]\nkind = \"directory\"\nurl = \"relative/dir\"").unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::from_toml("[downloads]\nmax_concurrent = 0").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = Config::from_toml(
+            "[[layers]]\nname = \"Shops\"\ngeometry = \"point\"\nfilter = { shop = [] }",
+        )
+        .unwrap();
+        assert_eq!(config.layers.len(), 1, "configured layers replace the defaults");
+        assert_eq!(
+            format!("{:#}", config.validate().unwrap_err()),
+            "layers: layer name 'Shops' must be lowercase letters, digits and _"
+        );
+    }
+}
@@ -1,71 +1,871 @@
-use anyhow::{anyhow, Result};
-use duckdb::Connection;
-use std::{path::Path, time::Instant};
-use tracing::{error, info};
+use anyhow::{anyhow, bail, Result};
+use duckdb::core::LogicalTypeHandle;
+use duckdb::types::Value;
+use duckdb::{params, Connection, InterruptHandle};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::config::SpatialConfig;
+use crate::layers::Layer;
+use crate::network::{DownloadFormat, DownloadedFile};
+use crate::pbf::{self, PbfReader};
+use crate::replication::{Action, Change, Element, ReplicationState};
+use crate::results::{Cell, Column, QueryResult};
+use crate::schema;
 
 pub struct Database {
     conn: Connection,
+    /// Theme layers built from every PBF import.
+    layers: Vec<Layer>,
+    spatial: SpatialStatus,
+}
+
+/// What an import is busy with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportPhase {
+    /// Reading the file; `done` and `total` count bytes, or layers of a zip.
+    #[default]
+    Reading,
+    /// Gathering the tags and tables of a PBF file.
+    Tables,
+    /// Building geometries and theme layers.
+    Geometries,
+}
+
+impl ImportPhase {
+    pub fn label(&self) -> &str {
+        match self {
+            ImportPhase::Reading => "reading",
+            ImportPhase::Tables => "building tables",
+            ImportPhase::Geometries => "building geometries",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportProgress {
+    pub phase: ImportPhase,
+    pub done: u64,
+    pub total: u64,
+    /// Elements or rows inserted so far.
+    pub rows: u64,
+}
+
+impl ImportProgress {
+    /// How far the current phase is, from 0.0 to 1.0.
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        (self.done as f64 / self.total as f64).min(1.0)
+    }
+}
+
+/// The error of an import stopped through `ImportControl::cancel`.
+#[derive(Debug)]
+pub struct ImportCancelled;
+
+impl std::fmt::Display for ImportCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Import cancelled")
+    }
+}
+
+impl std::error::Error for ImportCancelled {}
+
+/// Reports the progress of an import and lets another thread stop it. Cancelling
+/// interrupts the statement DuckDB is running, and the import is rolled back.
+#[derive(Clone)]
+pub struct ImportControl {
+    cancelled: Arc<AtomicBool>,
+    /// Set while the import holds the connection, so cancelling an import that
+    /// waits for the database doesn't interrupt whatever runs before it.
+    running: Arc<AtomicBool>,
+    interrupt: Arc<InterruptHandle>,
+    on_progress: Arc<dyn Fn(&ImportProgress) + Send + Sync>,
+}
+
+impl ImportControl {
+    pub fn new(interrupt: Arc<InterruptHandle>) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            running: Arc::default(),
+            interrupt,
+            on_progress: Arc::new(|_| {}),
+        }
+    }
+
+    pub fn on_progress(mut self, f: impl Fn(&ImportProgress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Arc::new(f);
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if self.running.load(Ordering::SeqCst) {
+            self.interrupt.interrupt();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(ImportCancelled.into());
+        }
+        Ok(())
+    }
+
+    fn report(&self, progress: &ImportProgress) {
+        (self.on_progress)(progress);
+    }
+}
+
+/// How often a running import reports its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether the spatial extension, which geometries, layers and shapefile imports
+/// need, could be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpatialStatus {
+    /// Loaded from the described source.
+    Loaded(String),
+    /// Why no source worked.
+    Unavailable(String),
+}
+
+/// What to do when the table an import would create already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IfExists {
+    /// Leave the existing table alone and skip the import.
+    Keep,
+    /// Drop the existing table and import in its place.
+    #[default]
+    Replace,
+    /// Import into the next free `<table>_v2`, `<table>_v3`, ...
+    Version,
+}
+
+/// How a download ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownloadOutcome {
+    #[default]
+    Success,
+    /// The file of an earlier download was up to date and kept.
+    Unchanged,
+    Failed,
+    Cancelled,
+}
+
+impl DownloadOutcome {
+    pub fn label(&self) -> &str {
+        match self {
+            DownloadOutcome::Success => "success",
+            DownloadOutcome::Unchanged => "unchanged",
+            DownloadOutcome::Failed => "failed",
+            DownloadOutcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Where a download came from and how it went; a row of `downloads`.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadRecord {
+    /// The URL fetched, a mirror's if the primary one failed.
+    pub url: String,
+    /// Region path in the catalog, e.g. `europe/monaco`.
+    pub region: Option<String>,
+    /// `pbf` or `shp`.
+    pub format: Option<String>,
+    pub local_path: Option<String>,
+    pub size: Option<u64>,
+    pub md5: Option<String>,
+    /// Whether `md5` matched the checksum published by the mirror.
+    pub md5_verified: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Seconds since the epoch the OSM data is current to, and its replication
+    /// sequence, as written in the header of PBF files.
+    pub osm_timestamp: Option<i64>,
+    pub replication_sequence: Option<i64>,
+    pub duration: Duration,
+    pub outcome: DownloadOutcome,
+    pub error: Option<String>,
+}
+
+impl DownloadRecord {
+    /// A finished download of `region`. PBF files are read for the timestamp and
+    /// replication sequence of their data.
+    pub fn completed(file: &DownloadedFile, region: &str, format: &DownloadFormat, duration: Duration) -> Self {
+        let header = match format {
+            DownloadFormat::Pbf => pbf::read_header(&file.path).unwrap_or_else(|e| {
+                warn!("Can't read the PBF header of {:?}: {:#}", file.path, e);
+                Default::default()
+            }),
+            DownloadFormat::Shapefile => Default::default(),
+        };
+        Self {
+            url: file.url.clone(),
+            region: Some(region.to_string()),
+            format: Some(format.short_name().to_string()),
+            local_path: Some(file.path.to_string_lossy().into_owned()),
+            size: Some(file.size),
+            md5: Some(file.md5.clone()),
+            md5_verified: file.verified,
+            etag: file.etag.clone(),
+            last_modified: file.last_modified.clone(),
+            osm_timestamp: header.replication_timestamp,
+            replication_sequence: header.replication_sequence,
+            duration,
+            outcome: if file.unchanged { DownloadOutcome::Unchanged } else { DownloadOutcome::Success },
+            error: None,
+        }
+    }
+
+    /// A download of `region` from `url` that failed or was cancelled.
+    pub fn unfinished(
+        url: &str,
+        region: &str,
+        format: &DownloadFormat,
+        duration: Duration,
+        outcome: DownloadOutcome,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            url: url.to_string(),
+            region: Some(region.to_string()),
+            format: Some(format.short_name().to_string()),
+            duration,
+            outcome,
+            error,
+            ..Default::default()
+        }
+    }
+}
+
+/// A region kept current by `refresh`; a row of `tracked_regions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedRegion {
+    /// Region path in the catalog, e.g. `europe/monaco`.
+    pub region: String,
+    /// `pbf` or `shp`.
+    pub format: String,
+    pub url: String,
+    /// Table every refresh replaces.
+    pub table: String,
+    pub interval_hours: u32,
+    /// When the region was last downloaded, successfully or not, e.g. `2024-05-01 20:21`.
+    pub last_checked: Option<String>,
+    /// Whether `interval_hours` have passed since then.
+    pub due: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportOutcome {
+    /// The dataset written to, with the row count of each of its tables.
+    Imported { table: String, rows: Vec<(String, usize)> },
+    /// The table existed and `IfExists::Keep` was asked for.
+    Kept(String),
 }
 
 impl Database {
     pub fn new(db_path: &Path) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-        
-        // Try to install and load spatial extension. 
-        // This might fail if no internet or blocked, but it's required for OSM/Shapefile
-        // We wrap in a result but don't fail the whole app init if it fails immediately,
-        // we might fail later during import.
-        if let Err(e) = conn.execute_batch("INSTALL spatial; LOAD spatial;") {
-            error!("Failed to load spatial extension: {}. Import might fail.", e);
+        let mut conn = Connection::open(db_path)?;
+        // Creates the app's own tables, or upgrades those of an older version
+        schema::migrate(&mut conn)?;
+
+        Ok(Self {
+            conn,
+            layers: Vec::new(),
+            spatial: SpatialStatus::Unavailable("not loaded".to_string()),
+        })
+    }
+
+    /// Loads the spatial extension from the first source in `config` that works.
+    /// Imports of PBF files work without it, but get no geometries or layers.
+    pub fn load_spatial(&mut self, config: &SpatialConfig) -> &SpatialStatus {
+        let mut errors = Vec::new();
+        for (source, sql) in spatial_sources(config) {
+            match self.conn.execute_batch(&sql) {
+                Ok(()) => {
+                    info!("Spatial extension loaded from {}.", source);
+                    self.spatial = SpatialStatus::Loaded(source);
+                    return &self.spatial;
+                }
+                Err(e) => errors.push(format!("{}: {}", source, e)),
+            }
+        }
+        let reason = errors.join("; ");
+        error!("Failed to load spatial extension: {}", reason);
+        self.spatial = SpatialStatus::Unavailable(reason);
+        &self.spatial
+    }
+
+    pub fn spatial_status(&self) -> &SpatialStatus {
+        &self.spatial
+    }
+
+    pub fn has_spatial(&self) -> bool {
+        matches!(self.spatial, SpatialStatus::Loaded(_))
+    }
+
+    /// Fails with an explanation when `what` can't work without the spatial extension.
+    pub fn require_spatial(&self, what: &str) -> Result<()> {
+        match &self.spatial {
+            SpatialStatus::Loaded(_) => Ok(()),
+            SpatialStatus::Unavailable(reason) => {
+                bail!("{} need the spatial extension, which isn't available ({})", what, reason)
+            }
+        }
+    }
+
+    pub fn set_layers(&mut self, layers: Vec<Layer>) {
+        self.layers = layers;
+    }
+
+    /// (Re)creates the theme layers inside the schema of a PBF import and returns
+    /// their names. Geometries are built first if the import doesn't have them,
+    /// e.g. because it was made without the spatial extension.
+    pub fn build_layers(&self, schema: &str) -> Result<Vec<String>> {
+        if !self.has_geometries(schema)? {
+            if !self.has_spatial() {
+                return Ok(Vec::new());
+            }
+            self.conn.execute_batch(&geometry_sql(schema, "true", "true", "true"))?;
+        }
+        let sql: String = self.layers.iter().map(|layer| layer.create_sql(schema)).collect();
+        self.conn.execute_batch(&sql)?;
+        Ok(self.layers.iter().map(|layer| layer.name.clone()).collect())
+    }
+
+    /// Records a download, finished or not, and returns its id in `downloads`.
+    pub fn record_download(&self, record: &DownloadRecord) -> Result<i64> {
+        let osm_timestamp = record
+            .osm_timestamp
+            .and_then(|seconds| chrono::DateTime::from_timestamp(seconds, 0))
+            .map(|t| t.naive_utc().to_string());
+        // `id` has no sequence behind it, so the next one is picked here.
+        let id = self.conn.query_row(
+            "INSERT INTO downloads (id, url, local_path, md5, region, format, size_bytes, md5_verified,
+                                    etag, last_modified, osm_timestamp, replication_sequence,
+                                    duration_ms, outcome, error)
+             VALUES ((SELECT COALESCE(MAX(id), 0) + 1 FROM downloads), ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             RETURNING id",
+            params![
+                record.url,
+                record.local_path,
+                record.md5,
+                record.region,
+                record.format,
+                record.size,
+                record.md5_verified,
+                record.etag,
+                record.last_modified,
+                osm_timestamp,
+                record.replication_sequence,
+                record.duration.as_millis() as i64,
+                record.outcome.label(),
+                record.error,
+            ],
+            |row| row.get(0),
+        )?;
+        // Any download of a tracked region counts as checking it
+        if let (Some(region), Some(format)) = (&record.region, &record.format) {
+            self.conn.execute(
+                "UPDATE tracked_regions SET last_checked = CURRENT_TIMESTAMP WHERE region = ? AND format = ?",
+                params![region, format],
+            )?;
+        }
+        Ok(id)
+    }
+
+    pub fn table_exists(&self, table: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT count(*) FROM information_schema.tables WHERE table_schema = 'main' AND table_name = ?",
+            [table],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// The last download that left the file at `path`, with its id in `downloads`.
+    pub fn download_of(&self, path: &str) -> Result<Option<(i64, DownloadRecord)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, url, region, format, md5, md5_verified, epoch(osm_timestamp)::BIGINT, replication_sequence
+             FROM downloads WHERE local_path = ? AND outcome IN ('success', 'unchanged')
+             ORDER BY id DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query([path])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let record = DownloadRecord {
+            url: row.get(1)?,
+            region: row.get(2)?,
+            format: row.get(3)?,
+            local_path: Some(path.to_string()),
+            md5: row.get(4)?,
+            md5_verified: row.get::<_, Option<bool>>(5)?.unwrap_or(false),
+            osm_timestamp: row.get(6)?,
+            replication_sequence: row.get(7)?,
+            ..Default::default()
+        };
+        Ok(Some((row.get(0)?, record)))
+    }
+
+    /// The dataset last imported from the file at `path`, if it still exists.
+    pub fn imported_from(&self, path: &str) -> Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT table_name FROM datasets WHERE source_path = ? ORDER BY imported_at DESC")?;
+        let tables = stmt
+            .query_map([path], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        for table in tables {
+            if self.dataset_exists(&table)? {
+                return Ok(Some(table));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether `table` exists and was last imported from the file at `path`.
+    pub fn holds_import_of(&self, table: &str, path: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT count(*) FROM datasets WHERE table_name = ? AND source_path = ?",
+            [table, path],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0 && self.dataset_exists(table)?)
+    }
+
+    /// Whether `name` is a PBF import, i.e. a schema holding `nodes`, `ways`,
+    /// `way_nodes`, `relations` and `members`.
+    pub fn is_osm_dataset(&self, name: &str) -> Result<bool> {
+        Ok(self.schema_tables(name)?.iter().any(|t| t == "nodes"))
+    }
+
+    /// Tables inside the schema `name`; empty if there is no such schema.
+    fn schema_tables(&self, name: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT table_name FROM information_schema.tables WHERE table_schema = ? ORDER BY table_name",
+        )?;
+        let tables = stmt
+            .query_map([name], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(tables)
+    }
+
+    /// Datasets are either a table, or a schema of tables for PBF files and
+    /// shapefile archives.
+    fn dataset_exists(&self, name: &str) -> Result<bool> {
+        let schema: i64 = self.conn.query_row(
+            "SELECT count(*) FROM information_schema.schemata WHERE schema_name = ?",
+            [name],
+            |row| row.get(0),
+        )?;
+        Ok(schema > 0 || self.table_exists(name)?)
+    }
+
+    /// The table to preview a dataset with: the table itself, the ways of a PBF
+    /// import, or the first layer of a shapefile archive.
+    pub fn preview_table(&self, name: &str) -> Result<String> {
+        let tables = self.schema_tables(name)?;
+        let preview = tables.iter().find(|t| *t == "ways").or(tables.first());
+        Ok(match preview {
+            Some(table) => format!("{}.{}", name, table),
+            None => name.to_string(),
+        })
+    }
+
+    /// Row counts of the tables of a dataset, qualified with the schema if it has one.
+    pub fn row_counts(&self, name: &str) -> Result<Vec<(String, usize)>> {
+        let tables = self.schema_tables(name)?;
+        let tables = if tables.is_empty() {
+            vec![name.to_string()]
+        } else {
+            tables.iter().map(|t| format!("{}.{}", name, t)).collect()
+        };
+        tables
+            .into_iter()
+            .map(|table| {
+                let count: i64 = self
+                    .conn
+                    .query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0))?;
+                Ok((table, count as usize))
+            })
+            .collect()
+    }
+
+    /// Imports `file_path` into the table (or, for PBF files and zips, the schema)
+    /// named `table`, or a versioned variant of it, and registers it in `datasets`
+    /// against `download_id`. It all happens in one transaction, so a failed or
+    /// cancelled import leaves any previous import of the table as it was.
+    pub fn import_dataset(
+        &self,
+        file_path: &str,
+        table: &str,
+        if_exists: IfExists,
+        download_id: Option<i64>,
+        control: &ImportControl,
+    ) -> Result<ImportOutcome> {
+        let target = if !self.dataset_exists(table)? {
+            table.to_string()
         } else {
-            info!("Spatial extension loaded successfully.");
+            match if_exists {
+                IfExists::Keep => return Ok(ImportOutcome::Kept(table.to_string())),
+                IfExists::Replace => table.to_string(),
+                IfExists::Version => {
+                    let mut version = 2;
+                    while self.dataset_exists(&format!("{}_v{}", table, version))? {
+                        version += 1;
+                    }
+                    format!("{}_v{}", table, version)
+                }
+            }
+        };
+        control.check()?;
+        self.conn.execute_batch("BEGIN TRANSACTION")?;
+        control.running.store(true, Ordering::SeqCst);
+        let result = (|| -> Result<()> {
+            self.import_data(file_path, &target, control)?;
+            control.check()?;
+            // A replaced table no longer matches the replication sequence of the old one.
+            self.conn
+                .execute("DELETE FROM replication WHERE table_name = ?", [&target])?;
+            self.conn.execute(
+                "INSERT OR REPLACE INTO datasets (table_name, download_id, source_path, imported_at)
+                 VALUES (?, ?, ?, CURRENT_TIMESTAMP)",
+                duckdb::params![target, download_id, file_path],
+            )?;
+            Ok(())
+        })();
+        control.running.store(false, Ordering::SeqCst);
+        if let Err(e) = result {
+            let _ = self.conn.execute_batch("ROLLBACK");
+            if control.is_cancelled() {
+                info!("Import of {} cancelled and rolled back", file_path);
+                return Err(ImportCancelled.into());
+            }
+            return Err(e);
+        }
+        self.conn.execute_batch("COMMIT")?;
+
+        let rows = self.row_counts(&target)?;
+        for (table, count) in &rows {
+            info!("{}: {} rows", table, count);
         }
+        Ok(ImportOutcome::Imported { table: target, rows })
+    }
 
-        // Create a metadata table for downloads
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS downloads (
-                id INTEGER PRIMARY KEY,
-                url VARCHAR,
-                local_path VARCHAR,
-                downloaded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            );"
+    pub fn record_replication(&self, table: &str, updates_url: &str, state: &ReplicationState) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO replication (table_name, updates_url, sequence, state_timestamp, updated_at)
+             VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)",
+            duckdb::params![table, updates_url, state.sequence as i64, state.timestamp],
         )?;
+        Ok(())
+    }
 
-        Ok(Self { conn })
+    /// The replication directory and last applied sequence of `table`, if tracked.
+    pub fn replication_state(&self, table: &str) -> Result<Option<(String, u64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT updates_url, sequence FROM replication WHERE table_name = ?")?;
+        let mut rows = stmt.query([table])?;
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get::<_, i64>(1)? as u64))),
+            None => Ok(None),
+        }
     }
 
-    pub fn record_download(&self, url: &str, path: &str) -> Result<()> {
+    /// Tracks `region` so `refresh` downloads it again every `interval_hours`,
+    /// or changes how an already tracked one is refreshed. A region downloaded
+    /// before counts as checked then.
+    pub fn track_region(&self, region: &TrackedRegion) -> Result<()> {
         self.conn.execute(
-            "INSERT INTO downloads (url, local_path) VALUES (?, ?)",
-            [url, path],
+            "INSERT INTO tracked_regions (region, format, url, table_name, interval_hours, last_checked)
+             VALUES ($1, $2, $3, $4, $5,
+                     (SELECT MAX(downloaded_at) FROM downloads WHERE region = $1 AND format = $2))
+             ON CONFLICT (region, format) DO UPDATE
+             SET url = excluded.url, table_name = excluded.table_name, interval_hours = excluded.interval_hours",
+            params![region.region, region.format, region.url, region.table, region.interval_hours],
         )?;
         Ok(())
     }
 
-    pub fn import_data(&self, file_path: &str, table_name: &str) -> Result<()> {
+    /// Stops tracking `region`; false if it wasn't tracked.
+    pub fn untrack_region(&self, region: &str, format: &str) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM tracked_regions WHERE region = ? AND format = ?", params![region, format])?;
+        Ok(deleted > 0)
+    }
+
+    /// All tracked regions, those checked longest ago first.
+    pub fn tracked_regions(&self) -> Result<Vec<TrackedRegion>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT region, format, url, table_name, interval_hours, strftime(last_checked, '%Y-%m-%d %H:%M'),
+                    last_checked IS NULL OR last_checked + to_hours(interval_hours) <= CURRENT_TIMESTAMP::TIMESTAMP
+             FROM tracked_regions ORDER BY last_checked NULLS FIRST, region, format",
+        )?;
+        let regions = stmt
+            .query_map([], |row| {
+                Ok(TrackedRegion {
+                    region: row.get(0)?,
+                    format: row.get(1)?,
+                    url: row.get(2)?,
+                    table: row.get(3)?,
+                    interval_hours: row.get(4)?,
+                    last_checked: row.get(5)?,
+                    due: row.get(6)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(regions)
+    }
+
+    /// Applies one change file to a PBF import and moves its replication sequence
+    /// forward, all in one transaction. Geometries of the changed elements, and of
+    /// the ways and relations built from them, are rebuilt if the import has them.
+    pub fn apply_changes(&self, schema: &str, changes: &[Change], state: &ReplicationState) -> Result<()> {
+        if self.has_geometries(schema)? {
+            self.require_spatial("Updates of tables with geometries")?;
+        }
+        self.conn.execute_batch("BEGIN TRANSACTION")?;
+        let result = (|| -> Result<()> {
+            let mut changed: [Vec<i64>; 3] = Default::default();
+            for change in changes {
+                let element = &change.element;
+                let i = match element.kind.as_str() {
+                    "node" => 0,
+                    "way" => 1,
+                    "relation" => 2,
+                    other => return Err(anyhow!("Unknown element type '{}'", other)),
+                };
+                changed[i].push(element.id);
+                // Creates are deleted first too, so replaying a file is harmless.
+                let mut sql = delete_element_sql(schema, element);
+                if change.action != Action::Delete {
+                    sql.push_str(&insert_element_sql(schema, element));
+                }
+                self.conn.execute_batch(&sql)?;
+            }
+            if self.has_geometries(schema)? {
+                // Ways move with their nodes, and multipolygons with their ways.
+                let [nodes, ways, relations] = changed.map(|ids| {
+                    let ids: Vec<String> = ids.iter().map(i64::to_string).collect();
+                    if ids.is_empty() { "NULL".to_string() } else { ids.join(", ") }
+                });
+                let way_filter = format!(
+                    "id IN ({}) OR id IN (SELECT way_id FROM {}.way_nodes WHERE node_id IN ({}))",
+                    ways, schema, nodes
+                );
+                let relation_filter = format!(
+                    "id IN ({r}) OR id IN (SELECT relation_id FROM {s}.members
+                                          WHERE member_type = 'way' AND member_id IN (SELECT id FROM {s}.ways WHERE {w}))",
+                    r = relations,
+                    s = schema,
+                    w = way_filter
+                );
+                let node_filter = format!("id IN ({})", nodes);
+                self.conn
+                    .execute_batch(&geometry_sql(schema, &node_filter, &way_filter, &relation_filter))?;
+            }
+            self.conn.execute(
+                "UPDATE replication SET sequence = ?, state_timestamp = ?, updated_at = CURRENT_TIMESTAMP
+                 WHERE table_name = ?",
+                duckdb::params![state.sequence as i64, state.timestamp, schema],
+            )?;
+            Ok(())
+        })();
+        match result {
+            Ok(()) => {
+                self.conn.execute_batch("COMMIT")?;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    fn has_geometries(&self, schema: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT count(*) FROM information_schema.columns
+             WHERE table_schema = ? AND table_name = 'nodes' AND column_name = 'geom'",
+            [schema],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Reads an `.osm.pbf` file into the tables of a new schema: `nodes`, `ways`,
+    /// `relations`, plus `way_nodes` and `members` listing the references in order
+    /// (`seq` starts at 1). Elements are appended one block at a time, and tags
+    /// are staged as rows until they're gathered into maps at the end.
+    pub fn import_pbf(&self, file_path: &str, schema: &str, control: &ImportControl) -> Result<u64> {
+        let file = std::fs::File::open(file_path)?;
+        let mut progress = ImportProgress {
+            total: file.metadata()?.len(),
+            ..ImportProgress::default()
+        };
+        let mut reader = PbfReader::new(std::io::BufReader::new(file));
+        self.conn.execute_batch(&format!(
+            "CREATE SCHEMA {s};
+             CREATE TABLE {s}._nodes (id BIGINT, lat DOUBLE, lon DOUBLE);
+             CREATE TABLE {s}._ways (id BIGINT);
+             CREATE TABLE {s}._relations (id BIGINT);
+             CREATE TABLE {s}._tags (kind VARCHAR, id BIGINT, key VARCHAR, value VARCHAR);
+             CREATE TABLE {s}.way_nodes (way_id BIGINT, seq BIGINT, node_id BIGINT);
+             CREATE TABLE {s}.members (relation_id BIGINT, seq BIGINT, member_type VARCHAR,
+                                       member_id BIGINT, role VARCHAR);",
+            s = schema
+        ))?;
+
+        let mut reported = Instant::now();
+        {
+            let mut nodes = self.conn.appender_to_db("_nodes", schema)?;
+            let mut ways = self.conn.appender_to_db("_ways", schema)?;
+            let mut relations = self.conn.appender_to_db("_relations", schema)?;
+            let mut tags = self.conn.appender_to_db("_tags", schema)?;
+            let mut way_nodes = self.conn.appender_to_db("way_nodes", schema)?;
+            let mut members = self.conn.appender_to_db("members", schema)?;
+            while let Some(elements) = reader.next_block()? {
+                control.check()?;
+                for element in &elements {
+                    for (key, value) in &element.tags {
+                        tags.append_row(params![element.kind, element.id, key, value])?;
+                    }
+                    match element.kind.as_str() {
+                        "node" => nodes.append_row(params![element.id, element.lat, element.lon])?,
+                        "way" => {
+                            ways.append_row([element.id])?;
+                            for (i, node) in element.refs.iter().enumerate() {
+                                way_nodes.append_row(params![element.id, i as i64 + 1, node])?;
+                            }
+                        }
+                        _ => {
+                            relations.append_row([element.id])?;
+                            let refs = element.refs.iter().zip(&element.ref_types).zip(&element.ref_roles);
+                            for (i, ((member, kind), role)) in refs.enumerate() {
+                                members.append_row(params![element.id, i as i64 + 1, kind, member, role])?;
+                            }
+                        }
+                    }
+                }
+                progress.rows += elements.len() as u64;
+                progress.done = reader.position();
+                if reported.elapsed() >= PROGRESS_INTERVAL {
+                    control.report(&progress);
+                    reported = Instant::now();
+                }
+            }
+        }
+        control.report(&progress);
+
+        progress.phase = ImportPhase::Tables;
+        control.report(&progress);
+
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE {s}._tag_maps AS
+                SELECT kind, id, map(list(key), list(value)) AS tags FROM {s}._tags GROUP BY kind, id;
+             CREATE TABLE {s}.nodes AS
+                SELECT n.id, coalesce(t.tags, {empty}) AS tags, n.lat, n.lon
+                FROM {s}._nodes n LEFT JOIN {s}._tag_maps t ON t.kind = 'node' AND t.id = n.id;
+             CREATE TABLE {s}.ways AS
+                SELECT w.id, coalesce(t.tags, {empty}) AS tags
+                FROM {s}._ways w LEFT JOIN {s}._tag_maps t ON t.kind = 'way' AND t.id = w.id;
+             CREATE TABLE {s}.relations AS
+                SELECT r.id, coalesce(t.tags, {empty}) AS tags
+                FROM {s}._relations r LEFT JOIN {s}._tag_maps t ON t.kind = 'relation' AND t.id = r.id;
+             DROP TABLE {s}._nodes; DROP TABLE {s}._ways; DROP TABLE {s}._relations;
+             DROP TABLE {s}._tags; DROP TABLE {s}._tag_maps;",
+            s = schema,
+            empty = "MAP {}::MAP(VARCHAR, VARCHAR)"
+        ))?;
+        Ok(progress.rows)
+    }
+
+    #[cfg(test)]
+    pub fn execute_batch(&self, sql: &str) -> Result<()> {
+        Ok(self.conn.execute_batch(sql)?)
+    }
+
+    /// Runs a single statement and returns the number of rows it changed (or, for
+    /// `COPY`, wrote).
+    pub fn execute(&self, sql: &str) -> Result<usize> {
+        Ok(self.conn.execute(sql, [])?)
+    }
+
+    fn import_data(&self, file_path: &str, table_name: &str, control: &ImportControl) -> Result<()> {
         info!("Importing {} into table {}...", file_path, table_name);
         let metadata = std::fs::metadata(file_path)?;
         info!("File size for import: {} bytes", metadata.len());
-        
-        // Drop table if exists to overwrite
-        let _ = self.conn.execute(&format!("DROP TABLE IF EXISTS {}", table_name), []);
+        // Everything but PBF files is read with GDAL; check before dropping anything
+        if !file_path.ends_with(".osm.pbf") {
+            self.require_spatial("Shapefile and GeoJSON imports")?;
+        }
+
+        // Drop an earlier import of either layout to overwrite
+        self.conn.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {t}; DROP SCHEMA IF EXISTS {t} CASCADE;",
+            t = table_name
+        ))?;
 
         // Detect file type roughly by extension
         if file_path.ends_with(".osm.pbf") {
-             // ST_ReadOSM logic
-             // Note: ST_ReadOSM returns nodes, ways, relations. Usually complex to just "SELECT * INTO".
-             // For simplicity in this tool, we might create a view or just specific tables.
-             // Let's try to create a view for nodes as a default "import" action.
-             let query = format!("CREATE TABLE {} AS SELECT * FROM ST_ReadOSM('{}')", table_name, file_path);
-             self.conn.execute(&query, [])?;
-        } else if file_path.contains(".shp") || file_path.ends_with(".zip") || file_path.ends_with(".geojson") {
-            // For Shapefiles (DuckDB can read from zip directly if spatial is loaded and configured correctly, 
-            // but often needs the specific .shp file inside the zip.
-            // For now, let's assume the user unzipped it or we point to the .shp file.
-            // If it's a zip, we might need to rely on the user to select the shp, or we handle unzip in App logic.
-            // Assuming `file_path` points to a readable file for DuckDB.
-            let query = format!("CREATE TABLE {} AS SELECT * FROM ST_Read('{}')", table_name, file_path);
-            self.conn.execute(&query, [])?;
+            // Read natively, so a PBF imports without the spatial extension;
+            // geometries and layers are only built when it's there.
+            let count = self.import_pbf(file_path, table_name, control)?;
+            info!("Read {} elements from {}", count, file_path);
+            if !self.has_spatial() {
+                warn!("Spatial extension not loaded: {} has no geometries or layers", table_name);
+            } else {
+                control.report(&ImportProgress {
+                    phase: ImportPhase::Geometries,
+                    rows: count,
+                    ..ImportProgress::default()
+                });
+                let layers = self.build_layers(table_name)?;
+                info!("Built layers {} in {}", layers.join(", "), table_name);
+            }
+        } else if file_path.ends_with(".zip") {
+            // Geofabrik's archives hold ~20 gis_osm_*.shp layers; GDAL reads each in
+            // place through /vsizip/, one table per layer.
+            let layers = zip_shapefiles(Path::new(file_path))?;
+            if layers.is_empty() {
+                return Err(anyhow!("No shapefiles in {}", file_path));
+            }
+            self.conn.execute_batch(&format!("CREATE SCHEMA {};", table_name))?;
+            let mut progress = ImportProgress {
+                total: layers.len() as u64,
+                ..ImportProgress::default()
+            };
+            for (layer, entry) in &layers {
+                control.report(&progress);
+                let source = format!("/vsizip/{}/{}", file_path, entry);
+                let rows = self.conn.execute(
+                    &format!("CREATE TABLE {}.{} AS SELECT * FROM ST_Read({})", table_name, layer, sql_string(&source)),
+                    [],
+                )?;
+                control.check()?;
+                progress.done += 1;
+                progress.rows += rows as u64;
+            }
+            control.report(&progress);
+        } else if file_path.contains(".shp") || file_path.ends_with(".geojson") {
+            let query = format!("CREATE TABLE {} AS SELECT * FROM ST_Read({})", table_name, sql_string(file_path));
+            let rows = self.conn.execute(&query, [])?;
+            control.report(&ImportProgress { done: 1, total: 1, rows: rows as u64, ..ImportProgress::default() });
         } else {
             return Err(anyhow!("Unsupported file type for auto-import"));
         }
@@ -74,112 +874,702 @@ impl Database {
         Ok(())
     }
 
-    pub fn query(&self, sql: &str) -> Result<String> {
+    /// A control for imports on this connection, with no progress reporting.
+    pub fn import_control(&self) -> ImportControl {
+        ImportControl::new(self.interrupt_handle())
+    }
+
+    /// Interrupts whatever statement runs on the connection; taken before the
+    /// database is shared, so imports can be cancelled while it's locked.
+    pub fn interrupt_handle(&self) -> Arc<InterruptHandle> {
+        self.conn.interrupt_handle()
+    }
+
+    /// Runs `sql` and returns all of its rows.
+    pub fn query(&self, sql: &str) -> Result<QueryResult> {
+        self.fetch(sql, 0)
+    }
+
+    /// Up to `limit` rows of `sql` starting at `offset`. Statements that can't be
+    /// wrapped in a `SELECT` (`PRAGMA`, `INSERT` and the like) run as they are
+    /// and return all their rows, with `pageable` false.
+    pub fn query_page(&self, sql: &str, offset: usize, limit: usize) -> Result<(QueryResult, bool)> {
+        let paged = format!("SELECT * FROM (\n{}\n) LIMIT {} OFFSET {}", statement(sql), limit, offset);
+        if self.conn.prepare(&paged).is_ok() {
+            Ok((self.fetch(&paged, offset)?, true))
+        } else {
+            Ok((self.fetch(sql, 0)?, false))
+        }
+    }
+
+    /// Number of rows a pageable `sql` returns.
+    pub fn count_rows(&self, sql: &str) -> Result<usize> {
+        let count = format!("SELECT count(*) FROM (\n{}\n)", statement(sql));
+        let rows: i64 = self.conn.query_row(&count, [], |row| row.get(0))?;
+        Ok(rows as usize)
+    }
+
+    fn fetch(&self, sql: &str, offset: usize) -> Result<QueryResult> {
         let start = Instant::now();
         let mut stmt = self.conn.prepare(sql)?;
         let mut rows = stmt.query([])?;
 
         let stmt_ref = rows.as_ref().unwrap();
-        let column_count = stmt_ref.column_count();
-        let column_names: Vec<String> = (0..column_count)
-            .map(|i| stmt_ref.column_name(i).map(|s| s.to_string()).unwrap_or("unknown".to_string()))
+        let columns: Vec<Column> = (0..stmt_ref.column_count())
+            .map(|i| Column {
+                name: stmt_ref.column_name(i).map_or("unknown".to_string(), |s| s.to_string()),
+                type_name: type_name(&stmt_ref.column_logical_type(i)),
+            })
             .collect();
 
-        let mut rows_buffer: Vec<Vec<String>> = Vec::new();
-        let mut widths: Vec<usize> = column_names.iter().map(|n| n.len()).collect();
-        let mut count = 0;
-        let mut truncated = false;
-
+        let mut values = Vec::new();
         while let Some(row) = rows.next()? {
-            if count > 50 {
-                truncated = true;
+            let cells = (0..columns.len())
+                .map(|i| row.get::<_, Value>(i).map(Cell::from))
+                .collect::<Result<Vec<_>, _>>()?;
+            values.push(cells);
+        }
+
+        Ok(QueryResult {
+            columns,
+            rows: values,
+            offset,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+/// `sql` without the trailing semicolon, so it can be used as a subquery.
+fn statement(sql: &str) -> &str {
+    sql.trim().trim_end_matches(';').trim_end()
+}
+
+/// The name DuckDB prints for a column type: the alias for extension types such
+/// as `GEOMETRY`, otherwise the name of the built-in type.
+fn type_name(logical_type: &LogicalTypeHandle) -> String {
+    if let Some(alias) = logical_type.get_alias() {
+        return alias;
+    }
+    match logical_type.try_id() {
+        Ok(id) => format!("{:?}", id).to_uppercase(),
+        Err(_) => "UNKNOWN".to_string(),
+    }
+}
+
+/// The `.shp` files in a zip archive, as (table name, path inside the archive).
+/// Geofabrik's `gis_osm_roads_free_1.shp` becomes `roads`, and the area variants
+/// such as `gis_osm_pois_a_free_1.shp` become `pois_a`.
+pub fn zip_shapefiles(path: &Path) -> Result<Vec<(String, String)>> {
+    let archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut layers: Vec<(String, String)> = Vec::new();
+    for entry in archive.file_names() {
+        let file_name = entry.rsplit('/').next().unwrap_or(entry);
+        let Some(stem) = file_name
+            .strip_suffix(".shp")
+            .or_else(|| file_name.strip_suffix(".SHP"))
+        else {
+            continue;
+        };
+        let stem = stem.strip_prefix("gis_osm_").unwrap_or(stem);
+        let stem = match stem.rsplit_once("_free_") {
+            Some((layer, version)) if version.chars().all(|c| c.is_ascii_digit()) => layer,
+            _ => stem,
+        };
+        let name = identifier(stem);
+        let mut unique = name.clone();
+        for i in 2.. {
+            if !layers.iter().any(|(n, _)| *n == unique) {
                 break;
             }
-            let mut values: Vec<String> = Vec::with_capacity(column_count);
-            for i in 0..column_count {
-                let as_string: Result<String, _> = row.get(i);
-                let mut value = match as_string {
-                    Ok(s) => s,
-                    Err(_) => {
-                        let val = row.get_ref(i).unwrap();
-                        format!("{:?}", val)
-                    }
-                };
-                if value.len() > 80 {
-                    value = format!("{}…", &value[..80]);
-                }
-                if value.len() > widths[i] {
-                    widths[i] = value.len();
-                }
-                values.push(value);
-            }
-            rows_buffer.push(values);
-            count += 1;
+            unique = format!("{}_{}", name, i);
         }
+        layers.push((unique, entry.to_string()));
+    }
+    layers.sort();
+    Ok(layers)
+}
 
-        let max_col_width = 30;
-        for w in &mut widths {
-            if *w > max_col_width {
-                *w = max_col_width;
-            }
+/// Table name for a dataset such as `asia/indonesia` in `format` (`pbf`, `shp`, ...),
+/// e.g. `asia_indonesia_pbf`.
+pub fn dataset_table_name(dataset: &str, format: &str) -> String {
+    identifier(&format!("{}_{}", dataset, format))
+}
+
+/// Lowercase SQL identifier for free text: runs of anything but ASCII letters
+/// and digits become a single `_`.
+pub(crate) fn identifier(text: &str) -> String {
+    let mut name = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.is_empty() && !name.ends_with('_') {
+            name.push('_');
         }
+    }
+    name.truncate(name.trim_end_matches('_').len());
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Table name for a local file, from its name without the Geofabrik suffixes
+/// (`monaco-latest.osm.pbf` becomes `monaco_pbf`).
+pub fn file_table_name(path: &Path) -> String {
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let (stem, format) = if let Some(stem) = file_name.strip_suffix(".osm.pbf") {
+        (stem, "pbf")
+    } else if let Some(stem) = file_name.strip_suffix(".shp.zip") {
+        (stem, "shp")
+    } else {
+        match file_name.rsplit_once('.') {
+            Some((stem, ext)) => (stem, ext),
+            None => (file_name.as_ref(), "data"),
+        }
+    };
+    let stem = stem
+        .trim_end_matches("-free")
+        .trim_end_matches("-latest");
+    dataset_table_name(stem, format)
+}
+
+/// The ways to load the spatial extension that `config` allows, as a description
+/// and the SQL, in the order they are tried.
+fn spatial_sources(config: &SpatialConfig) -> Vec<(String, String)> {
+    let mut sources = Vec::new();
+    let bundled = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("extensions").join("spatial.duckdb_extension")))
+        .filter(|path| path.exists());
+    for path in config.extension.iter().chain(bundled.iter()) {
+        let path = path.to_string_lossy();
+        sources.push((path.to_string(), format!("LOAD {};", sql_string(&path))));
+    }
+    sources.push(("installed extension".to_string(), "LOAD spatial;".to_string()));
+    if config.install {
+        sources.push(("extension repository".to_string(), "INSTALL spatial; LOAD spatial;".to_string()));
+    }
+    sources
+}
+
+/// Keys whose closed ways are areas unless tagged `area=no`.
+const AREA_KEYS: [&str; 11] = [
+    "building", "landuse", "amenity", "leisure", "natural", "place", "shop", "tourism", "historic",
+    "military", "aeroway",
+];
+
+/// Builds (or rebuilds) the `geom` columns of the rows matching the filters: points
+/// for nodes, linestrings or polygons for ways, and (multi)polygons for
+/// multipolygon and boundary relations. Filters are SQL conditions on `id`.
+fn geometry_sql(schema: &str, nodes: &str, ways: &str, relations: &str) -> String {
+    let keys: Vec<String> = AREA_KEYS
+        .iter()
+        .map(|k| format!("ways.tags[{}] IS NOT NULL", sql_string(k)))
+        .collect();
+    let is_area = format!(
+        "ways.tags['area'] IS DISTINCT FROM 'no'
+         AND (ways.tags['area'] = 'yes' OR {})
+         AND ways.tags['natural'] IS DISTINCT FROM 'coastline'",
+        keys.join(" OR ")
+    );
+    format!(
+        "ALTER TABLE {s}.nodes ADD COLUMN IF NOT EXISTS geom GEOMETRY;
+         ALTER TABLE {s}.ways ADD COLUMN IF NOT EXISTS geom GEOMETRY;
+         ALTER TABLE {s}.relations ADD COLUMN IF NOT EXISTS geom GEOMETRY;
+         UPDATE {s}.nodes SET geom = ST_Point(lon, lat) WHERE {nodes};
+         UPDATE {s}.ways SET geom = NULL WHERE {ways};
+         UPDATE {s}.ways SET geom = CASE
+                WHEN ST_IsClosed(l.line) AND ST_NPoints(l.line) >= 4 AND {is_area} THEN ST_MakePolygon(l.line)
+                ELSE l.line
+             END
+         FROM (
+             SELECT wn.way_id, ST_MakeLine(list(n.geom ORDER BY wn.seq)) AS line
+             FROM {s}.way_nodes wn JOIN {s}.nodes n ON n.id = wn.node_id
+             WHERE wn.way_id IN (SELECT id FROM {s}.ways WHERE {ways})
+             GROUP BY wn.way_id
+             HAVING count(*) >= 2
+         ) l
+         WHERE ways.id = l.way_id;
+         UPDATE {s}.relations SET geom = NULL WHERE {relations};
+         UPDATE {s}.relations SET geom = a.area
+         FROM (
+             SELECT m.relation_id, ST_BuildArea(ST_Collect(list(
+                 CASE WHEN ST_Dimension(w.geom) = 2 THEN ST_ExteriorRing(w.geom) ELSE w.geom END
+             ))) AS area
+             FROM {s}.members m JOIN {s}.ways w ON w.id = m.member_id
+             WHERE m.member_type = 'way' AND w.geom IS NOT NULL
+               AND m.relation_id IN (
+                   SELECT id FROM {s}.relations
+                   WHERE tags['type'] IN ('multipolygon', 'boundary') AND ({relations})
+               )
+             GROUP BY m.relation_id
+         ) a
+         WHERE relations.id = a.relation_id;",
+        s = schema,
+        nodes = nodes,
+        ways = ways,
+        relations = relations,
+        is_area = is_area
+    )
+}
+
+pub(crate) fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
 
-        let mut output = String::new();
+fn delete_element_sql(schema: &str, element: &Element) -> String {
+    match element.kind.as_str() {
+        "node" => format!("DELETE FROM {}.nodes WHERE id = {};", schema, element.id),
+        "way" => format!(
+            "DELETE FROM {s}.ways WHERE id = {id}; DELETE FROM {s}.way_nodes WHERE way_id = {id};",
+            s = schema,
+            id = element.id
+        ),
+        _ => format!(
+            "DELETE FROM {s}.relations WHERE id = {id}; DELETE FROM {s}.members WHERE relation_id = {id};",
+            s = schema,
+            id = element.id
+        ),
+    }
+}
 
-        for (idx, name) in column_names.iter().enumerate() {
-            if idx > 0 {
-                output.push_str(" | ");
+/// `INSERT`s for an element; values are literals because the map can't be bound
+/// as a parameter.
+fn insert_element_sql(schema: &str, element: &Element) -> String {
+    let tags: Vec<String> = element
+        .tags
+        .iter()
+        .map(|(k, v)| format!("{}: {}", sql_string(k), sql_string(v)))
+        .collect();
+    let tags = format!("MAP {{{}}}::MAP(VARCHAR, VARCHAR)", tags.join(", "));
+    let float = |v: Option<f64>| v.map_or("NULL".to_string(), |v| v.to_string());
+    match element.kind.as_str() {
+        "node" => format!(
+            "INSERT INTO {}.nodes (id, tags, lat, lon) VALUES ({}, {}, {}, {});",
+            schema,
+            element.id,
+            tags,
+            float(element.lat),
+            float(element.lon)
+        ),
+        "way" => {
+            let mut sql = format!("INSERT INTO {}.ways (id, tags) VALUES ({}, {});", schema, element.id, tags);
+            if !element.refs.is_empty() {
+                let rows: Vec<String> = element
+                    .refs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, node)| format!("({}, {}, {})", element.id, i + 1, node))
+                    .collect();
+                sql.push_str(&format!(
+                    "INSERT INTO {}.way_nodes (way_id, seq, node_id) VALUES {};",
+                    schema,
+                    rows.join(", ")
+                ));
             }
-            let cell = if name.len() > widths[idx] {
-                &name[..widths[idx]]
-            } else {
-                name
-            };
-            let padded = format!("{:width$}", cell, width = widths[idx]);
-            output.push_str(&padded);
+            sql
         }
-        output.push('\n');
-
-        for (idx, w) in widths.iter().enumerate() {
-            if idx > 0 {
-                output.push_str("-+-");
+        _ => {
+            let mut sql = format!(
+                "INSERT INTO {}.relations (id, tags) VALUES ({}, {});",
+                schema, element.id, tags
+            );
+            if !element.refs.is_empty() {
+                let rows: Vec<String> = element
+                    .refs
+                    .iter()
+                    .zip(&element.ref_types)
+                    .zip(&element.ref_roles)
+                    .enumerate()
+                    .map(|(i, ((member, kind), role))| {
+                        format!("({}, {}, {}, {}, {})", element.id, i + 1, sql_string(kind), member, sql_string(role))
+                    })
+                    .collect();
+                sql.push_str(&format!(
+                    "INSERT INTO {}.members (relation_id, seq, member_type, member_id, role) VALUES {};",
+                    schema,
+                    rows.join(", ")
+                ));
             }
-            output.push_str(&"-".repeat(*w));
+            sql
         }
-        output.push('\n');
+    }
+}
 
-        let displayed_rows = rows_buffer.len();
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{pbf, replication};
 
-        for row_values in rows_buffer {
-            for (idx, value) in row_values.iter().enumerate() {
-                if idx > 0 {
-                    output.push_str(" | ");
-                }
-                let cell = if value.len() > widths[idx] {
-                    &value[..widths[idx]]
-                } else {
-                    value
-                };
-                let padded = format!("{:width$}", cell, width = widths[idx]);
-                output.push_str(&padded);
-            }
-            output.push('\n');
+    /// Stands in for the spatial extension, which can't be installed offline:
+    /// geometries become strings describing how they were built.
+    pub(crate) fn stub_spatial(db: &mut Database) {
+        db.spatial = SpatialStatus::Loaded("stub".to_string());
+        db.execute_batch(
+            "CREATE TYPE GEOMETRY AS VARCHAR;
+             CREATE MACRO ST_Point(x, y) AS 'POINT(' || x || ' ' || y || ')';
+             CREATE MACRO ST_MakeLine(points) AS 'LINE' || len(points);
+             CREATE MACRO ST_IsClosed(g) AS true;
+             CREATE MACRO ST_NPoints(g) AS regexp_extract(g, '[0-9]+')::INT;
+             CREATE MACRO ST_MakePolygon(g) AS 'POLYGON';
+             CREATE MACRO ST_Dimension(g) AS CASE WHEN g = 'POLYGON' THEN 2 ELSE 1 END;
+             CREATE MACRO ST_ExteriorRing(g) AS 'RING';
+             CREATE MACRO ST_Collect(parts) AS list_sort(parts)::VARCHAR;
+             CREATE MACRO ST_BuildArea(g) AS 'AREA' || g;",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_table_names() {
+        assert_eq!(dataset_table_name("asia/indonesia", "pbf"), "asia_indonesia_pbf");
+        assert_eq!(dataset_table_name("North America/us/Georgia", "shp"), "north_america_us_georgia_shp");
+        assert_eq!(dataset_table_name("2024 extract", "pbf"), "_2024_extract_pbf");
+        assert_eq!(file_table_name(Path::new("/tmp/monaco-latest.osm.pbf")), "monaco_pbf");
+        assert_eq!(file_table_name(Path::new("monaco-latest-free.shp.zip")), "monaco_shp");
+        assert_eq!(file_table_name(Path::new("parks.geojson")), "parks_geojson");
+    }
+
+    #[test]
+    fn test_registry_links_downloads_and_keeps_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        let file = DownloadedFile {
+            path: "/tmp/y-latest-free.shp.zip".into(),
+            url: "https://mirror/y-latest-free.shp.zip".to_string(),
+            size: 1024,
+            md5: "abc".to_string(),
+            verified: true,
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Wed, 01 May 2024 20:21:02 GMT".to_string()),
+            unchanged: false,
+        };
+        let failed = DownloadRecord::unfinished(
+            "https://a/x-latest.osm.pbf",
+            "asia/x",
+            &DownloadFormat::Pbf,
+            Duration::from_millis(1500),
+            DownloadOutcome::Failed,
+            Some("HTTP Error: 404 Not Found".to_string()),
+        );
+        let first = db.record_download(&failed).unwrap();
+        let mut completed = DownloadRecord::completed(&file, "asia/y", &DownloadFormat::Shapefile, Duration::from_secs(3));
+        completed.osm_timestamp = Some(1_714_594_862);
+        completed.replication_sequence = Some(4321);
+        let second = db.record_download(&completed).unwrap();
+        assert_eq!((first, second), (1, 2));
+        let rows = db
+            .query(
+                "SELECT url, region, format, local_path, size_bytes, md5, md5_verified, etag, last_modified,
+                        osm_timestamp, replication_sequence, duration_ms, outcome, error
+                 FROM downloads ORDER BY id",
+            )
+            .unwrap()
+            .rows;
+        let text = |row: &[Cell]| row.iter().map(Cell::to_string).collect::<Vec<_>>().join("|");
+        assert_eq!(
+            text(&rows[0]),
+            "https://a/x-latest.osm.pbf|asia/x|pbf|NULL|NULL|NULL|false|NULL|NULL|NULL|NULL|1500|failed|HTTP Error: 404 Not Found"
+        );
+        assert_eq!(
+            text(&rows[1]),
+            "https://mirror/y-latest-free.shp.zip|asia/y|shp|/tmp/y-latest-free.shp.zip|1024|abc|true|\"v1\"|\
+             Wed, 01 May 2024 20:21:02 GMT|2024-05-01 20:21:02|4321|3000|success|NULL"
+        );
+
+        db.execute_batch("CREATE TABLE asia_indonesia_pbf AS SELECT 1 AS id").unwrap();
+        assert!(db.table_exists("asia_indonesia_pbf").unwrap());
+        let outcome = db
+            .import_dataset("/does/not/exist.osm.pbf", "asia_indonesia_pbf", IfExists::Keep, Some(second), &db.import_control())
+            .unwrap();
+        assert_eq!(outcome, ImportOutcome::Kept("asia_indonesia_pbf".to_string()));
+        // Replacing or versioning has to read the file, which doesn't exist.
+        assert!(db
+            .import_dataset("/does/not/exist.osm.pbf", "asia_indonesia_pbf", IfExists::Version, None, &db.import_control())
+            .is_err());
+        assert!(!db.table_exists("asia_indonesia_pbf_v2").unwrap());
+    }
+
+    #[test]
+    fn test_tracked_regions_fall_due() {
+        let db = Database::new(Path::new(":memory:")).unwrap();
+        let download = |region: &str| DownloadRecord {
+            url: format!("https://a/{}-latest.osm.pbf", region),
+            region: Some(region.to_string()),
+            format: Some("pbf".to_string()),
+            ..Default::default()
+        };
+        let tracked = |region: &str| TrackedRegion {
+            region: region.to_string(),
+            format: "pbf".to_string(),
+            url: format!("https://a/{}-latest.osm.pbf", region),
+            table: dataset_table_name(region, "pbf"),
+            interval_hours: 24,
+            last_checked: None,
+            due: true,
+        };
+        db.record_download(&download("europe/monaco")).unwrap();
+        db.track_region(&tracked("europe/monaco")).unwrap();
+        db.track_region(&tracked("europe/andorra")).unwrap();
+        let due = |db: &Database| -> Vec<(String, bool)> {
+            db.tracked_regions().unwrap().into_iter().map(|r| (r.region, r.due)).collect()
+        };
+        // Monaco was downloaded before it was tracked, Andorra never was
+        assert_eq!(due(&db), [("europe/andorra".to_string(), true), ("europe/monaco".to_string(), false)]);
+
+        // Downloads of a tracked region check it, even failed ones
+        let mut failed = download("europe/andorra");
+        failed.outcome = DownloadOutcome::Failed;
+        db.record_download(&failed).unwrap();
+        assert!(due(&db).iter().all(|(_, due)| !due));
+        db.execute_batch("UPDATE tracked_regions SET last_checked = last_checked - INTERVAL 25 HOURS WHERE region = 'europe/monaco'")
+            .unwrap();
+        assert_eq!(due(&db)[0], ("europe/monaco".to_string(), true));
+
+        // Tracking again changes the interval but keeps the last check
+        let mut again = tracked("europe/monaco");
+        again.interval_hours = 48;
+        db.track_region(&again).unwrap();
+        let monaco = db.tracked_regions().unwrap().remove(0);
+        assert_eq!((monaco.interval_hours, monaco.due), (48, false));
+        assert!(monaco.last_checked.is_some());
+
+        assert!(db.untrack_region("europe/monaco", "pbf").unwrap());
+        assert!(!db.untrack_region("europe/monaco", "pbf").unwrap());
+        assert_eq!(db.tracked_regions().unwrap().len(), 1);
+    }
+
+    /// Writes elements (as osmChange XML) to `fixture.osm.pbf` in `dir`.
+    fn fixture_file(dir: &Path, xml: &str) -> String {
+        let path = dir.join("fixture.osm.pbf");
+        std::fs::write(&path, pbf::tests::write_pbf(&["OsmSchema-V0.6"], &pbf::tests::elements(xml))).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Imports elements (as osmChange XML) as `osm`.
+    pub(crate) fn import_fixture(db: &Database, xml: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        db.import_pbf(&fixture_file(dir.path(), xml), "osm", &db.import_control())
+            .unwrap();
+    }
+
+    /// A small PBF import named `osm`, built with `stub_spatial` geometries.
+    pub(crate) fn osm_fixture() -> Database {
+        let mut db = Database::new(Path::new(":memory:")).unwrap();
+        stub_spatial(&mut db);
+        import_fixture(
+            &db,
+            r#"<node id="1" lat="43.7" lon="7.4"/>
+               <node id="2" lat="43.8" lon="7.5"/>
+               <node id="3" lat="43.9" lon="7.6"/>
+               <node id="4" lat="43.6" lon="7.3"><tag k="amenity" v="cafe"/><tag k="name" v="Chez l'Ami"/></node>
+               <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/>
+                 <tag k="building" v="yes"/><tag k="addr:street" v="Rue Grimaldi"/></way>
+               <way id="11"><nd ref="1"/><nd ref="2"/><tag k="highway" v="track"/></way>
+               <way id="12"><nd ref="3"/><tag k="highway" v="track"/></way>
+               <relation id="20"><member type="way" ref="10" role="outer"/>
+                 <member type="way" ref="11" role="inner"/><tag k="type" v="multipolygon"/></relation>"#,
+        );
+        db.execute_batch(&geometry_sql("osm", "true", "true", "true"))
+            .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_import_pbf() {
+        let db = osm_fixture();
+        let counts = db.row_counts("osm").unwrap();
+        let counts: Vec<(&str, usize)> = counts.iter().map(|(t, n)| (t.as_str(), *n)).collect();
+        assert_eq!(
+            counts,
+            vec![("osm.members", 2), ("osm.nodes", 4), ("osm.relations", 1), ("osm.way_nodes", 7), ("osm.ways", 3)]
+        );
+        let node = db.query("SELECT tags['name'] AS name, lat, lon FROM osm.nodes WHERE id = 4").unwrap().to_string();
+        assert!(node.contains("Chez l'Ami") && node.contains("43.6") && node.contains("7.3"), "{}", node);
+        let untagged = db.query("SELECT cardinality(tags) AS n FROM osm.nodes WHERE id = 1").unwrap();
+        assert_eq!(untagged.rows, vec![vec![Cell::Int(0)]]);
+        let members = db
+            .query("SELECT seq || member_type || member_id || role AS m FROM osm.members ORDER BY seq")
+            .unwrap().to_string();
+        assert!(members.contains("1way10outer") && members.contains("2way11inner"), "{}", members);
+    }
+
+    #[test]
+    fn test_geometries_follow_changes() {
+        let db = osm_fixture();
+        let geometries = || {
+            db.query(
+                "SELECT id || '=' || coalesce(geom, '-') AS g FROM (
+                    SELECT id, geom FROM osm.nodes WHERE id = 2 UNION ALL
+                    SELECT id, geom FROM osm.ways UNION ALL
+                    SELECT id, geom FROM osm.relations)",
+            )
+            .unwrap().to_string()
+        };
+        // A closed building is a polygon, a single-node way has no geometry, and the
+        // multipolygon is built from the ring of its outer way and the inner line.
+        let before = geometries();
+        for expected in ["2=POINT(7.5 43.8)", "10=POLYGON", "11=LINE2", "12=-", "20=AREA[LINE2, RING]"] {
+            assert!(before.contains(expected), "{} missing in {}", expected, before);
         }
 
-        if truncated {
-            output.push_str("... (more rows truncated)\n");
+        let state = |sequence| replication::ReplicationState { sequence, timestamp: None };
+        db.record_replication("osm", "file:///updates", &state(1)).unwrap();
+        let changes = replication::parse_osc(
+            r#"<osmChange><modify><node id="2" lat="1" lon="2"/></modify>
+               <modify><way id="11"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way></modify></osmChange>"#,
+        )
+        .unwrap();
+        db.apply_changes("osm", &changes, &state(2)).unwrap();
+        let after = geometries();
+        for expected in ["2=POINT(2.0 1.0)", "10=POLYGON", "11=LINE3", "12=-", "20=AREA[LINE3, RING]"] {
+            assert!(after.contains(expected), "{} missing in {}", expected, after);
         }
+    }
 
-        let rows_label = if truncated {
-            format!("Rows: {}+ (showing {})", displayed_rows, displayed_rows)
-        } else {
-            format!("Rows: {}", displayed_rows)
+    #[test]
+    fn test_typed_and_paged_queries() {
+        let db = Database::new(Path::new(":memory:")).unwrap();
+        let sql = "SELECT i AS id, 'n' || i AS name, i / 2 AS half, NULL AS nothing, DATE '2024-05-01' + i::INTEGER AS day,
+                   MAP {'k': i::VARCHAR} AS tags FROM range(1, 1001) t(i) ORDER BY i;";
+        let (page, pageable) = db.query_page(sql, 500, 2).unwrap();
+        assert!(pageable);
+        assert_eq!(db.count_rows(sql).unwrap(), 1000);
+        let types: Vec<&str> = page.columns.iter().map(|c| c.type_name.as_str()).collect();
+        assert_eq!(types, ["BIGINT", "VARCHAR", "DOUBLE", "INTEGER", "DATE", "MAP"]);
+        assert_eq!(page.offset, 500);
+        assert_eq!(
+            page.rows[0],
+            vec![
+                Cell::Int(501),
+                Cell::Text("n501".to_string()),
+                Cell::Float(250.5),
+                Cell::Null,
+                Cell::Text("2025-09-14".to_string()),
+                Cell::Text("{k=501}".to_string()),
+            ]
+        );
+        assert_eq!(page.rows.len(), 2);
+
+        // Statements that can't be a subquery run unpaged
+        let (pragma, pageable) = db.query_page("PRAGMA database_list", 0, 1).unwrap();
+        assert!(!pageable);
+        assert!(pragma.to_string().contains("memory"), "{}", pragma);
+        assert!(db.query("SELECT * FROM missing").is_err());
+    }
+
+    #[test]
+    fn test_import_progress_and_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        let file = fixture_file(dir.path(), r#"<node id="1" lat="1" lon="2"/><node id="2" lat="3" lon="4"/>"#);
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let control = db
+            .import_control()
+            .on_progress(move |p| seen.lock().unwrap().push(p.clone()));
+        db.import_dataset(&file, "osm", IfExists::Replace, Some(1), &control)
+            .unwrap();
+        let reports = reports.lock().unwrap();
+        let size = std::fs::metadata(&file).unwrap().len();
+        assert!(reports.contains(&ImportProgress { phase: ImportPhase::Reading, done: size, total: size, rows: 2 }));
+        assert_eq!(reports.last().unwrap().phase, ImportPhase::Tables);
+
+        // Cancelled part way: the first import and its registry row are untouched.
+        let file = fixture_file(dir.path(), r#"<node id="3" lat="1" lon="2"/>"#);
+        let control = db.import_control();
+        let handle = control.clone();
+        let control = control.on_progress(move |_| handle.cancel());
+        let error = db
+            .import_dataset(&file, "osm", IfExists::Replace, None, &control)
+            .unwrap_err();
+        assert!(error.is::<ImportCancelled>(), "{:#}", error);
+        assert_eq!(db.row_counts("osm").unwrap()[1], ("osm.nodes".to_string(), 2));
+        let registry = db.query("SELECT download_id FROM datasets").unwrap();
+        assert_eq!(registry.rows, vec![vec![Cell::Int(1)]]);
+        // The same file name, so this is where `osm` came from
+        assert_eq!(db.imported_from(&file).unwrap().as_deref(), Some("osm"));
+        assert!(db.holds_import_of("osm", &file).unwrap());
+        assert!(!db.holds_import_of("osm_copy", &file).unwrap());
+        db.execute_batch("DROP SCHEMA osm CASCADE").unwrap();
+        assert_eq!(db.imported_from(&file).unwrap(), None);
+        assert!(!db.holds_import_of("osm", &file).unwrap());
+    }
+
+    #[test]
+    fn test_spatial_sources_and_gating() {
+        let config = SpatialConfig {
+            extension: Some("/opt/spatial.duckdb_extension".into()),
+            install: false,
         };
-        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
-        let summary = format!("{} | Columns: {} | Time: {:.2} ms", rows_label, column_count, elapsed_ms);
-        output.push_str(&summary);
-        output.push('\n');
+        let sources: Vec<String> = spatial_sources(&config).into_iter().map(|(source, _)| source).collect();
+        assert_eq!(sources, vec!["/opt/spatial.duckdb_extension", "installed extension"]);
+        let sources = spatial_sources(&SpatialConfig::default());
+        assert_eq!(sources.last().unwrap().1, "INSTALL spatial; LOAD spatial;");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Database::new(Path::new(":memory:")).unwrap();
+        db.spatial = SpatialStatus::Unavailable("offline".to_string());
+        db.set_layers(crate::layers::default_layers());
+        db.execute_batch("CREATE TABLE parks_geojson AS SELECT 1 AS id").unwrap();
+        let geojson = dir.path().join("parks.geojson");
+        std::fs::write(&geojson, "{}").unwrap();
+        let error = db
+            .import_data(geojson.to_str().unwrap(), "parks_geojson", &db.import_control())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Shapefile and GeoJSON imports need the spatial extension, which isn't available (offline)"
+        );
+        assert!(db.table_exists("parks_geojson").unwrap(), "the old table is kept");
+
+        // PBF files still import, without geometries or layers.
+        let file = fixture_file(dir.path(), r#"<node id="1" lat="1" lon="2"/>"#);
+        db.import_data(&file, "osm", &db.import_control()).unwrap();
+        assert!(!db.has_geometries("osm").unwrap());
+        assert_eq!(db.row_counts("osm").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_shapefile_zip_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("monaco-latest-free.shp.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for entry in [
+            "README",
+            "gis_osm_roads_free_1.shp",
+            "gis_osm_roads_free_1.dbf",
+            "gis_osm_pois_free_1.shp",
+            "gis_osm_pois_a_free_1.shp",
+            "extra/Parks 2024.SHP",
+        ] {
+            zip.start_file(entry, options).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let layers = zip_shapefiles(&path).unwrap();
+        let names: Vec<&str> = layers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["parks_2024", "pois", "pois_a", "roads"]);
+        assert_eq!(layers[0].1, "extra/Parks 2024.SHP");
 
-        Ok(output)
+        // Layers land in a schema named after the dataset.
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        db.execute_batch(
+            "CREATE SCHEMA monaco_shp;
+             CREATE TABLE monaco_shp.roads AS SELECT * FROM range(3);
+             CREATE TABLE monaco_shp.pois AS SELECT * FROM range(5);",
+        )
+        .unwrap();
+        assert_eq!(db.preview_table("monaco_shp").unwrap(), "monaco_shp.pois");
+        assert_eq!(
+            db.row_counts("monaco_shp").unwrap(),
+            vec![("monaco_shp.pois".to_string(), 5), ("monaco_shp.roads".to_string(), 3)]
+        );
+        assert!(!db.is_osm_dataset("monaco_shp").unwrap());
+        let outcome = db
+            .import_dataset(path.to_str().unwrap(), "monaco_shp", IfExists::Keep, None, &db.import_control())
+            .unwrap();
+        assert_eq!(outcome, ImportOutcome::Kept("monaco_shp".to_string()));
     }
 }
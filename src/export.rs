@@ -0,0 +1,205 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use crate::db::{sql_string, Database};
+use crate::results::Column;
+
+/// File formats query results can be written to. CSV and Parquet are written by
+/// DuckDB itself, the others through GDAL and need the spatial extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    /// GeoParquet when the result has a geometry column
+    Parquet,
+    #[value(name = "geojson")]
+    GeoJson,
+    #[value(name = "fgb")]
+    FlatGeobuf,
+    #[value(name = "gpkg")]
+    GeoPackage,
+}
+
+/// Column types GDAL can't store; they are written as their text form instead.
+const TEXT_ONLY_TYPES: [&str; 8] = ["MAP", "LIST", "ARRAY", "STRUCT", "UNION", "HUGEINT", "UHUGEINT", "INTERVAL"];
+
+impl ExportFormat {
+    /// The format a file name asks for, going by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match extension.as_str() {
+            "csv" => ExportFormat::Csv,
+            "parquet" | "geoparquet" => ExportFormat::Parquet,
+            "geojson" | "json" => ExportFormat::GeoJson,
+            "fgb" => ExportFormat::FlatGeobuf,
+            "gpkg" => ExportFormat::GeoPackage,
+            _ => return None,
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            ExportFormat::Csv => "CSV",
+            ExportFormat::Parquet => "Parquet",
+            ExportFormat::GeoJson => "GeoJSON",
+            ExportFormat::FlatGeobuf => "FlatGeobuf",
+            ExportFormat::GeoPackage => "GeoPackage",
+        }
+    }
+
+    /// The GDAL driver writing this format, if DuckDB can't write it on its own.
+    fn gdal_driver(&self) -> Option<&str> {
+        match self {
+            ExportFormat::Csv | ExportFormat::Parquet => None,
+            ExportFormat::GeoJson => Some("GeoJSON"),
+            ExportFormat::FlatGeobuf => Some("FlatGeobuf"),
+            ExportFormat::GeoPackage => Some("GPKG"),
+        }
+    }
+}
+
+/// Writes the rows of `sql` (a query, or `SELECT * FROM` a table) to `path` and
+/// returns how many were written. Geometry columns are written as WKT to CSV, as
+/// GeoParquet to Parquet, and as the feature geometry of the GDAL formats.
+pub fn export(db: &Database, sql: &str, path: &Path, format: ExportFormat) -> Result<usize> {
+    let (empty, pageable) = db.query_page(sql, 0, 0)?;
+    if !pageable {
+        bail!("Only queries can be exported, not other statements");
+    }
+    let geometries = empty.columns.iter().filter(|c| is_geometry(c)).count();
+    if format.gdal_driver().is_some() {
+        db.require_spatial(&format!("{} exports", format.label()))?;
+        if geometries == 0 {
+            bail!("{} needs a geometry column; export to CSV or Parquet instead", format.label());
+        }
+        // GDAL won't replace an existing file
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    } else if geometries > 0 {
+        db.require_spatial("Exports of geometry columns")?;
+    }
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let copy = copy_sql(sql, &empty.columns, path, format);
+    tracing::info!("Exporting to {:?}: {}", path, copy);
+    db.execute(&copy)
+}
+
+fn copy_sql(sql: &str, columns: &[Column], path: &Path, format: ExportFormat) -> String {
+    let mut first_geometry = true;
+    let projection: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            let name = quoted(&column.name);
+            match format.gdal_driver() {
+                // GDAL writes the first geometry as the feature geometry, the others as text
+                Some(_) if is_geometry(column) && std::mem::take(&mut first_geometry) => name,
+                Some(_) if is_geometry(column) => format!("ST_AsText({}) AS {}", name, name),
+                Some(_) if TEXT_ONLY_TYPES.contains(&column.type_name.as_str()) => {
+                    format!("CAST({} AS VARCHAR) AS {}", name, name)
+                }
+                None if format == ExportFormat::Csv && is_geometry(column) => {
+                    format!("ST_AsText({}) AS {}", name, name)
+                }
+                _ => name,
+            }
+        })
+        .collect();
+    let options = match format.gdal_driver() {
+        None if format == ExportFormat::Csv => "FORMAT CSV, HEADER".to_string(),
+        None => "FORMAT PARQUET".to_string(),
+        // OSM data and the shapefiles from Geofabrik are all WGS 84
+        Some(driver) => format!("FORMAT GDAL, DRIVER '{}', SRS 'EPSG:4326'", driver),
+    };
+    format!(
+        "COPY (SELECT {} FROM (\n{}\n)) TO {} ({})",
+        projection.join(", "),
+        sql.trim().trim_end_matches(';').trim_end(),
+        sql_string(&path.to_string_lossy()),
+        options
+    )
+}
+
+fn is_geometry(column: &Column) -> bool {
+    column.type_name == "GEOMETRY"
+}
+
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, type_name: &str) -> Column {
+        Column {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ExportFormat::from_path(Path::new("roads.GeoJSON")), Some(ExportFormat::GeoJson));
+        assert_eq!(ExportFormat::from_path(Path::new("out/pois.fgb")), Some(ExportFormat::FlatGeobuf));
+        assert_eq!(ExportFormat::from_path(Path::new("monaco.gpkg")), Some(ExportFormat::GeoPackage));
+        assert_eq!(ExportFormat::from_path(Path::new("a.parquet")), Some(ExportFormat::Parquet));
+        assert_eq!(ExportFormat::from_path(Path::new("a.txt")), None);
+        assert_eq!(ExportFormat::from_path(Path::new("csv")), None);
+    }
+
+    #[test]
+    fn test_geometry_columns_per_format() {
+        let columns = [
+            column("id", "BIGINT"),
+            column("geom", "GEOMETRY"),
+            column("tags", "MAP"),
+            column("centroid", "GEOMETRY"),
+        ];
+        let path = Path::new("/tmp/it's.gpkg");
+        let sql = copy_sql("SELECT * FROM osm.ways;", &columns, path, ExportFormat::GeoPackage);
+        assert_eq!(
+            sql,
+            "COPY (SELECT \"id\", \"geom\", CAST(\"tags\" AS VARCHAR) AS \"tags\", \
+             ST_AsText(\"centroid\") AS \"centroid\" FROM (\nSELECT * FROM osm.ways\n)) \
+             TO '/tmp/it''s.gpkg' (FORMAT GDAL, DRIVER 'GPKG', SRS 'EPSG:4326')"
+        );
+        let csv = copy_sql("SELECT * FROM osm.ways", &columns, Path::new("w.csv"), ExportFormat::Csv);
+        assert!(csv.contains("ST_AsText(\"geom\") AS \"geom\", \"tags\", ST_AsText(\"centroid\")"), "{}", csv);
+        assert!(csv.ends_with("(FORMAT CSV, HEADER)"), "{}", csv);
+        let parquet = copy_sql("SELECT * FROM osm.ways", &columns, Path::new("w.parquet"), ExportFormat::Parquet);
+        assert!(parquet.starts_with("COPY (SELECT \"id\", \"geom\", \"tags\", \"centroid\" FROM"), "{}", parquet);
+    }
+
+    #[test]
+    fn test_export_csv_and_parquet() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        db.execute_batch("CREATE TABLE places AS SELECT i AS id, 'place ' || i AS \"name, quoted\" FROM range(3) t(i)")
+            .unwrap();
+
+        let csv = dir.path().join("places.csv");
+        let rows = export(&db, "SELECT * FROM places WHERE id > 0;", &csv, ExportFormat::Csv).unwrap();
+        assert_eq!(rows, 2);
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap(),
+            "id,\"name, quoted\"\n1,place 1\n2,place 2\n"
+        );
+
+        let parquet = dir.path().join("places.parquet");
+        assert_eq!(export(&db, "SELECT * FROM places", &parquet, ExportFormat::Parquet).unwrap(), 3);
+        let back = db
+            .query(&format!("SELECT count(*) FROM read_parquet({})", sql_string(&parquet.to_string_lossy())))
+            .unwrap();
+        assert_eq!(back.rows, vec![vec![crate::results::Cell::Int(3)]]);
+
+        // GDAL formats need the spatial extension and a geometry column
+        let geojson = dir.path().join("places.geojson");
+        let error = export(&db, "SELECT * FROM places", &geojson, ExportFormat::GeoJson).unwrap_err();
+        assert!(error.to_string().contains("GeoJSON exports need the spatial extension"), "{}", error);
+        let error = export(&db, "PRAGMA database_list", &csv, ExportFormat::Csv).unwrap_err();
+        assert!(error.to_string().contains("Only queries"), "{}", error);
+    }
+}
@@ -0,0 +1,408 @@
+use anyhow::{anyhow, bail, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use crate::db::{Database, DownloadRecord, IfExists, ImportControl, ImportOutcome};
+use crate::network::meta_path;
+use crate::replication::{self, ReplicationState};
+
+/// Suffixes of the files a download keeps next to the extract.
+const SIDECARS: [&str; 2] = [".part", ".meta"];
+
+/// Whether a file's MD5 is known to be right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// Matched the checksum the mirror published when it was downloaded.
+    Verified,
+    /// Recorded at download time, but the mirror published none to compare with.
+    Unverified,
+    /// No download of the file was recorded, e.g. because it was copied in by hand.
+    Unknown,
+    /// Hashed again and still the file that was downloaded.
+    Intact,
+    /// Hashed again and changed since it was downloaded.
+    Corrupt,
+}
+
+impl Checksum {
+    pub fn label(&self) -> &str {
+        match self {
+            Checksum::Verified => "verified",
+            Checksum::Unverified => "unverified",
+            Checksum::Unknown => "unknown",
+            Checksum::Intact => "intact",
+            Checksum::Corrupt => "CORRUPT",
+        }
+    }
+}
+
+/// An extract in the download directory.
+#[derive(Debug, Clone)]
+pub struct LocalFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// The last download that left this file, and its id in `downloads`.
+    pub download: Option<(i64, DownloadRecord)>,
+    pub checksum: Checksum,
+    /// Dataset last imported from the file, if it still exists.
+    pub table: Option<String>,
+}
+
+impl LocalFile {
+    pub fn name(&self) -> String {
+        self.path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned())
+    }
+
+    fn record(&self) -> Option<&DownloadRecord> {
+        self.download.as_ref().map(|(_, record)| record)
+    }
+
+    /// Region path the file was downloaded for, e.g. `europe/monaco`.
+    pub fn region(&self) -> Option<&str> {
+        self.record()?.region.as_deref()
+    }
+
+    /// `pbf` or `shp`, from the download or else the file name.
+    pub fn format(&self) -> Option<&str> {
+        if let Some(format) = self.record().and_then(|r| r.format.as_deref()) {
+            return Some(format);
+        }
+        let name = self.name();
+        if name.ends_with(".osm.pbf") {
+            Some("pbf")
+        } else if name.ends_with(".shp.zip") {
+            Some("shp")
+        } else {
+            None
+        }
+    }
+
+    /// Table a re-import writes to: the one imported from the file last, or else
+    /// one named after its region or file name.
+    pub fn import_table(&self) -> String {
+        if let Some(table) = &self.table {
+            return table.clone();
+        }
+        match (self.region(), self.format()) {
+            (Some(region), Some(format)) => crate::db::dataset_table_name(region, format),
+            _ => crate::db::file_table_name(&self.path),
+        }
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.modified).unwrap_or_default()
+    }
+}
+
+/// The finished downloads in `dir`, newest first, with what the database knows
+/// about them. `.part`, `.part.meta` and `.meta` sidecars are left out.
+pub fn list(db: &Database, dir: &Path) -> Result<Vec<LocalFile>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || SIDECARS.iter().any(|suffix| name.ends_with(suffix)) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let path_str = path.to_string_lossy();
+        let download = db.download_of(&path_str)?;
+        let checksum = match download.as_ref().map(|(_, record)| record) {
+            Some(record) if record.md5_verified => Checksum::Verified,
+            Some(record) if record.md5.is_some() => Checksum::Unverified,
+            _ => Checksum::Unknown,
+        };
+        files.push(LocalFile {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            table: db.imported_from(&path_str)?,
+            path,
+            download,
+            checksum,
+        });
+    }
+    files.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.path.cmp(&b.path)));
+    Ok(files)
+}
+
+/// Hashes `file` again and compares it with the MD5 recorded when it was downloaded.
+pub fn verify(file: &LocalFile) -> Result<Checksum> {
+    let Some(expected) = file.record().and_then(|r| r.md5.clone()) else {
+        return Ok(Checksum::Unknown);
+    };
+    let mut hasher = md5::Context::new();
+    let mut reader = std::fs::File::open(&file.path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.consume(&buf[..n]);
+    }
+    let actual = format!("{:x}", hasher.compute());
+    Ok(if actual == expected { Checksum::Intact } else { Checksum::Corrupt })
+}
+
+/// Deletes a downloaded file and the `.meta` sidecar its next download would
+/// compare against. Rows in `downloads` and imported tables are kept.
+pub fn delete(path: &Path) -> Result<()> {
+    std::fs::remove_file(path)?;
+    match std::fs::remove_file(meta_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Imports `file` again, replacing the table it went into before. Its data is as
+/// old as the file, so updates continue from the replication sequence in its
+/// header rather than the latest one.
+pub fn reimport(db: &Database, file: &LocalFile, control: &ImportControl) -> Result<ImportOutcome> {
+    let download_id = file.download.as_ref().map(|(id, _)| *id);
+    let outcome = db.import_dataset(
+        &file.path.to_string_lossy(),
+        &file.import_table(),
+        IfExists::Replace,
+        download_id,
+        control,
+    )?;
+    if let (ImportOutcome::Imported { table, .. }, Some(record)) = (&outcome, file.record()) {
+        let header = ReplicationState::from_header(record);
+        if let (Some(updates), Some(state)) = (replication::updates_url(&record.url), header) {
+            db.record_replication(table, &updates, &state)?;
+        }
+    }
+    Ok(outcome)
+}
+
+/// Shows `path` in the system's file manager: selected in Finder or Explorer, or
+/// its directory opened with `xdg-open` elsewhere.
+pub fn reveal(path: &Path) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("open");
+        command.arg("-R").arg(path);
+        command
+    } else if cfg!(windows) {
+        let mut command = Command::new("explorer");
+        command.arg(format!("/select,{}", path.display()));
+        command
+    } else {
+        let mut command = Command::new("xdg-open");
+        command.arg(path.parent().unwrap_or(path));
+        command
+    };
+    // The TUI owns the terminal, so the opener mustn't write to it
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// What a clean-up of the download directory removes: files older than `max_age`,
+/// then the oldest of the rest until they fit in `quota` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CleanRule {
+    pub max_age: Option<Duration>,
+    pub quota: Option<u64>,
+}
+
+impl CleanRule {
+    /// Parses an age in days and/or a quota, e.g. `30d`, `20G` or `30d 500M`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rule = CleanRule::default();
+        for part in text.split_whitespace() {
+            let upper = part.to_ascii_uppercase();
+            let split = upper.find(|c: char| !c.is_ascii_digit()).unwrap_or(upper.len());
+            let (number, unit) = upper.split_at(split);
+            let too_large = || anyhow!("'{}' is too large", part);
+            let number: u64 = match number {
+                "" => bail!("'{}' doesn't start with a number", part),
+                digits => digits.parse().map_err(|_| too_large())?,
+            };
+            let scale = |factor: u64| number.checked_mul(factor).ok_or_else(too_large);
+            match unit.trim_end_matches('B') {
+                "D" => rule.max_age = Some(Duration::from_secs(scale(24 * 3600)?)),
+                "" => rule.quota = Some(number),
+                "K" => rule.quota = Some(scale(1 << 10)?),
+                "M" => rule.quota = Some(scale(1 << 20)?),
+                "G" => rule.quota = Some(scale(1 << 30)?),
+                "T" => rule.quota = Some(scale(1 << 40)?),
+                _ => bail!("'{}' is neither an age in days (30d) nor a size (500M, 20G)", part),
+            }
+        }
+        if rule == CleanRule::default() {
+            bail!("Give an age in days (30d), a disk quota (20G) or both");
+        }
+        Ok(rule)
+    }
+
+    /// The files of `files` to delete, oldest first.
+    pub fn select<'a>(&self, files: &'a [LocalFile], now: SystemTime) -> Vec<&'a LocalFile> {
+        let mut oldest_first: Vec<&LocalFile> = files.iter().collect();
+        oldest_first.sort_by_key(|f| f.modified);
+        let mut total: u64 = files.iter().map(|f| f.size).sum();
+        oldest_first
+            .into_iter()
+            .filter(|file| {
+                let too_old = self.max_age.is_some_and(|max| file.age(now) > max);
+                let over_quota = self.quota.is_some_and(|quota| total > quota);
+                if too_old || over_quota {
+                    total -= file.size;
+                }
+                too_old || over_quota
+            })
+            .collect()
+    }
+}
+
+/// State of the Files tab.
+#[derive(Default)]
+pub struct FileList {
+    pub files: Vec<LocalFile>,
+    /// Cursor in the list.
+    pub selected: usize,
+    /// Whether the list is being read again.
+    pub loading: bool,
+}
+
+impl FileList {
+    /// Replaces the files, keeping the cursor on the same file if it is still there.
+    pub fn set_files(&mut self, files: Vec<LocalFile>) {
+        let selected = self.selected_file().map(|f| f.path.clone());
+        self.selected = selected
+            .and_then(|path| files.iter().position(|f| f.path == path))
+            .unwrap_or(self.selected)
+            .min(files.len().saturating_sub(1));
+        self.files = files;
+    }
+
+    pub fn selected_file(&self) -> Option<&LocalFile> {
+        self.files.get(self.selected)
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.files.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// Files waiting for the user to confirm that they should be deleted.
+pub struct PendingDeletion {
+    pub question: String,
+    pub paths: Vec<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{DownloadFormat, DownloadedFile};
+
+    #[test]
+    fn test_list_verify_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        let downloads = dir.path().join("downloads");
+        std::fs::create_dir(&downloads).unwrap();
+        let write = |name: &str, body: &[u8]| {
+            let path = downloads.join(name);
+            std::fs::write(&path, body).unwrap();
+            path
+        };
+        let monaco = write("monaco-latest.osm.pbf", b"monaco");
+        write("monaco-latest.osm.pbf.meta", b"{}");
+        write("andorra-latest.osm.pbf.part", b"and");
+        write("andorra-latest.osm.pbf.part.meta", b"{}");
+        write("parks.geojson", b"{}");
+        let file = DownloadedFile {
+            path: monaco.clone(),
+            url: "https://a/europe/monaco-latest.osm.pbf".to_string(),
+            size: 6,
+            md5: format!("{:x}", md5::compute(b"monaco")),
+            verified: true,
+            ..Default::default()
+        };
+        let record = DownloadRecord::completed(&file, "europe/monaco", &DownloadFormat::Pbf, Duration::ZERO);
+        let id = db.record_download(&record).unwrap();
+
+        let files = list(&db, &downloads).unwrap();
+        let names: Vec<String> = files.iter().map(LocalFile::name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["monaco-latest.osm.pbf", "parks.geojson"]);
+        let monaco_file = files.iter().find(|f| f.path == monaco).unwrap();
+        assert_eq!(monaco_file.download.as_ref().map(|(id, _)| *id), Some(id));
+        assert_eq!((monaco_file.region(), monaco_file.format()), (Some("europe/monaco"), Some("pbf")));
+        assert_eq!(monaco_file.checksum, Checksum::Verified);
+        assert_eq!(monaco_file.import_table(), "europe_monaco_pbf");
+        let parks = files.iter().find(|f| f.path != monaco).unwrap();
+        assert_eq!((parks.checksum, parks.format(), parks.import_table()), (Checksum::Unknown, None, "parks_geojson".to_string()));
+
+        assert_eq!(verify(monaco_file).unwrap(), Checksum::Intact);
+        std::fs::write(&monaco, b"monacO").unwrap();
+        assert_eq!(verify(monaco_file).unwrap(), Checksum::Corrupt);
+        assert_eq!(verify(parks).unwrap(), Checksum::Unknown);
+
+        delete(&monaco).unwrap();
+        assert!(!monaco.exists());
+        assert!(!downloads.join("monaco-latest.osm.pbf.meta").exists());
+        assert!(downloads.join("andorra-latest.osm.pbf.part").exists());
+        assert_eq!(list(&db, &downloads).unwrap().len(), 1);
+        assert!(list(&db, &dir.path().join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clean_rules() {
+        assert_eq!(
+            CleanRule::parse("30d 500M").unwrap(),
+            CleanRule { max_age: Some(Duration::from_secs(30 * 86400)), quota: Some(500 << 20) }
+        );
+        assert_eq!(CleanRule::parse("20gb").unwrap().quota, Some(20 << 30));
+        assert!(CleanRule::parse("").is_err());
+        assert!(CleanRule::parse("30 days").is_err());
+        assert!(CleanRule::parse("d").is_err());
+        assert!(CleanRule::parse("99999999999999999d").is_err());
+        assert!(CleanRule::parse("99999999T").is_err());
+        assert!(CleanRule::parse("99999999999999999999").is_err());
+
+        let now = SystemTime::now();
+        let day = Duration::from_secs(86400);
+        let file = |name: &str, days: u32, size: u64| LocalFile {
+            path: PathBuf::from(name),
+            size,
+            modified: now - day * days,
+            download: None,
+            checksum: Checksum::Unknown,
+            table: None,
+        };
+        let files = [file("new", 1, 300), file("old", 40, 100), file("mid", 10, 500)];
+        let names = |rule: CleanRule| -> Vec<String> {
+            rule.select(&files, now).iter().map(|f| f.name()).collect()
+        };
+        assert_eq!(names(CleanRule::parse("30d").unwrap()), ["old"]);
+        // 900 bytes in all: the oldest go until the rest fit
+        assert_eq!(names(CleanRule::parse("800").unwrap()), ["old"]);
+        assert_eq!(names(CleanRule::parse("700").unwrap()), ["old", "mid"]);
+        assert_eq!(names(CleanRule::parse("5d 1K").unwrap()), ["old", "mid"]);
+        assert!(names(CleanRule::parse("1T").unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::db::{identifier, sql_string};
+
+/// Names taken by the tables a PBF import is split into, and by the fixed
+/// columns of every layer.
+const RESERVED_TABLES: [&str; 5] = ["nodes", "ways", "way_nodes", "relations", "members"];
+const RESERVED_COLUMNS: [&str; 3] = ["osm_type", "osm_id", "geom"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeometryKind {
+    /// Tagged nodes.
+    Point,
+    /// Ways that aren't areas.
+    Line,
+    /// Closed ways that are areas, and multipolygon relations.
+    Polygon,
+}
+
+/// A thematic table built from a PBF import, such as `roads` or `buildings`.
+///
+/// ```toml
+/// [[layers]]
+/// name = "roads"
+/// geometry = "line"                  # point, line or polygon
+/// columns = ["name", "highway", "maxspeed"]
+/// filter = { highway = [] }          # tag key -> accepted values, [] for any
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub name: String,
+    pub geometry: GeometryKind,
+    /// Features with any of these tags belong to the layer; an empty list of
+    /// values accepts any value.
+    pub filter: BTreeMap<String, Vec<String>>,
+    /// Tags copied into columns, named after the tag with `:` and other
+    /// punctuation replaced by `_` (`addr:street` becomes `addr_street`).
+    #[serde(default)]
+    pub columns: Vec<String>,
+}
+
+impl Layer {
+    fn new(name: &str, geometry: GeometryKind, filter: &[(&str, &[&str])], columns: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            geometry,
+            filter: filter
+                .iter()
+                .map(|(key, values)| (key.to_string(), values.iter().map(|v| v.to_string()).collect()))
+                .collect(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !is_identifier(&self.name) {
+            bail!("layer name '{}' must be lowercase letters, digits and _", self.name);
+        }
+        if RESERVED_TABLES.contains(&self.name.as_str()) {
+            bail!("layer name '{}' is taken by the imported OSM tables", self.name);
+        }
+        if self.filter.is_empty() {
+            bail!("layer '{}' needs at least one tag in its filter", self.name);
+        }
+        let mut seen = Vec::new();
+        for column in self.columns.iter().map(|c| identifier(c)) {
+            if column.is_empty() || RESERVED_COLUMNS.contains(&column.as_str()) || seen.contains(&column) {
+                bail!("layer '{}' has an invalid or duplicate column '{}'", self.name, column);
+            }
+            seen.push(column);
+        }
+        Ok(())
+    }
+
+    /// `CREATE TABLE` for this layer inside the schema of a PBF import.
+    pub fn create_sql(&self, schema: &str) -> String {
+        let conditions: Vec<String> = self
+            .filter
+            .iter()
+            .map(|(key, values)| {
+                let tag = format!("tags[{}]", sql_string(key));
+                if values.is_empty() {
+                    format!("{} IS NOT NULL", tag)
+                } else {
+                    let values: Vec<String> = values.iter().map(|v| sql_string(v)).collect();
+                    format!("{} IN ({})", tag, values.join(", "))
+                }
+            })
+            .collect();
+        let filter = conditions.join(" OR ");
+        let mut columns = vec!["osm_type".to_string(), "id AS osm_id".to_string()];
+        columns.extend(
+            self.columns
+                .iter()
+                .map(|tag| format!("tags[{}] AS \"{}\"", sql_string(tag), identifier(tag))),
+        );
+        columns.push("geom".to_string());
+        let select = |osm_type: &str, table: &str, condition: &str| {
+            format!(
+                "SELECT {} FROM (SELECT '{}' AS osm_type, * FROM {}.{}) WHERE geom IS NOT NULL{} AND ({})",
+                columns.join(", "),
+                osm_type,
+                schema,
+                table,
+                condition,
+                filter
+            )
+        };
+        let query = match self.geometry {
+            GeometryKind::Point => select("node", "nodes", ""),
+            GeometryKind::Line => select("way", "ways", " AND ST_Dimension(geom) = 1"),
+            GeometryKind::Polygon => format!(
+                "{} UNION ALL {}",
+                select("way", "ways", " AND ST_Dimension(geom) = 2"),
+                select("relation", "relations", "")
+            ),
+        };
+        format!("CREATE OR REPLACE TABLE {}.{} AS {};", schema, self.name, query)
+    }
+}
+
+/// Layers similar to those of Geofabrik's free shapefiles.
+pub fn default_layers() -> Vec<Layer> {
+    use GeometryKind::*;
+    vec![
+        Layer::new(
+            "roads",
+            Line,
+            &[("highway", &[])],
+            &["name", "highway", "ref", "oneway", "maxspeed", "bridge", "tunnel"],
+        ),
+        Layer::new(
+            "railways",
+            Line,
+            &[("railway", &["rail", "light_rail", "subway", "tram", "narrow_gauge", "monorail"])],
+            &["name", "railway"],
+        ),
+        Layer::new("waterways", Line, &[("waterway", &[])], &["name", "waterway"]),
+        Layer::new(
+            "buildings",
+            Polygon,
+            &[("building", &[])],
+            &["name", "building", "addr:housenumber", "addr:street"],
+        ),
+        Layer::new("landuse", Polygon, &[("landuse", &[])], &["name", "landuse"]),
+        Layer::new(
+            "water",
+            Polygon,
+            &[("natural", &["water", "wetland", "glacier"]), ("water", &[]), ("landuse", &["reservoir", "basin"])],
+            &["name", "natural", "water"],
+        ),
+        Layer::new(
+            "pois",
+            Point,
+            &[("amenity", &[]), ("shop", &[]), ("tourism", &[]), ("leisure", &[])],
+            &["name", "amenity", "shop", "tourism", "leisure"],
+        ),
+        Layer::new("places", Point, &[("place", &[])], &["name", "place", "population"]),
+    ]
+}
+
+/// Checks a set of layers for problems and duplicate names.
+pub fn validate(layers: &[Layer]) -> Result<()> {
+    for (i, layer) in layers.iter().enumerate() {
+        layer.validate()?;
+        if layers[..i].iter().any(|l| l.name == layer.name) {
+            bail!("layer '{}' is defined twice", layer.name);
+        }
+    }
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::osm_fixture;
+    use crate::results::Cell;
+
+    #[test]
+    fn test_default_layers_are_valid() {
+        validate(&default_layers()).unwrap();
+
+        let mut layers = default_layers();
+        layers.push(Layer::new("roads", GeometryKind::Line, &[("highway", &[])], &[]));
+        assert_eq!(validate(&layers).unwrap_err().to_string(), "layer 'roads' is defined twice");
+        let layer = Layer::new("ways", GeometryKind::Line, &[("highway", &[])], &[]);
+        assert!(layer.validate().is_err());
+        let layer = Layer::new("shops", GeometryKind::Point, &[("shop", &[])], &["name", "Name"]);
+        assert!(layer.validate().is_err(), "columns collide after renaming");
+    }
+
+    #[test]
+    fn test_build_layers() {
+        let mut db = osm_fixture();
+        db.set_layers(default_layers());
+        let built = db.build_layers("osm").unwrap();
+        assert_eq!(built.len(), 8);
+
+        let buildings = db
+            .query("SELECT osm_type || osm_id || ' ' || addr_street AS b FROM osm.buildings")
+            .unwrap().to_string();
+        assert!(buildings.contains("way10 Rue Grimaldi") && buildings.contains("Rows: 1 "), "{}", buildings);
+        let roads = db.query("SELECT string_agg(osm_id::VARCHAR, ',' ORDER BY osm_id) AS ids FROM osm.roads").unwrap().to_string();
+        assert!(roads.contains("11"), "{}", roads);
+        let pois = db.query("SELECT name, amenity FROM osm.pois").unwrap().to_string();
+        assert!(pois.contains("Chez l'Ami") && pois.contains("cafe"), "{}", pois);
+        let places = db.query("SELECT count(*) AS n FROM osm.places").unwrap();
+        assert_eq!(places.rows, vec![vec![Cell::Int(0)]]);
+    }
+}
@@ -1,37 +1,78 @@
 use anyhow::Result;
+use clap::Parser;
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::{io, sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use std::{io, path::PathBuf, process::ExitCode, time::Duration};
 use futures::StreamExt;
 use crossterm::event::EventStream;
 
 mod app;
+mod catalog;
+mod cli;
+mod config;
 mod db;
+mod export;
+mod files;
+mod layers;
 mod logging;
 mod network;
+mod pbf;
+mod provider;
+mod queue;
+mod region_tree;
+mod replication;
+mod result_view;
+mod results;
+mod schema;
 mod ui;
+mod worker;
 
-use app::{App, ActiveTab, FocusField};
-use network::{Downloader, DownloadEvent};
-use db::Database;
+use app::{App, ActiveTab, AppEvent, DatabaseFocus, FocusField, PendingQuery};
+use result_view::{ResultView, PAGE_SIZE};
+use export::ExportFormat;
+use files::CleanRule;
+use queue::{Job, JobState};
+use network::{DownloadControl, DownloadFormat, Downloader, DownloadEvent};
+use db::{
+    Database, DownloadOutcome, DownloadRecord, IfExists, ImportCancelled, ImportControl, ImportOutcome, TrackedRegion,
+};
+use worker::{DbWorker, Stopped};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
+    let cli = cli::Cli::parse();
+
     // Init logging
     logging::init()?;
 
+    let config = match config::Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {:#}", e);
+            eprintln!("Error: invalid configuration: {:#}", e);
+            return Ok(ExitCode::from(cli::EXIT_FAILURE));
+        }
+    };
+    let data_dir = config::data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+
+    // Headless mode for scripts and cron
+    if let Some(command) = cli.command {
+        return Ok(cli::run(command, &config, &data_dir).await);
+    }
+
     // Init DB
-    let project_dirs = directories::ProjectDirs::from("com", "osm-downloader", "osm-downloader").unwrap();
-    let data_dir = project_dirs.data_dir();
-    std::fs::create_dir_all(data_dir)?;
-    let db_path = data_dir.join("osm.duckdb");
-    
-    let db = Arc::new(Mutex::new(Database::new(&db_path)?));
+    if let Some(parent) = config.paths.database.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut db = Database::new(&config.paths.database)?;
+    db.set_layers(config.layers.clone());
+    let spatial = db.load_spatial(&config.spatial).clone();
+    let worker = DbWorker::spawn(db);
 
     // Setup Terminal
     enable_raw_mode()?;
@@ -41,12 +82,26 @@ async fn main() -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // App State
-    let mut app = App::new();
-    let downloader = Downloader::new();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<DownloadEvent>(100);
+    let mut app = App::new(&config);
+    app.set_spatial(spatial);
+    let downloader = Downloader::new(&config);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<AppEvent>(100);
+
+    // Load the region index in the background so startup isn't blocked on the network
+    let catalog_tx = tx.clone();
+    let providers = config.providers.clone();
+    let cache_dir = data_dir.clone();
+    let catalog_downloader = downloader.clone();
+    tokio::spawn(async move {
+        let evt = match provider::load_catalog(&providers, &catalog_downloader, &cache_dir).await {
+            Ok(catalog) => AppEvent::CatalogLoaded(Box::new(catalog)),
+            Err(e) => AppEvent::CatalogFailed(e.to_string()),
+        };
+        let _ = catalog_tx.send(evt).await;
+    });
 
     // Run Loop
-    let res = run_app(&mut terminal, &mut app, downloader, tx, &mut rx, db).await;
+    let res = run_app(&mut terminal, &mut app, downloader, tx, &mut rx, worker).await;
 
     // Restore Terminal
     disable_raw_mode()?;
@@ -59,200 +114,842 @@ async fn main() -> Result<()> {
 
     if let Err(err) = res {
         println!("{:?}", err);
+        return Ok(ExitCode::FAILURE);
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
+/// How often the TUI looks for tracked regions that are due for a refresh.
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 async fn run_app<B: ratatui::backend::Backend>(
     terminal: &mut Terminal<B>,
     app: &mut App<'_>,
     downloader: Downloader,
-    tx: tokio::sync::mpsc::Sender<DownloadEvent>,
-    rx: &mut tokio::sync::mpsc::Receiver<DownloadEvent>,
-    db: Arc<Mutex<Database>>,
+    tx: tokio::sync::mpsc::Sender<AppEvent>,
+    rx: &mut tokio::sync::mpsc::Receiver<AppEvent>,
+    worker: DbWorker,
 ) -> Result<()> {
     let mut interval = tokio::time::interval(Duration::from_millis(250));
     let mut event_stream = EventStream::new();
+    // Ticks right away, so due regions are refreshed at startup
+    let mut refresh_check = tokio::time::interval(REFRESH_CHECK_INTERVAL);
 
     loop {
+        app.db_status = worker.status();
         terminal.draw(|f| ui::draw(f, app))?;
 
         tokio::select! {
             _ = interval.tick() => {
                 app.on_tick();
             }
+            _ = refresh_check.tick() => {
+                check_tracked_regions(app, &worker, &tx);
+            }
             Some(evt) = rx.recv() => {
                 match evt {
-                    DownloadEvent::Progress(p) => {
-                        app.download_progress = p;
-                        app.download_status_text = "Downloading...".to_string();
-                    }
-                    DownloadEvent::Complete(path) => {
-                        app.download_progress = 100.0;
-                        app.is_downloading = false;
-                        app.download_status_text = format!("Saved to {:?}", path.file_name().unwrap());
-                        app.last_downloaded_path = Some(path.clone());
-                        app.add_log(format!("Download complete: {:?}", path));
-
-                        // Auto-import to DB
-                        let db_clone = db.clone();
-                        let path_clone = path.clone();
-                        let path_str = path_clone.to_string_lossy().to_string();
-                        let url_str = "manual_download"; // Todo: track actual URL
-                        let table_name = "imported_data";
-                        
-                        let tx_import = tx.clone(); // Clone for the background task
-
-                        tokio::task::spawn_blocking(move || {
-                            let _ = tx_import.blocking_send(DownloadEvent::ImportStarted);
-                            let db = db_clone.blocking_lock();
-                            let _ = db.record_download(url_str, &path_str);
-                            match db.import_data(&path_str, table_name) {
-                                Ok(_) => {
-                                    let _ = tx_import.blocking_send(DownloadEvent::ImportFinished("Import successful.".to_string()));
-                                },
-                                Err(e) => {
-                                    tracing::error!("Import failed: {}", e);
-                                    let _ = tx_import.blocking_send(DownloadEvent::ImportFailed(e.to_string()));
+                    AppEvent::Download(evt) => match evt {
+                        DownloadEvent::Progress(id, progress) => {
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.progress = progress;
+                            }
+                        }
+                        DownloadEvent::Verifying(id) => {
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Verifying;
+                            }
+                        }
+                        DownloadEvent::VerificationFailed(id, e) => {
+                            app.add_log(format!("Job #{}: verification failed: {}", id, e));
+                        }
+                        DownloadEvent::Retrying(id, e) => {
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Active;
+                            }
+                            app.add_log(format!("Job #{}: {}", id, e));
+                        }
+                        DownloadEvent::Paused(id) => {
+                            app.download_controls.remove(&id);
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Paused;
+                            }
+                            app.add_log(format!("Job #{}: paused, partial file kept.", id));
+                        }
+                        DownloadEvent::Cancelled(id) => {
+                            app.download_controls.remove(&id);
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Cancelled;
+                                job.progress = Default::default();
+                                let record = DownloadRecord::unfinished(
+                                    &job.url, &job.name, &job.format, job.elapsed(), DownloadOutcome::Cancelled, None,
+                                );
+                                record_download(&worker, record);
+                            }
+                            app.add_log(format!("Job #{}: cancelled, partial file removed.", id));
+                        }
+                        DownloadEvent::Complete(id, file) | DownloadEvent::UpToDate(id, file) => {
+                            app.download_controls.remove(&id);
+                            let mut finished = None;
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Completed;
+                                job.progress.percent = 100.0;
+                                job.path = Some(file.path.clone());
+                                finished = Some(job.clone());
+                            }
+                            app.last_downloaded_path = Some(file.path.clone());
+                            if file.unchanged {
+                                app.add_log(format!("Job #{}: already up to date, not downloaded again.", id));
+                            } else {
+                                app.add_log(format!("Download complete: {:?}", file.path));
+                            }
+                            if let Some(job) = finished {
+                                let record = DownloadRecord::completed(&file, &job.name, &job.format, job.elapsed());
+                                if matches!(job.format, DownloadFormat::Shapefile) && !app.has_spatial() {
+                                    app.add_log(format!(
+                                        "Job #{}: not imported, shapefiles need the spatial extension.",
+                                        id
+                                    ));
+                                    record_download(&worker, record);
+                                } else {
+                                    // Auto-import to DB
+                                    let control = ImportControl::new(worker.interrupt_handle());
+                                    app.start_import(job.id, control.clone());
+                                    spawn_import(&worker, &downloader, &tx, job, record, app.if_exists, control);
                                 }
                             }
-                        });
+                        }
+                        DownloadEvent::Error(id, e) => {
+                            app.download_controls.remove(&id);
+                            if let Some(job) = app.queue.get_mut(id) {
+                                job.state = JobState::Failed(e.clone());
+                                let record = DownloadRecord::unfinished(
+                                    &job.url, &job.name, &job.format, job.elapsed(), DownloadOutcome::Failed, Some(e.clone()),
+                                );
+                                record_download(&worker, record);
+                            }
+                            app.add_log(format!("Job #{}: download error: {}", id, e));
+                        }
+                    },
+                    AppEvent::ImportStarted(id) => {
+                        app.add_log(format!("Job #{}: importing into DuckDB...", id));
                     }
-                    DownloadEvent::ImportStarted => {
-                        app.add_log("Starting auto-import to DuckDB...".to_string());
+                    AppEvent::ImportProgress(id, progress) => {
+                        if let Some(job) = app.queue.get_mut(id) {
+                            job.import = Some(progress);
+                        }
                     }
-                    DownloadEvent::ImportFinished(msg) => {
+                    AppEvent::ImportFinished(id, table, msg) => {
+                         app.finish_import(id, JobState::Completed);
                          app.add_log(msg);
-                         // Pre-populate SQL input for convenience
-                         app.sql_input = tui_textarea::TextArea::default();
-                         let query = "SELECT * FROM imported_data LIMIT 10;";
-                         app.sql_input.insert_str(query);
-                         
-                         // Auto-execute query
-                         app.add_log("Auto-executing preview query...".to_string());
-                         // We use blocking_lock which can panic in async context if not careful.
-                         // Instead, use try_lock() to avoid blocking the runtime thread, or spawn_blocking if we really need to wait.
-                         // Since we want to update UI immediately, try_lock is safer. If busy, we skip preview.
-                         if let Ok(db_lock) = db.try_lock() {
-                              match db_lock.query(query) {
-                                   Ok(output) => app.sql_output = output,
-                                   Err(e) => app.sql_output = format!("Error executing preview: {}", e),
-                              }
+                         // A query the user is waiting for keeps the editor and results
+                         if app.pending_query.as_ref().is_some_and(|q| q.focus_results) {
+                             app.add_log(format!("Not previewing {} while your query runs.", table));
                          } else {
-                              app.add_log("DB busy, skip preview.".to_string());
+                             // Pre-populate SQL input for convenience
+                             app.sql_input = tui_textarea::TextArea::default();
+                             let query = format!("SELECT * FROM {} LIMIT 10;", table);
+                             app.sql_input.insert_str(&query);
+
+                             // Auto-execute query
+                             app.add_log("Auto-executing preview query...".to_string());
+                             run_query(app, &worker, &tx, query, false);
                          }
                     }
-                    DownloadEvent::ImportFailed(e) => {
-                         app.add_log(format!("Import failed: {}", e));
+                    AppEvent::ImportFailed(id, e) => {
+                         app.finish_import(id, JobState::Failed(format!("import: {}", e)));
+                         app.add_log(format!("Job #{}: import failed: {}", id, e));
+                    }
+                    AppEvent::ImportCancelled(id) => {
+                         app.finish_import(id, JobState::Cancelled);
+                         app.add_log(format!("Job #{}: import cancelled and rolled back.", id));
+                    }
+                    AppEvent::CatalogLoaded(catalog) => {
+                        app.add_log(format!("Region index loaded ({} regions).", catalog.region_count()));
+                        app.catalog = Some(*catalog);
+                    }
+                    AppEvent::CatalogFailed(e) => {
+                        tracing::warn!("Region index unavailable: {}", e);
+                        app.add_log(format!("Region index unavailable, guessing URLs: {}", e));
+                    }
+                    AppEvent::RemoteSize(url, size) => {
+                        app.remote_sizes.insert(url, size);
+                    }
+                    AppEvent::QueryFinished(id, sql, result) => {
+                        let Some(pending) = app.pending_query.take_if(|p| p.handle.id() == id) else {
+                            continue; // replaced by a newer query
+                        };
+                        match result {
+                            Ok((page, total)) => {
+                                app.result_view = Some(ResultView::new(sql, page, total));
+                                if pending.focus_results {
+                                    app.database_focus = DatabaseFocus::Results;
+                                }
+                            }
+                            Err(e) => app.show_message(e),
+                        }
+                    }
+                    AppEvent::PageLoaded(sql, result) => {
+                        if let Some(view) = app.result_view.as_mut().filter(|v| v.sql == sql) {
+                            view.loading = false;
+                            match result {
+                                Ok(page) => view.set_page(page),
+                                Err(e) => app.add_log(format!("Error loading rows: {}", e)),
+                            }
+                        }
+                        // The cursor may have moved on while the page was loading
+                        load_wanted_page(app, &worker, &tx);
+                    }
+                    AppEvent::Exported(path, rows) => {
+                        app.add_log(format!("Exported {} rows to {}.", rows, path.display()));
+                    }
+                    AppEvent::ExportFailed(path, e) => {
+                        app.add_log(format!("Export to {} failed: {}", path.display(), e));
+                    }
+                    AppEvent::TrackedRegions(result) => {
+                        app.checking_tracked = false;
+                        match result {
+                            Ok(regions) => {
+                                app.tracked = regions;
+                                app.queue_due_refreshes();
+                            }
+                            Err(e) => app.add_log(format!("Failed to check tracked regions: {}", e)),
+                        }
+                    }
+                    AppEvent::FilesListed(result) => {
+                        app.files.loading = false;
+                        match result {
+                            Ok(files) => app.files.set_files(files),
+                            Err(e) => app.add_log(format!("Failed to list downloaded files: {}", e)),
+                        }
+                    }
+                    AppEvent::FileVerified(path, result) => {
+                        match &result {
+                            Ok(checksum) => app.add_log(format!("{}: checksum {}.", path.display(), checksum.label())),
+                            Err(e) => app.add_log(format!("Failed to verify {}: {}", path.display(), e)),
+                        }
+                        if let (Some(file), Ok(checksum)) = (app.files.files.iter_mut().find(|f| f.path == path), result) {
+                            file.checksum = checksum;
+                        }
                     }
-                    DownloadEvent::Error(e) => {
-                        app.is_downloading = false;
-                        app.download_status_text = format!("Error: {}", e);
-                        app.add_log(format!("Download error: {}", e));
+                    AppEvent::FileImported(path, result) => {
+                        match result {
+                            Ok(msg) => app.add_log(msg),
+                            Err(e) => app.add_log(format!("Re-import of {} failed: {}", path.display(), e)),
+                        }
+                        load_files(app, &worker, &tx);
                     }
                 }
+                // A finished job frees a slot for the next one in the queue
+                start_queued_downloads(app, &downloader, &tx)?;
             }
             Some(Ok(event)) = event_stream.next() => {
-                 match event {
-                        Event::Key(key) => {
-                            if key.code == KeyCode::Char('q') && key.modifiers.contains(KeyModifiers::CONTROL) {
-                                return Ok(());
+                 if let Event::Key(key) = event {
+                        if key.code == KeyCode::Char('q') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                            return Ok(());
+                        }
+
+                        // Global Tab Switch
+                        if key.code == KeyCode::Char('b') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                            app.active_tab = app.active_tab.next();
+                            if app.active_tab == ActiveTab::Files {
+                                load_files(app, &worker, &tx);
                             }
+                        }
 
-                            // Global Tab Switch
-                            if key.code == KeyCode::Char('b') && key.modifiers.contains(KeyModifiers::CONTROL) {
-                                app.active_tab = match app.active_tab {
-                                    ActiveTab::Download => ActiveTab::Database,
-                                    ActiveTab::Database => ActiveTab::Download,
-                                };
+                        match app.active_tab {
+                            ActiveTab::Download => {
+                                match key.code {
+                                    KeyCode::Tab => app.next_focus(),
+                                    KeyCode::Enter if app.focus_field == FocusField::Tree => {
+                                        // Queue the highlighted region directly
+                                        // Jobs are named by region path, which also names the table they import into
+                                        let selected = app.catalog.as_ref().and_then(|c| {
+                                            app.region_tree
+                                                .selected_region(c)
+                                                .map(|r| (c.path(&r.id), r.url(&app.download_format).map(str::to_string)))
+                                        });
+                                        match selected {
+                                            Some((name, Some(url))) => enqueue_download(app, &downloader, name, url, &tx)?,
+                                            Some((name, None)) => {
+                                                app.add_log(format!("Error: {} has no {:?} extract", name, app.download_format));
+                                            }
+                                            None => app.add_log("Error: No region selected".to_string()),
+                                        }
+                                    }
+                                    KeyCode::Enter if app.focus_field != FocusField::Queue => {
+                                        // Queue Download
+                                        let continent = app.input_continent.lines()[0].to_string();
+                                        let country = app.input_country.lines()[0].to_string();
+                                        let region = app.input_region.lines().first().cloned().unwrap_or_default();
+                                        
+                                        if continent.is_empty() {
+                                            app.add_log("Error: Continent is required".to_string());
+                                        } else {
+                                            let name = [continent.as_str(), country.as_str(), region.as_str()]
+                                                .into_iter()
+                                                .filter(|s| !s.trim().is_empty())
+                                                .collect::<Vec<_>>()
+                                                .join("/");
+                                            match app.resolve_download_url(&continent, &country, &region) {
+                                                Ok(url) => enqueue_download(app, &downloader, name, url, &tx)?,
+                                                Err(e) => app.add_log(format!("Error: {}", e)),
+                                            }
+                                        }
+                                    }
+                                    KeyCode::Char(' ') if app.focus_field == FocusField::Format => {
+                                        app.toggle_format();
+                                    }
+                                    KeyCode::Char('q') if matches!(app.focus_field, FocusField::Format | FocusField::Queue) => {
+                                        return Ok(());
+                                    }
+                                    _ if app.focus_field == FocusField::Queue => {
+                                        let queue = &mut app.queue;
+                                        match key.code {
+                                            KeyCode::Up => queue.select_previous(),
+                                            KeyCode::Down => queue.select_next(),
+                                            KeyCode::Char('[') => queue.move_selected(-1),
+                                            KeyCode::Char(']') => queue.move_selected(1),
+                                            KeyCode::Char('p') => app.toggle_pause_selected_job(),
+                                            KeyCode::Char('c') => {
+                                                // A transfer paused midway is recorded like one cancelled while running
+                                                let paused = queue
+                                                    .selected_job()
+                                                    .filter(|job| job.state == JobState::Paused && job.started.is_some())
+                                                    .cloned();
+                                                let cancelled = app.cancel_selected_job();
+                                                if !cancelled {
+                                                    app.add_log("Nothing to cancel for this job.".to_string());
+                                                } else if let Some(job) = paused {
+                                                    let record = DownloadRecord::unfinished(
+                                                        &job.url, &job.name, &job.format, job.elapsed(), DownloadOutcome::Cancelled, None,
+                                                    );
+                                                    record_download(&worker, record);
+                                                    app.add_log(format!("Job #{}: cancelled, partial file removed.", job.id));
+                                                }
+                                            }
+                                            KeyCode::Char('r') => {
+                                                let retried = queue.retry_selected();
+                                                if !retried {
+                                                    app.add_log("Only failed or cancelled jobs can be retried.".to_string());
+                                                }
+                                            }
+                                            KeyCode::Char('t') => toggle_tracking(app, &worker, &tx),
+                                            KeyCode::Char('+') => queue.set_max_concurrent(queue.max_concurrent + 1),
+                                            KeyCode::Char('-') => queue.set_max_concurrent(queue.max_concurrent - 1),
+                                            _ => {}
+                                        }
+                                        start_queued_downloads(app, &downloader, &tx)?;
+                                    }
+                                    _ if app.focus_field == FocusField::Tree => {
+                                        if let Some(catalog) = &app.catalog {
+                                            let tree = &mut app.region_tree;
+                                            match key.code {
+                                                KeyCode::Up => tree.move_up(),
+                                                KeyCode::Down => tree.move_down(catalog),
+                                                KeyCode::Right => tree.expand(catalog),
+                                                KeyCode::Left => tree.collapse(catalog),
+                                                KeyCode::Backspace => tree.pop_filter(catalog),
+                                                KeyCode::Esc => {
+                                                    tree.filter.clear();
+                                                    tree.selected = 0;
+                                                }
+                                                KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                                    tree.push_filter(c, catalog)
+                                                }
+                                                _ => {}
+                                            }
+                                        }
+                                        // Look up extract sizes for the newly highlighted region
+                                        for url in app.take_unsized_urls() {
+                                            let tx_size = tx.clone();
+                                            let downloader = downloader.clone();
+                                            tokio::spawn(async move {
+                                                let size = downloader.remote_size(&url).await.ok().flatten();
+                                                let _ = tx_size.send(AppEvent::RemoteSize(url, size)).await;
+                                            });
+                                        }
+                                    }
+                                    _ => {
+                                        // Pass input to text areas
+                                        match app.focus_field {
+                                            FocusField::Continent => { app.input_continent.input(key); },
+                                            FocusField::Country => { app.input_country.input(key); },
+                                            FocusField::Region => { app.input_region.input(key); },
+                                            _ => {}
+                                        }
+                                    }
+                                }
                             }
+                            ActiveTab::Database => {
+                                // Log for debugging
+                                // app.add_log(format!("Key: {:?} Mod: {:?}", key.code, key.modifiers));
+
+                                let is_ctrl_enter = key.code == KeyCode::Enter && key.modifiers.contains(KeyModifiers::CONTROL);
+                                let is_ctrl_e = key.code == KeyCode::Char('e') && key.modifiers.contains(KeyModifiers::CONTROL);
+                                let is_shift_ctrl_enter = key.code == KeyCode::Enter && key.modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::SHIFT);
+                                
+                                // Also allow simple F5
+                                let is_f5 = key.code == KeyCode::F(5);
+                                let is_ctrl_s = key.code == KeyCode::Char('s') && key.modifiers.contains(KeyModifiers::CONTROL);
+
+                                let is_ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
 
-                            match app.active_tab {
-                                ActiveTab::Download => {
+                                if let Some(pending) = app.pending_query.as_ref().filter(|_| is_ctrl_c || key.code == KeyCode::Esc) {
+                                    pending.handle.cancel();
+                                } else if let Some(input) = app.export_input.as_mut() {
                                     match key.code {
-                                        KeyCode::Tab => app.next_focus(),
+                                        KeyCode::Esc => app.export_input = None,
                                         KeyCode::Enter => {
-                                            // Start Download
-                                            if !app.is_downloading {
-                                                let continent = app.input_continent.lines()[0].to_string();
-                                                let country = app.input_country.lines()[0].to_string();
-                                                let region = app.input_region.lines().get(0).cloned().unwrap_or_default();
-                                                
-                                                if continent.is_empty() {
-                                                    app.add_log("Error: Continent is required".to_string());
-                                                } else {
-                                                    app.is_downloading = true;
-                                                    app.download_progress = 0.0;
-                                                    app.download_status_text = "Starting...".to_string();
-                                                    app.add_log(format!("Requesting: {}/{}/{}", continent, country, region));
-
-                                                    let url = downloader.construct_url(&continent, &country, &region, &app.download_format);
-                                                    app.add_log(format!("URL: {}", url));
-                                                    
-                                                    let tx_clone = tx.clone();
-                                                    let downloader_clone = Downloader::new(); // Cheap clone of client
-                                                    
-                                                    let project_dirs = directories::ProjectDirs::from("com", "osm-downloader", "osm-downloader").unwrap();
-                                                    let download_dir = project_dirs.data_dir().join("downloads");
-                                                    std::fs::create_dir_all(&download_dir)?;
-
-                                                    tokio::spawn(async move {
-                                                        let _ = downloader_clone.download_file(url, download_dir, tx_clone).await;
-                                                    });
+                                            let path = PathBuf::from(input.lines()[0].trim());
+                                            app.export_input = None;
+                                            match ExportFormat::from_path(&path) {
+                                                Some(format) => {
+                                                    app.add_log(format!("Exporting to {} ({})...", path.display(), format.label()));
+                                                    spawn_export(&worker, &tx, app.export_sql(), path, format);
                                                 }
+                                                None => app.add_log(
+                                                    "Error: export files need a .csv, .parquet, .geojson, .fgb or .gpkg extension".to_string(),
+                                                ),
                                             }
                                         }
-                                        KeyCode::Char(' ') if app.focus_field == FocusField::Format => {
-                                            app.toggle_format();
+                                        _ => {
+                                            input.input(key);
                                         }
-                                        KeyCode::Char('q') if app.focus_field != FocusField::Continent && app.focus_field != FocusField::Country && app.focus_field != FocusField::Region => {
-                                            return Ok(());
+                                    }
+                                } else if is_ctrl_s {
+                                    app.open_export_prompt();
+                                } else if is_ctrl_enter || is_ctrl_e || is_shift_ctrl_enter || is_f5 {
+                                    // Execute Query
+                                    let query = app.sql_input.lines().join("\n");
+                                    app.add_log(format!("Executing: {}", query));
+                                    run_query(app, &worker, &tx, query, true);
+                                } else if key.code == KeyCode::Tab {
+                                    app.database_focus = match app.database_focus {
+                                        DatabaseFocus::Editor if app.result_view.is_some() => DatabaseFocus::Results,
+                                        _ => DatabaseFocus::Editor,
+                                    };
+                                } else if app.database_focus == DatabaseFocus::Editor {
+                                    app.sql_input.input(key);
+                                } else if let Some(view) = app.result_view.as_mut() {
+                                    match key.code {
+                                        KeyCode::Esc if view.inspecting => view.inspecting = false,
+                                        KeyCode::Esc => app.database_focus = DatabaseFocus::Editor,
+                                        KeyCode::Enter => view.inspecting = !view.inspecting,
+                                        KeyCode::Up => view.move_up(1),
+                                        KeyCode::Down => view.move_down(1),
+                                        KeyCode::PageUp => view.page_up(),
+                                        KeyCode::PageDown => view.page_down(),
+                                        KeyCode::Home => view.home(),
+                                        KeyCode::End => view.end(),
+                                        KeyCode::Left => view.move_left(),
+                                        KeyCode::Right => view.move_right(),
+                                        KeyCode::Char('<') => view.resize(-2),
+                                        KeyCode::Char('>') => view.resize(2),
+                                        _ => {}
+                                    }
+                                    load_wanted_page(app, &worker, &tx);
+                                }
+                            }
+                            ActiveTab::Files => {
+                                if let Some(pending) = app.pending_deletion.take() {
+                                    if matches!(key.code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+                                        app.delete_files(pending.paths);
+                                        load_files(app, &worker, &tx);
+                                    } else {
+                                        app.add_log("Nothing deleted.".to_string());
+                                    }
+                                } else if let Some(input) = app.clean_input.as_mut() {
+                                    match key.code {
+                                        KeyCode::Esc => app.clean_input = None,
+                                        KeyCode::Enter => {
+                                            let text = input.lines()[0].clone();
+                                            app.clean_input = None;
+                                            match CleanRule::parse(&text) {
+                                                Ok(rule) => app.confirm_clean(rule),
+                                                Err(e) => app.add_log(format!("Error: {}", e)),
+                                            }
                                         }
                                         _ => {
-                                            // Pass input to text areas
-                                            match app.focus_field {
-                                                FocusField::Continent => { app.input_continent.input(key); },
-                                                FocusField::Country => { app.input_country.input(key); },
-                                                FocusField::Region => { app.input_region.input(key); },
-                                                _ => {}
-                                            }
+                                            input.input(key);
                                         }
                                     }
-                                }
-                                ActiveTab::Database => {
-                                    // Log for debugging
-                                    // app.add_log(format!("Key: {:?} Mod: {:?}", key.code, key.modifiers));
-
-                                    let is_ctrl_enter = key.code == KeyCode::Enter && key.modifiers.contains(KeyModifiers::CONTROL);
-                                    let is_ctrl_e = key.code == KeyCode::Char('e') && key.modifiers.contains(KeyModifiers::CONTROL);
-                                    let is_shift_ctrl_enter = key.code == KeyCode::Enter && key.modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::SHIFT);
-                                    
-                                    // Also allow simple F5
-                                    let is_f5 = key.code == KeyCode::F(5);
-
-                                    if is_ctrl_enter || is_ctrl_e || is_shift_ctrl_enter || is_f5 {
-                                        // Execute Query
-                                        let query = app.sql_input.lines().join("\n");
-                                        app.add_log(format!("Executing: {}", query));
-                                        
-                                        if let Ok(db_lock) = db.try_lock() {
-                                            match db_lock.query(&query) {
-                                                Ok(output) => app.sql_output = output,
-                                                Err(e) => app.sql_output = format!("Error: {}", e),
+                                } else {
+                                    match key.code {
+                                        KeyCode::Char('q') => return Ok(()),
+                                        KeyCode::Up => app.files.select_previous(),
+                                        KeyCode::Down => app.files.select_next(),
+                                        KeyCode::F(5) => load_files(app, &worker, &tx),
+                                        KeyCode::Char('d') => app.confirm_delete_selected(),
+                                        KeyCode::Char('x') => app.open_clean_prompt(),
+                                        KeyCode::Char('v') => verify_file(app, &tx),
+                                        KeyCode::Char('i') => reimport_file(app, &worker, &tx),
+                                        KeyCode::Char('o') => {
+                                            if let Some(path) = app.files.selected_file().map(|f| f.path.clone()) {
+                                                let msg = match files::reveal(&path) {
+                                                    Ok(()) => path.display().to_string(),
+                                                    Err(e) => format!("{} (no file manager to show it in: {})", path.display(), e),
+                                                };
+                                                app.add_log(msg);
                                             }
-                                        } else {
-                                            app.sql_output = "DB busy, cannot execute query.".to_string();
                                         }
-                                    } else {
-                                        app.sql_input.input(key);
+                                        _ => {}
                                     }
                                 }
                             }
                         }
-                        _ => {}
-                     }
+                 }
              }
         }
     }
 }
+
+/// Imports a finished download (`job.path`) in the background, into a table named
+/// after the job's region and format, or the table a refresh replaces. A file that
+/// was up to date is only imported if the target table doesn't hold it already.
+fn spawn_import(
+    worker: &DbWorker,
+    downloader: &Downloader,
+    tx: &tokio::sync::mpsc::Sender<AppEvent>,
+    job: Job,
+    record: DownloadRecord,
+    if_exists: IfExists,
+    control: ImportControl,
+) {
+    let path_str = job.path.as_deref().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+    let (table_name, if_exists) = match &job.table {
+        Some(table) => (table.clone(), IfExists::Replace),
+        None => (db::dataset_table_name(&job.name, job.format.short_name()), if_exists),
+    };
+
+    let tx_import = tx.clone(); // Clone for the background task
+    let (url, header) = (job.url.clone(), record.clone());
+
+    // Queued behind whatever the worker is doing; the job shows as importing meanwhile
+    let request = worker.submit(format!("import of {}", job.name), move |db| {
+        let download_id = match db.record_download(&record) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                tracing::warn!("Failed to record download of {}: {:#}", record.url, e);
+                None
+            }
+        };
+        if record.outcome == DownloadOutcome::Unchanged && matches!(db.holds_import_of(&table_name, &path_str), Ok(true)) {
+            let msg = format!("{} is unchanged, kept table {}.", job.name, table_name);
+            let preview = db.preview_table(&table_name).unwrap_or_else(|_| table_name.clone());
+            let _ = tx_import.blocking_send(AppEvent::ImportFinished(job.id, preview, msg));
+            return None;
+        }
+        let _ = tx_import.blocking_send(AppEvent::ImportStarted(job.id));
+        let progress_tx = tx_import.clone();
+        let control = control.on_progress(move |p| {
+            let _ = progress_tx.blocking_send(AppEvent::ImportProgress(job.id, p.clone()));
+        });
+        match db.import_dataset(&path_str, &table_name, if_exists, download_id, &control) {
+            Ok(ImportOutcome::Imported { table, rows }) => {
+                let counts: Vec<String> = rows
+                    .iter()
+                    .map(|(name, count)| format!("{} {}", name.rsplit('.').next().unwrap_or(name), count))
+                    .collect();
+                let msg = format!("Imported into table {} (rows: {}).", table, counts.join(", "));
+                let preview = db.preview_table(&table).unwrap_or_else(|_| table.clone());
+                let _ = tx_import.blocking_send(AppEvent::ImportFinished(job.id, preview, msg));
+                Some(table)
+            }
+            Ok(ImportOutcome::Kept(table)) => {
+                let msg = format!("Table {} already exists, kept it.", table);
+                let preview = db.preview_table(&table).unwrap_or(table);
+                let _ = tx_import.blocking_send(AppEvent::ImportFinished(job.id, preview, msg));
+                None
+            }
+            Err(e) if e.is::<ImportCancelled>() => {
+                let _ = tx_import.blocking_send(AppEvent::ImportCancelled(job.id));
+                None
+            }
+            Err(e) => {
+                tracing::error!("Import failed: {}", e);
+                let _ = tx_import.blocking_send(AppEvent::ImportFailed(job.id, e.to_string()));
+                None
+            }
+        }
+    });
+
+    // Remember the replication sequence so `update` can apply later diffs. A file
+    // without one in its header needs the server's, fetched off the worker.
+    let (worker, downloader) = (worker.clone(), downloader.clone());
+    tokio::spawn(async move {
+        let Ok(Some(table)) = request.wait(None).await else {
+            return;
+        };
+        match replication::initial_state(&downloader, &url, &header).await {
+            Ok(Some((updates, state))) => {
+                worker.submit(format!("replication state of {}", table), move |db| {
+                    match db.record_replication(&table, &updates, &state) {
+                        Ok(()) => tracing::info!("Tracking {} at replication sequence {}", table, state.sequence),
+                        Err(e) => tracing::warn!("Failed to record the replication state of {}: {:#}", table, e),
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("No replication state for {}: {:#}", url, e),
+        }
+    });
+}
+
+/// Adds a download that won't be imported to the `downloads` table.
+fn record_download(worker: &DbWorker, record: DownloadRecord) {
+    worker.submit("recording a download", move |db| {
+        if let Err(e) = db.record_download(&record) {
+            tracing::warn!("Failed to record download of {}: {:#}", record.url, e);
+        }
+    });
+}
+
+/// Loads the tracked regions on the database worker; the due ones are queued when
+/// they arrive as `AppEvent::TrackedRegions`. While a check waits behind a
+/// long import, no further ones are submitted.
+fn check_tracked_regions(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    if std::mem::replace(&mut app.checking_tracked, true) {
+        return;
+    }
+    let request = worker.submit("checking tracked regions", |db| db.tracked_regions());
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let result = request.wait(None).await.and_then(|result| result).map_err(|e| format!("{:#}", e));
+        let _ = tx.send(AppEvent::TrackedRegions(result)).await;
+    });
+}
+
+/// Tracks the region of the selected job, or stops tracking it, then checks the
+/// tracked regions again so the queue shows the change.
+fn toggle_tracking(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    let Some(job) = app.queue.selected_job() else {
+        return;
+    };
+    let untrack = app.tracked_region(job).is_some();
+    let region = TrackedRegion {
+        region: job.name.clone(),
+        format: job.format.short_name().to_string(),
+        url: job.url.clone(),
+        table: job
+            .table
+            .clone()
+            .unwrap_or_else(|| db::dataset_table_name(&job.name, job.format.short_name())),
+        interval_hours: app.refresh_interval_hours,
+        last_checked: None,
+        due: true,
+    };
+    app.add_log(if untrack {
+        format!("No longer tracking {}.", region.region)
+    } else {
+        format!("Tracking {}, refreshed into table {} every {} h.", region.region, region.table, region.interval_hours)
+    });
+    worker.submit(format!("tracking {}", region.region), move |db| {
+        let result = if untrack {
+            db.untrack_region(&region.region, &region.format).map(|_| ())
+        } else {
+            db.track_region(&region)
+        };
+        if let Err(e) = result {
+            tracing::warn!("Failed to change the tracking of {}: {:#}", region.region, e);
+        }
+    });
+    // A check submitted before the change would miss it
+    app.checking_tracked = false;
+    check_tracked_regions(app, worker, tx);
+}
+
+/// Reads the download directory again, with what the database knows about each
+/// file, for the Files tab.
+fn load_files(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    if std::mem::replace(&mut app.files.loading, true) {
+        return;
+    }
+    let dir = app.download_dir.clone();
+    let request = worker.submit("listing downloaded files", move |db| files::list(db, &dir));
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let result = request.wait(None).await.and_then(|result| result).map_err(|e| format!("{:#}", e));
+        let _ = tx.send(AppEvent::FilesListed(result)).await;
+    });
+}
+
+/// Hashes the selected file again in the background; extracts take a while.
+fn verify_file(app: &mut App, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    let Some(file) = app.files.selected_file().cloned() else {
+        return;
+    };
+    app.add_log(format!("Verifying {}...", file.name()));
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let path = file.path.clone();
+        let result = match tokio::task::spawn_blocking(move || files::verify(&file)).await {
+            Ok(result) => result.map_err(|e| format!("{:#}", e)),
+            Err(e) => Err(e.to_string()),
+        };
+        let _ = tx.send(AppEvent::FileVerified(path, result)).await;
+    });
+}
+
+/// Imports the selected file again on the database worker, replacing its table.
+fn reimport_file(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    let Some(file) = app.files.selected_file().cloned() else {
+        return;
+    };
+    if file.format() == Some("shp") && !app.has_spatial() {
+        app.add_log("Shapefiles can't be imported without the spatial extension.".to_string());
+        return;
+    }
+    app.add_log(format!("Re-importing {} into table {}...", file.name(), file.import_table()));
+    let path = file.path.clone();
+    let request = worker.submit(format!("re-import of {}", file.name()), move |db| {
+        Ok::<_, anyhow::Error>(match files::reimport(db, &file, &db.import_control())? {
+            ImportOutcome::Imported { table, rows } => {
+                let counts: Vec<String> = rows
+                    .iter()
+                    .map(|(name, count)| format!("{} {}", name.rsplit('.').next().unwrap_or(name), count))
+                    .collect();
+                format!("Re-imported into table {} (rows: {}).", table, counts.join(", "))
+            }
+            ImportOutcome::Kept(table) => format!("Table {} already exists, kept it.", table),
+        })
+    });
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let result = request.wait(None).await.and_then(|result| result).map_err(|e| format!("{:#}", e));
+        let _ = tx.send(AppEvent::FileImported(path, result)).await;
+    });
+}
+
+/// Writes the rows of `sql` to `path` on the database worker, so large exports
+/// don't block the UI.
+fn spawn_export(
+    worker: &DbWorker,
+    tx: &tokio::sync::mpsc::Sender<AppEvent>,
+    sql: String,
+    path: PathBuf,
+    format: ExportFormat,
+) {
+    let tx = tx.clone();
+    let target = path.clone();
+    let request = worker.submit(format!("export to {}", path.display()), move |db| {
+        export::export(db, &sql, &target, format)
+    });
+    tokio::spawn(async move {
+        let event = match request.wait(None).await.and_then(|rows| rows) {
+            Ok(rows) => AppEvent::Exported(path, rows),
+            Err(e) => {
+                tracing::error!("Export to {:?} failed: {:#}", path, e);
+                AppEvent::ExportFailed(path, format!("{:#}", e))
+            }
+        };
+        let _ = tx.send(event).await;
+    });
+}
+
+/// Runs `sql` from the Database tab on the database worker, replacing any query
+/// still waiting for its result. The first page of the result (or the error)
+/// arrives as `AppEvent::QueryFinished`.
+fn run_query(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>, sql: String, focus_results: bool) {
+    if let Some(pending) = app.pending_query.take() {
+        pending.handle.cancel();
+    }
+    let query = sql.clone();
+    let request = worker.submit("query", move |db| {
+        let (page, pageable) = db.query_page(&query, 0, PAGE_SIZE)?;
+        // Without a count the first page is all that can be shown
+        let total = if pageable { db.count_rows(&query).ok() } else { None };
+        Ok::<_, anyhow::Error>((page, total))
+    });
+    let id = request.handle.id();
+    app.show_message("Running query... (Esc or Ctrl+c: Cancel)".to_string());
+    app.pending_query = Some(PendingQuery { handle: request.handle.clone(), focus_results });
+
+    let timeout = app.query_timeout;
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let result = request.wait(timeout).await.and_then(|result| result).map_err(|e| match e.downcast_ref() {
+            Some(Stopped::Cancelled) => "Query cancelled.".to_string(),
+            Some(Stopped::TimedOut(limit)) => format!(
+                "Query interrupted after {} s; queries.timeout_secs in the config sets the limit.",
+                limit.as_secs()
+            ),
+            None => format!("Error: {}", e),
+        });
+        let _ = tx.send(AppEvent::QueryFinished(id, sql, result)).await;
+    });
+}
+
+/// Fetches the rows around the cursor of the result table once it leaves the
+/// page that is loaded.
+fn load_wanted_page(app: &mut App, worker: &DbWorker, tx: &tokio::sync::mpsc::Sender<AppEvent>) {
+    let Some(view) = app.result_view.as_mut().filter(|v| !v.loading) else {
+        return;
+    };
+    let Some(offset) = view.wanted_page() else {
+        return;
+    };
+    view.loading = true;
+    let sql = view.sql.clone();
+    let query = sql.clone();
+    let request = worker.submit("loading rows", move |db| db.query_page(&query, offset, PAGE_SIZE));
+    let timeout = app.query_timeout;
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let result = request.wait(timeout).await.and_then(|result| result);
+        let page = result.map(|(page, _)| page).map_err(|e| e.to_string());
+        let _ = tx.send(AppEvent::PageLoaded(sql, page)).await;
+    });
+}
+
+fn enqueue_download(
+    app: &mut App,
+    downloader: &Downloader,
+    name: String,
+    url: String,
+    tx: &tokio::sync::mpsc::Sender<AppEvent>,
+) -> Result<()> {
+    let format = app.download_format.clone();
+    match app.queue.push(name, url.clone(), format) {
+        Ok(id) => app.add_log(format!("Queued job #{}: {}", id, url)),
+        Err(e) => app.add_log(format!("Error: {}", e)),
+    }
+    start_queued_downloads(app, downloader, tx)
+}
+
+/// Starts as many queued jobs as the concurrency limit allows.
+fn start_queued_downloads(
+    app: &mut App,
+    downloader: &Downloader,
+    tx: &tokio::sync::mpsc::Sender<AppEvent>,
+) -> Result<()> {
+    let jobs = app.queue.start_ready();
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let download_dir = app.download_dir.clone();
+    std::fs::create_dir_all(&download_dir)?;
+
+    for job in jobs {
+        app.add_log(format!("Starting job #{}: {}", job.id, job.name));
+        let tx_clone = tx.clone();
+        let downloader_clone = downloader.clone(); // Cheap clone of client
+        let download_dir = download_dir.clone();
+        let control = DownloadControl::default();
+        let urls = provider::mirror_urls(&app.providers, &job.url);
+        app.download_controls.insert(job.id, control.clone());
+        tokio::spawn(async move {
+            // Passed on to the event loop until the download drops its sender
+            let (download_tx, mut download_rx) = tokio::sync::mpsc::channel(100);
+            let forward = async move {
+                while let Some(evt) = download_rx.recv().await {
+                    let _ = tx_clone.send(AppEvent::Download(evt)).await;
+                }
+            };
+            let download = downloader_clone.download_file(job.id, urls, download_dir, control, download_tx);
+            let _ = tokio::join!(download, forward);
+        });
+    }
+    Ok(())
+}
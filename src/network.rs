@@ -1,19 +1,43 @@
 use anyhow::{anyhow, Result};
 use futures::StreamExt;
-use reqwest::Client;
+use reqwest::{header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-#[derive(Clone, Debug)]
+use crate::config::Config;
+use crate::queue::JobId;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DownloadFormat {
     Pbf,
+    #[serde(rename = "shp", alias = "shapefile")]
     Shapefile,
 }
 
 impl DownloadFormat {
+    /// Parses `pbf`, `shp` or `shapefile`, ignoring case.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "pbf" => Some(DownloadFormat::Pbf),
+            "shp" | "shapefile" => Some(DownloadFormat::Shapefile),
+            _ => None,
+        }
+    }
+
+    /// `pbf` or `shp`, as used in table names.
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            DownloadFormat::Pbf => "pbf",
+            DownloadFormat::Shapefile => "shp",
+        }
+    }
+
     pub fn suffix(&self) -> &'static str {
         match self {
             DownloadFormat::Pbf => "-latest.osm.pbf",
@@ -22,78 +46,189 @@ impl DownloadFormat {
     }
 }
 
+/// Snapshot of a running transfer.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadProgress {
+    pub percent: f64, // 0.0 to 100.0
+    pub downloaded: u64,
+    pub total: u64, // 0 if the server didn't send a length
+    pub bytes_per_sec: f64,
+}
+
+impl DownloadProgress {
+    pub fn eta(&self) -> Option<Duration> {
+        if self.total == 0 || self.bytes_per_sec <= 0.0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.downloaded) as f64;
+        Some(Duration::from_secs_f64(remaining / self.bytes_per_sec))
+    }
+}
+
+/// A finished download, with what is known about where it came from. It is also
+/// stored next to the file (as `<file>.meta`), so the next download of the same
+/// file can ask the server whether it changed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DownloadedFile {
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The URL it was fetched from, a mirror's if the primary one failed.
+    pub url: String,
+    pub size: u64,
+    /// MD5 of the file, and whether it matched one published by the mirror.
+    pub md5: String,
+    pub verified: bool,
+    pub etag: Option<String>,
+    /// `Last-Modified` of the response, or the modification time of a local copy.
+    pub last_modified: Option<String>,
+    /// Nothing was transferred: the copy from an earlier download is up to date.
+    #[serde(skip)]
+    pub unchanged: bool,
+}
+
 #[derive(Debug)]
 pub enum DownloadEvent {
-    Progress(f64), // Percentage 0.0 to 100.0
-    Complete(PathBuf),
-    Error(String),
-    ImportStarted,
-    ImportFinished(String), // Message
-    ImportFailed(String), // Error message
+    Progress(JobId, DownloadProgress),
+    Verifying(JobId),
+    VerificationFailed(JobId, String), // Error message
+    Complete(JobId, DownloadedFile),
+    UpToDate(JobId, DownloadedFile), // The earlier download is current, nothing was transferred
+    Retrying(JobId, String), // Error message of the failed attempt
+    Paused(JobId),    // Stopped by the user, `.part` file kept for resuming
+    Cancelled(JobId), // Stopped by the user, partial files removed
+    Error(JobId, String),
+}
+
+/// Lets the UI stop a running download, either for good (`cancel`) or so that it
+/// can be resumed later from its `.part` file (`pause`).
+#[derive(Clone, Debug, Default)]
+pub struct DownloadControl {
+    cancel: CancellationToken,
+    pause: CancellationToken,
+}
+
+impl DownloadControl {
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn pause(&self) {
+        self.pause.cancel();
+    }
 }
 
+/// HTTP client plus the retry settings from the config. Cloning is cheap, the
+/// connection pool is shared.
+#[derive(Clone)]
 pub struct Downloader {
     client: Client,
+    max_retries: u32,
+    retry_delay: Duration,
 }
 
 impl Downloader {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         Self {
             client: Client::builder()
-                .timeout(Duration::from_secs(300)) // Increased timeout to 5 minutes
+                .timeout(config.timeout())
+                .connect_timeout(config.connect_timeout())
                 .build()
                 .unwrap_or_default(),
+            max_retries: config.retry.max_retries,
+            retry_delay: config.retry_delay(),
         }
     }
 
-    pub fn construct_url(
+    /// Downloads the first of `urls` that works; the others are mirrors of the
+    /// same file, tried in order once the retries for the previous one run out.
+    pub async fn download_file(
         &self,
-        continent: &str,
-        country: &str,
-        region: &str,
-        format: &DownloadFormat,
-    ) -> String {
-        let base = "https://download.geofabrik.de";
-        let suffix = format.suffix();
-        
-        // Normalize inputs (basic trim and lowercase)
-        let continent = continent.trim().to_lowercase();
-        let country = country.trim().to_lowercase();
-        let region = region.trim().to_lowercase();
-
-        if region.is_empty() {
-            if country.is_empty() {
-                format!("{}/{}{}", base, continent, suffix)
-            } else {
-                format!("{}/{}/{}{}", base, continent, country, suffix)
+        job: JobId,
+        urls: Vec<String>,
+        output_dir: PathBuf,
+        control: DownloadControl,
+        tx: tokio::sync::mpsc::Sender<DownloadEvent>,
+    ) -> Result<DownloadedFile> {
+        let url = urls.first().cloned().unwrap_or_default();
+        // Dropping the transfer future closes the connection and the `.part` file.
+        tokio::select! {
+            biased;
+            _ = control.cancel.cancelled() => {
+                // Any of the mirrors may have been the one in progress.
+                discard_partial_files(&urls, &output_dir);
+                info!("Download cancelled: {}", url);
+                let _ = tx.send(DownloadEvent::Cancelled(job)).await;
+                Err(anyhow!("Download cancelled"))
+            }
+            _ = control.pause.cancelled() => {
+                info!("Download paused: {}", url);
+                let _ = tx.send(DownloadEvent::Paused(job)).await;
+                Err(anyhow!("Download paused"))
+            }
+            result = self.download_from_mirrors(job, &urls, &output_dir, &tx) => {
+                match &result {
+                    Ok(file) if file.unchanged => {
+                        info!("Already up to date: {:?}", file.path);
+                        let _ = tx.send(DownloadEvent::UpToDate(job, file.clone())).await;
+                    }
+                    Ok(file) => {
+                        info!("Download completed: {:?}", file.path);
+                        if let Err(e) = file.save_meta().await {
+                            warn!("Failed to store the headers of {:?}: {}", file.path, e);
+                        }
+                        let _ = tx.send(DownloadEvent::Complete(job, file.clone())).await;
+                    }
+                    Err(_) => {}
+                }
+                result
             }
-        } else {
-             format!("{}/{}/{}/{}{}", base, continent, country, region, suffix)
         }
     }
 
-    pub async fn download_file(
+    async fn download_from_mirrors(
         &self,
-        url: String,
-        output_dir: PathBuf,
-        tx: tokio::sync::mpsc::Sender<DownloadEvent>,
-    ) -> Result<PathBuf> {
-        let max_retries = 3;
+        job: JobId,
+        urls: &[String],
+        output_dir: &Path,
+        tx: &tokio::sync::mpsc::Sender<DownloadEvent>,
+    ) -> Result<DownloadedFile> {
+        let mut last_error = anyhow!("No download URL");
+        for (i, url) in urls.iter().enumerate() {
+            match self.download_with_retries(job, url, output_dir, tx).await {
+                Ok(file) => return Ok(file),
+                Err(e) => last_error = e,
+            }
+            if let Some(next) = urls.get(i + 1) {
+                let msg = format!("{:#}; falling back to {}", last_error, next);
+                warn!("{}", msg);
+                let _ = tx.send(DownloadEvent::Retrying(job, msg)).await;
+            }
+        }
+        let _ = tx.send(DownloadEvent::Error(job, last_error.to_string())).await;
+        Err(last_error)
+    }
+
+    async fn download_with_retries(
+        &self,
+        job: JobId,
+        url: &str,
+        output_dir: &Path,
+        tx: &tokio::sync::mpsc::Sender<DownloadEvent>,
+    ) -> Result<DownloadedFile> {
+        let max_retries = self.max_retries;
         let mut retry_count = 0;
 
         loop {
-            match self.attempt_download(&url, &output_dir, &tx).await {
-                Ok(path) => return Ok(path),
+            match self.attempt_download(job, url, output_dir, tx).await {
+                Ok(file) => return Ok(file),
                 Err(e) => {
                     retry_count += 1;
                     if retry_count >= max_retries {
-                        let err_msg = format!("Failed after {} retries: {}", max_retries, e);
-                        let _ = tx.send(DownloadEvent::Error(err_msg.clone())).await;
-                        return Err(anyhow!(err_msg));
+                        return Err(anyhow!("Failed after {} retries: {}", max_retries, e));
                     }
-                    let _ = tx.send(DownloadEvent::Error(format!("Retry {}/{}: {}", retry_count, max_retries, e))).await;
+                    let _ = tx.send(DownloadEvent::Retrying(job, format!("Retry {}/{}: {}", retry_count, max_retries, e))).await;
                     warn!("Download failed, retrying ({}/{}): {}", retry_count, max_retries, e);
-                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    tokio::time::sleep(self.retry_delay).await;
                 }
             }
         }
@@ -101,35 +236,188 @@ impl Downloader {
 
     async fn attempt_download(
         &self,
+        job: JobId,
         url: &str,
         output_dir: &Path,
         tx: &tokio::sync::mpsc::Sender<DownloadEvent>,
-    ) -> Result<PathBuf> {
-        info!("Starting download from: {}", url);
-        let response = self.client.get(url).send().await?;
+    ) -> Result<DownloadedFile> {
+        let paths = DownloadPaths::for_url(url, output_dir);
+        if let Some(source) = url.strip_prefix("file://") {
+            if let Some(previous) = DownloadedFile::previous(&paths.file).await {
+                let metadata = tokio::fs::metadata(source).await?;
+                let modified = metadata.modified().ok().map(http_date);
+                if previous.url == url && is_unchanged(&previous, Some(metadata.len()), &None, &modified) {
+                    return Ok(previous);
+                }
+            }
+            let file = self.copy_local(job, Path::new(source), &paths, tx).await?;
+            return Ok(DownloadedFile { url: url.to_string(), ..file });
+        }
+        let expected_md5 = self.fetch_md5(url).await;
 
-        if !response.status().is_success() {
-            return Err(anyhow!("HTTP Error: {}", response.status()));
+        // Pick up where a previous attempt (or a previous run of the app) left off,
+        // but only if the partial file belongs to the same URL.
+        let mut meta = PartMeta::load(&paths.meta).await.filter(|m| m.url == url);
+        let mut existing = match (&meta, tokio::fs::metadata(&paths.part).await) {
+            (Some(_), Ok(m)) => m.len(),
+            _ => 0,
+        };
+
+        // A resumed download is of a newer file than the one there may be already.
+        // Validators and sizes only mean something to the server that handed them out.
+        let previous = match existing {
+            0 => DownloadedFile::previous(&paths.file).await.filter(|p| p.url == url),
+            _ => None,
+        };
+
+        let mut request = self.client.get(url);
+        if existing > 0 {
+            info!("Resuming download from byte {}: {}", existing, url);
+            request = request.header(header::RANGE, format!("bytes={}-", existing));
+            if let Some(etag) = meta.as_ref().and_then(|m| m.etag.as_deref()) {
+                request = request.header(header::IF_RANGE, etag);
+            }
+        } else {
+            info!("Starting download from: {}", url);
+            if let Some(previous) = &previous {
+                if let Some(etag) = &previous.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(date) = &previous.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, date);
+                }
+            }
         }
+        let response = request.send().await?;
+        let status = response.status();
+        let header_value = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.to_string())
+        };
+        let etag = header_value(header::ETAG);
+        let last_modified = header_value(header::LAST_MODIFIED);
 
-        let total_size = response.content_length().unwrap_or(0);
-        
-        // Extract filename from URL
-        let filename = url.split('/').last().unwrap_or("downloaded_file");
-        let file_path = output_dir.join(filename);
+        if let Some(previous) = previous {
+            let same = status == StatusCode::OK
+                && is_unchanged(&previous, response.content_length(), &etag, &last_modified);
+            if status == StatusCode::NOT_MODIFIED || same {
+                // Dropping the response closes the connection without reading the body
+                return Ok(previous);
+            }
+        }
+
+        if status == StatusCode::RANGE_NOT_SATISFIABLE && existing > 0 {
+            // The partial file is already complete (or garbage); decide by the reported size.
+            let total = response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range)
+                .map(|(_, total)| total);
+            if total == Some(existing) {
+                let mut hasher = md5::Context::new();
+                hash_file(&paths.part, &mut hasher).await?;
+                let file = self.finish_download(job, &paths, hasher, expected_md5, tx).await?;
+                let meta = meta.unwrap_or_default();
+                return Ok(DownloadedFile {
+                    url: url.to_string(),
+                    etag: meta.etag,
+                    last_modified: meta.last_modified,
+                    ..file
+                });
+            }
+            warn!("Server rejected resume range, restarting download: {}", url);
+            PartMeta::discard(&paths.part, &paths.meta).await;
+            return Err(anyhow!("HTTP Error: {}", status));
+        }
+
+        if !status.is_success() {
+            return Err(anyhow!("HTTP Error: {}", status));
+        }
+
+        let resuming = if status == StatusCode::PARTIAL_CONTENT && existing > 0 {
+            let range = response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range);
+            let stored_etag = meta.as_ref().and_then(|m| m.etag.as_deref());
+            let etag_matches = match (stored_etag, etag.as_deref()) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            };
+            match range {
+                Some((start, _)) if start == existing && etag_matches => true,
+                _ => {
+                    // Unexpected range or the remote file changed; the bytes we have are useless.
+                    PartMeta::discard(&paths.part, &paths.meta).await;
+                    return Err(anyhow!("Server returned mismatched range or ETag, restarting download"));
+                }
+            }
+        } else {
+            // Full response: either a fresh download or the server ignored/refused the range.
+            false
+        };
+
+        let total_size = if resuming {
+            existing + response.content_length().unwrap_or(0)
+        } else {
+            existing = 0;
+            response.content_length().unwrap_or(0)
+        };
 
-        let mut file = File::create(&file_path).await?;
+        if !resuming {
+            meta = Some(PartMeta {
+                url: url.to_string(),
+                etag,
+                last_modified,
+                total_size,
+            });
+            meta.as_ref().unwrap().save(&paths.meta).await?;
+        }
+
+        // The checksum covers the whole file, so bytes from a previous attempt are hashed first.
+        let mut hasher = md5::Context::new();
+        let mut file = if resuming {
+            hash_file(&paths.part, &mut hasher).await?;
+            OpenOptions::new().append(true).open(&paths.part).await?
+        } else {
+            File::create(&paths.part).await?
+        };
         let mut stream = response.bytes_stream();
-        let mut downloaded: u64 = 0;
+        let mut downloaded: u64 = existing;
+        let started = Instant::now();
+        let mut last_report: Option<Instant> = None;
 
         while let Some(chunk_result) = stream.next().await {
             let chunk = chunk_result?;
             file.write_all(&chunk).await?;
+            hasher.consume(&chunk);
             downloaded += chunk.len() as u64;
 
-            if total_size > 0 {
-                let percentage = (downloaded as f64 / total_size as f64) * 100.0;
-                let _ = tx.send(DownloadEvent::Progress(percentage)).await;
+            // Several jobs share the UI channel, so report a few times a second rather than per chunk.
+            let due = last_report.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL);
+            if due || downloaded == total_size {
+                last_report = Some(Instant::now());
+                let elapsed = started.elapsed().as_secs_f64();
+                let progress = DownloadProgress {
+                    percent: if total_size > 0 {
+                        (downloaded as f64 / total_size as f64) * 100.0
+                    } else {
+                        0.0
+                    },
+                    downloaded,
+                    total: total_size,
+                    bytes_per_sec: if elapsed > 0.0 {
+                        (downloaded - existing) as f64 / elapsed
+                    } else {
+                        0.0
+                    },
+                };
+                let _ = tx.send(DownloadEvent::Progress(job, progress)).await;
             }
         }
 
@@ -140,38 +428,705 @@ impl Downloader {
                 total_size, downloaded
             );
             warn!("{}", msg);
-            let _ = tx.send(DownloadEvent::Error(msg.clone())).await;
             return Err(anyhow!(msg));
         }
-        let _ = tx.send(DownloadEvent::Complete(file_path.clone())).await;
-        info!("Download completed: {:?}", file_path);
-        
-        Ok(file_path)
+
+        let file = self.finish_download(job, &paths, hasher, expected_md5, tx).await?;
+        // A resumed download keeps the headers of the response it started with
+        let meta = meta.unwrap_or_default();
+        Ok(DownloadedFile {
+            url: url.to_string(),
+            etag: meta.etag,
+            last_modified: meta.last_modified,
+            ..file
+        })
+    }
+
+    /// Copies an extract from a local mirror, checking it against the `.md5` file
+    /// next to it when there is one.
+    async fn copy_local(
+        &self,
+        job: JobId,
+        source: &Path,
+        paths: &DownloadPaths,
+        tx: &tokio::sync::mpsc::Sender<DownloadEvent>,
+    ) -> Result<DownloadedFile> {
+        info!("Copying from local mirror: {:?}", source);
+        let mut md5_path = source.as_os_str().to_owned();
+        md5_path.push(".md5");
+        let expected_md5 = tokio::fs::read_to_string(&md5_path)
+            .await
+            .ok()
+            .and_then(|body| parse_md5_sidecar(&body));
+
+        let mut input = File::open(source).await?;
+        let metadata = input.metadata().await?;
+        let total = metadata.len();
+        let mut output = File::create(&paths.part).await?;
+        let mut hasher = md5::Context::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut copied = 0u64;
+        loop {
+            let n = input.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            output.write_all(&buf[..n]).await?;
+            hasher.consume(&buf[..n]);
+            copied += n as u64;
+        }
+        output.flush().await?;
+        let progress = DownloadProgress {
+            percent: 100.0,
+            downloaded: copied,
+            total,
+            bytes_per_sec: 0.0,
+        };
+        let _ = tx.send(DownloadEvent::Progress(job, progress)).await;
+
+        let file = self.finish_download(job, paths, hasher, expected_md5, tx).await?;
+        Ok(DownloadedFile {
+            last_modified: metadata.modified().ok().map(http_date),
+            ..file
+        })
+    }
+
+    async fn finish_download(
+        &self,
+        job: JobId,
+        paths: &DownloadPaths,
+        hasher: md5::Context,
+        expected_md5: Option<String>,
+        tx: &tokio::sync::mpsc::Sender<DownloadEvent>,
+    ) -> Result<DownloadedFile> {
+        let actual_md5 = format!("{:x}", hasher.compute());
+        if let Some(expected) = &expected_md5 {
+            let _ = tx.send(DownloadEvent::Verifying(job)).await;
+            if *expected != actual_md5 {
+                let msg = format!(
+                    "Checksum mismatch: expected MD5 {}, got {}",
+                    expected, actual_md5
+                );
+                warn!("{}", msg);
+                // Never leave a corrupt file where it could be resumed or imported.
+                PartMeta::discard(&paths.part, &paths.meta).await;
+                let _ = tx.send(DownloadEvent::VerificationFailed(job, msg.clone())).await;
+                return Err(anyhow!(msg));
+            }
+            info!("Checksum verified: {}", actual_md5);
+        }
+
+        tokio::fs::rename(&paths.part, &paths.file).await?;
+        let _ = tokio::fs::remove_file(&paths.meta).await;
+
+        Ok(DownloadedFile {
+            path: paths.file.clone(),
+            size: tokio::fs::metadata(&paths.file).await?.len(),
+            verified: expected_md5.is_some(),
+            md5: actual_md5,
+            ..Default::default()
+        })
     }
 }
 
+impl Downloader {
+    /// Asks the server for the size of `url` with a `HEAD` request.
+    pub async fn remote_size(&self, url: &str) -> Result<Option<u64>> {
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Some(tokio::fs::metadata(path).await?.len()));
+        }
+        let response = self.client.head(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("HTTP Error: {}", response.status()));
+        }
+        Ok(response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()))
+    }
+
+    /// Reads a small file (state or change file) from an `http(s)://` or `file://` URL.
+    pub async fn fetch_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        if let Some(path) = url.strip_prefix("file://") {
+            return tokio::fs::read(path)
+                .await
+                .map_err(|e| anyhow!("Failed to read {}: {}", url, e));
+        }
+        let response = self.client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("HTTP Error: {} for {}", response.status(), url));
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Fetches the `<url>.md5` sidecar Geofabrik publishes next to every extract.
+    /// Returns `None` (and logs) if the mirror doesn't provide one.
+    async fn fetch_md5(&self, url: &str) -> Option<String> {
+        let md5_url = format!("{}.md5", url);
+        let response = match self.client.get(&md5_url).send().await {
+            Ok(r) if r.status().is_success() => r,
+            Ok(r) => {
+                warn!("No checksum available at {}: HTTP {}", md5_url, r.status());
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to fetch checksum {}: {}", md5_url, e);
+                return None;
+            }
+        };
+        let body = response.text().await.ok()?;
+        let checksum = parse_md5_sidecar(&body);
+        if checksum.is_none() {
+            warn!("Unrecognised checksum file at {}", md5_url);
+        }
+        checksum
+    }
+}
+
+async fn hash_file(path: &Path, hasher: &mut md5::Context) -> Result<()> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.consume(&buf[..n]);
+    }
+}
+
+impl DownloadedFile {
+    /// The earlier download stored at `path`, if the file is still there in full.
+    async fn previous(path: &Path) -> Option<Self> {
+        let bytes = tokio::fs::read(meta_path(path)).await.ok()?;
+        let previous: Self = serde_json::from_slice(&bytes).ok()?;
+        let size = tokio::fs::metadata(path).await.ok()?.len();
+        (size == previous.size).then(|| Self {
+            path: path.to_path_buf(),
+            unchanged: true,
+            ..previous
+        })
+    }
+
+    async fn save_meta(&self) -> Result<()> {
+        tokio::fs::write(meta_path(&self.path), serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+}
+
+/// The sidecar a finished download's headers are kept in.
+pub fn meta_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".meta");
+    PathBuf::from(path)
+}
+
+/// Whether a full response (or a local file) is the file of an earlier download:
+/// the same size, and no validator that differs.
+fn is_unchanged(
+    previous: &DownloadedFile,
+    size: Option<u64>,
+    etag: &Option<String>,
+    last_modified: &Option<String>,
+) -> bool {
+    let differs = |old: &Option<String>, new: &Option<String>| matches!((old, new), (Some(a), Some(b)) if a != b);
+    size == Some(previous.size) && !differs(&previous.etag, etag) && !differs(&previous.last_modified, last_modified)
+}
+
+/// Formats a time the way HTTP headers such as `Last-Modified` do.
+pub fn http_date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Parses an `md5sum`-style line (`<hex>  <filename>`) into the lowercase hex digest.
+fn parse_md5_sidecar(body: &str) -> Option<String> {
+    let digest = body.split_whitespace().next()?.to_ascii_lowercase();
+    if digest.len() == 32 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Final file of a download plus the `.part` data and `.part.meta` sidecar used while it runs.
+struct DownloadPaths {
+    file: PathBuf,
+    part: PathBuf,
+    meta: PathBuf,
+}
+
+impl DownloadPaths {
+    fn for_url(url: &str, output_dir: &Path) -> Self {
+        // Extract filename from URL
+        let filename = url.split('/').next_back().unwrap_or("downloaded_file");
+        Self {
+            file: output_dir.join(filename),
+            part: output_dir.join(format!("{}.part", filename)),
+            meta: output_dir.join(format!("{}.part.meta", filename)),
+        }
+    }
+}
+
+/// Removes the `.part` files (and sidecars) that a download from any of `urls`,
+/// mirrors of the same file, left in `output_dir`.
+pub fn discard_partial_files(urls: &[String], output_dir: &Path) {
+    for url in urls {
+        let paths = DownloadPaths::for_url(url, output_dir);
+        let _ = std::fs::remove_file(&paths.part);
+        let _ = std::fs::remove_file(&paths.meta);
+    }
+}
+
+/// Sidecar stored next to a `.part` file so an interrupted download can be
+/// resumed safely, even after the app has been restarted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PartMeta {
+    url: String,
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    total_size: u64,
+}
+
+impl PartMeta {
+    async fn load(path: &Path) -> Option<Self> {
+        let bytes = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        tokio::fs::write(path, serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+
+    async fn discard(part_path: &Path, meta_path: &Path) {
+        let _ = tokio::fs::remove_file(part_path).await;
+        let _ = tokio::fs::remove_file(meta_path).await;
+    }
+}
+
+/// Parses a `Content-Range: bytes start-end/total` header into `(start, total)`.
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let rest = value.trim().strip_prefix("bytes")?.trim_start();
+    let (range, total) = rest.split_once('/')?;
+    let total = total.trim().parse().ok()?;
+    let start = if range.trim() == "*" {
+        0
+    } else {
+        range.split_once('-')?.0.trim().parse().ok()?
+    };
+    Some((start, total))
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
-    fn test_construct_url() {
-        let downloader = Downloader::new();
-        
-        // Case 1: Continent/Country/Region
-        let url = downloader.construct_url("Asia", "Indonesia", "Kalimantan", &DownloadFormat::Pbf);
-        assert_eq!(url, "https://download.geofabrik.de/asia/indonesia/kalimantan-latest.osm.pbf");
-
-        // Case 2: Continent/Country
-        let url = downloader.construct_url("Europe", "Germany", "", &DownloadFormat::Shapefile);
-        assert_eq!(url, "https://download.geofabrik.de/europe/germany-latest-free.shp.zip");
-
-        // Case 3: Continent only
-        let url = downloader.construct_url("Africa", "", "", &DownloadFormat::Pbf);
-        assert_eq!(url, "https://download.geofabrik.de/africa-latest.osm.pbf");
-        
-        // Case 4: Trimming and Lowercase
-        let url = downloader.construct_url(" Asia ", " Indonesia ", " Kalimantan ", &DownloadFormat::Pbf);
-        assert_eq!(url, "https://download.geofabrik.de/asia/indonesia/kalimantan-latest.osm.pbf");
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, 200)));
+        assert_eq!(parse_content_range("bytes */200"), Some((0, 200)));
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+    }
+
+    /// Minimal HTTP server that honours `Range` requests and can be told to drop
+    /// the first response after `cut_at` bytes. Requests for `*.md5` are answered
+    /// with `md5` (or a 404), requests with `If-None-Match: "v1"` with a 304.
+    /// Returns the base URL and the `Range` start offsets of every file request
+    /// it served.
+    async fn spawn_flaky_server(
+        body: Vec<u8>,
+        cut_at: Option<usize>,
+        md5: Option<String>,
+    ) -> (String, std::sync::Arc<std::sync::Mutex<Vec<Option<u64>>>>) {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen_server = seen.clone();
+
+        tokio::spawn(async move {
+            let mut served = 0;
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(socket);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).await.unwrap();
+                let mut range_start = None;
+                let mut not_modified = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    let line = line.to_ascii_lowercase();
+                    if let Some(v) = line.strip_prefix("range: bytes=") {
+                        range_start = v.trim().trim_end_matches('-').parse::<u64>().ok();
+                    }
+                    not_modified |= line.trim() == "if-none-match: \"v1\"";
+                }
+                if request_line.contains(".md5 ") {
+                    let response = match &md5 {
+                        Some(sum) => {
+                            let line = format!("{}  region-latest.osm.pbf\n", sum);
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                line.len(),
+                                line
+                            )
+                        }
+                        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                    };
+                    let mut socket = reader.into_inner();
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                    continue;
+                }
+                seen_server.lock().unwrap().push(range_start);
+                if not_modified {
+                    let mut socket = reader.into_inner();
+                    socket.write_all(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n").await.unwrap();
+                    continue;
+                }
+
+                let start = range_start.unwrap_or(0) as usize;
+                let head = if range_start.is_some() {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nETag: \"v1\"\r\nLast-Modified: Wed, 01 May 2024 20:21:02 GMT\r\nConnection: close\r\n\r\n",
+                        body.len() - start, start, body.len() - 1, body.len()
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\nLast-Modified: Wed, 01 May 2024 20:21:02 GMT\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                };
+                let end = match cut_at {
+                    Some(cut) if served == 0 => cut,
+                    _ => body.len(),
+                };
+                let mut socket = reader.into_inner();
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body[start..end]).await.unwrap();
+                socket.flush().await.unwrap();
+                drop(socket);
+                served += 1;
+            }
+        });
+
+        (format!("http://{}", addr), seen)
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_after_connection_drop() {
+        let body: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let md5 = format!("{:x}", md5::compute(&body));
+        let (base, seen) = spawn_flaky_server(body.clone(), Some(20_000), Some(md5.clone())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let events = tokio::spawn(async move {
+            let mut completed = None;
+            while let Some(evt) = rx.recv().await {
+                if let DownloadEvent::Complete(_, file) = evt {
+                    completed = Some(file);
+                }
+            }
+            completed
+        });
+
+        let url = format!("{}/region-latest.osm.pbf", base);
+        let file = Downloader::new(&Config::default())
+            .download_file(1, vec![url.clone()], dir.path().to_path_buf(), DownloadControl::default(), tx)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&file.path).unwrap(), body);
+        let completed = events.await.unwrap().unwrap();
+        assert_eq!((completed.url, completed.size), (url, body.len() as u64));
+        assert_eq!((completed.md5, completed.verified), (md5, true));
+        assert_eq!(completed.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(completed.last_modified.as_deref(), Some("Wed, 01 May 2024 20:21:02 GMT"));
+        assert!(!dir.path().join("region-latest.osm.pbf.part").exists());
+        assert!(!dir.path().join("region-latest.osm.pbf.part.meta").exists());
+        assert_eq!(*seen.lock().unwrap(), vec![None, Some(20_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_existing_part_file() {
+        let body: Vec<u8> = (0..10_000).map(|i| (i % 13) as u8).collect();
+        let md5 = format!("{:x}", md5::compute(&body));
+        let (base, seen) = spawn_flaky_server(body.clone(), None, Some(md5)).await;
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("{}/region-latest.osm.pbf", base);
+
+        // Leftovers from a previous run of the app.
+        std::fs::write(dir.path().join("region-latest.osm.pbf.part"), &body[..4_000]).unwrap();
+        PartMeta {
+            url: url.clone(),
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+            total_size: body.len() as u64,
+        }
+        .save(&dir.path().join("region-latest.osm.pbf.part.meta"))
+        .await
+        .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        let file = Downloader::new(&Config::default())
+            .download_file(1, vec![url], dir.path().to_path_buf(), DownloadControl::default(), tx)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&file.path).unwrap(), body);
+        assert_eq!(*seen.lock().unwrap(), vec![Some(4_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_file_is_not_downloaded_again() {
+        let body: Vec<u8> = (0..10_000).map(|i| (i % 11) as u8).collect();
+        let (base, seen) = spawn_flaky_server(body.clone(), None, None).await;
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("{}/region-latest.osm.pbf", base);
+        let output_dir = dir.path().to_path_buf();
+        let download_from = |url: String| {
+            let output_dir = output_dir.clone();
+            async move {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+                let events = tokio::spawn(async move {
+                    let mut seen = Vec::new();
+                    while let Some(evt) = rx.recv().await {
+                        match evt {
+                            DownloadEvent::Complete(..) => seen.push("complete"),
+                            DownloadEvent::UpToDate(..) => seen.push("up to date"),
+                            _ => {}
+                        }
+                    }
+                    seen
+                });
+                let file = Downloader::new(&Config::default())
+                    .download_file(1, vec![url], output_dir, DownloadControl::default(), tx)
+                    .await
+                    .unwrap();
+                (file, events.await.unwrap())
+            }
+        };
+        let download = || download_from(url.clone());
+
+        let (first, events) = download().await;
+        assert_eq!((first.unchanged, events), (false, vec!["complete"]));
+        assert!(dir.path().join("region-latest.osm.pbf.meta").exists());
+        let (second, events) = download().await;
+        assert_eq!((second.unchanged, events), (true, vec!["up to date"]));
+        assert_eq!((second.path, second.md5), (first.path.clone(), first.md5));
+
+        // A file that is no longer the one downloaded is fetched again
+        std::fs::write(&first.path, b"truncated").unwrap();
+        let (third, events) = download().await;
+        assert_eq!((third.unchanged, events), (false, vec!["complete"]));
+        assert_eq!(std::fs::read(&third.path).unwrap(), body);
+        assert_eq!(*seen.lock().unwrap(), vec![None, None, None]);
+
+        // Another server's file of the same size and ETag may still differ
+        let (mirror, _) = spawn_flaky_server(body.clone(), None, None).await;
+        let (fourth, events) = download_from(format!("{}/region-latest.osm.pbf", mirror)).await;
+        assert_eq!((fourth.unchanged, events), (false, vec!["complete"]));
+    }
+
+    #[test]
+    fn test_unchanged_needs_same_size_and_validators() {
+        let previous = DownloadedFile {
+            size: 100,
+            etag: Some("\"v1\"".to_string()),
+            ..Default::default()
+        };
+        let etag = |tag: &str| Some(tag.to_string());
+        assert!(is_unchanged(&previous, Some(100), &etag("\"v1\""), &None));
+        // Servers that don't send an ETag are judged by the size
+        assert!(is_unchanged(&previous, Some(100), &None, &etag("Wed, 01 May 2024 20:21:02 GMT")));
+        assert!(!is_unchanged(&previous, Some(100), &etag("\"v2\""), &None));
+        assert!(!is_unchanged(&previous, Some(101), &etag("\"v1\""), &None));
+        assert!(!is_unchanged(&previous, None, &None, &None));
+    }
+
+    #[test]
+    fn test_parse_md5_sidecar() {
+        assert_eq!(
+            parse_md5_sidecar("D41D8CD98F00B204E9800998ECF8427E  monaco-latest.osm.pbf\n"),
+            Some("d41d8cd98f00b204e9800998ecf8427e".to_string())
+        );
+        assert_eq!(parse_md5_sidecar("<html>Not found</html>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_checksum_mismatch() {
+        let body: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+        let bogus = "0".repeat(32);
+        let (base, _) = spawn_flaky_server(body, None, Some(bogus)).await;
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let events = tokio::spawn(async move {
+            let (mut failed, mut completed) = (0, 0);
+            while let Some(evt) = rx.recv().await {
+                match evt {
+                    DownloadEvent::VerificationFailed(..) => failed += 1,
+                    DownloadEvent::Complete(..) => completed += 1,
+                    _ => {}
+                }
+            }
+            (failed, completed)
+        });
+
+        let mut config = Config::default();
+        config.retry.delay_secs = 0;
+        let url = format!("{}/region-latest.osm.pbf", base);
+        let result = Downloader::new(&config)
+            .download_file(1, vec![url], dir.path().to_path_buf(), DownloadControl::default(), tx)
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(events.await.unwrap(), (3, 0));
+        assert!(!dir.path().join("region-latest.osm.pbf").exists());
+        assert!(!dir.path().join("region-latest.osm.pbf.part").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_falls_back_to_local_mirror() {
+        let mirror = tempfile::tempdir().unwrap();
+        let body = b"local extract".to_vec();
+        let source = mirror.path().join("region-latest.osm.pbf");
+        std::fs::write(&source, &body).unwrap();
+        std::fs::write(
+            mirror.path().join("region-latest.osm.pbf.md5"),
+            format!("{:x}  region-latest.osm.pbf\n", md5::compute(&body)),
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config.retry.max_retries = 1;
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let events = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while let Some(evt) = rx.recv().await {
+                match evt {
+                    DownloadEvent::Retrying(_, msg) => seen.push(msg),
+                    DownloadEvent::Complete(_, file) => seen.push(format!("complete {}", file.verified)),
+                    _ => {}
+                }
+            }
+            seen
+        });
+
+        // Nothing listens on port 9, so the primary fails straight away.
+        let urls = vec![
+            "http://127.0.0.1:9/region-latest.osm.pbf".to_string(),
+            format!("file://{}", source.display()),
+        ];
+        let file = Downloader::new(&config)
+            .download_file(1, urls.clone(), dir.path().to_path_buf(), DownloadControl::default(), tx)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&file.path).unwrap(), body);
+        assert_eq!(file.url, urls[1]);
+        assert!(file.last_modified.is_some_and(|date| date.ends_with(" GMT")));
+        let seen = events.await.unwrap();
+        assert!(seen[0].contains("falling back to file://"), "{:?}", seen);
+        assert_eq!(seen[1], "complete true");
+    }
+
+    /// Serves the headers and the first `sent` bytes of `body`, then stalls so the
+    /// transfer stays in flight until the client gives up.
+    async fn spawn_stalling_server(body: Vec<u8>, sent: usize) -> String {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(socket);
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).await.unwrap();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).await.unwrap();
+                        if line == "\r\n" || line.is_empty() {
+                            break;
+                        }
+                    }
+                    let mut socket = reader.into_inner();
+                    if request_line.contains(".md5 ") {
+                        let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+                        return;
+                    }
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n", body.len());
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    socket.write_all(&body[..sent]).await.unwrap();
+                    socket.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Starts a download against a stalling server, waits for the first progress
+    /// event, then pauses or cancels it. Returns the final event and the output dir.
+    pub(crate) async fn stop_in_flight_download(pause: bool) -> (DownloadEvent, tempfile::TempDir) {
+        let body = vec![7u8; 50_000];
+        let base = spawn_stalling_server(body, 20_000).await;
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+        let control = DownloadControl::default();
+
+        let url = format!("{}/region-latest.osm.pbf", base);
+        let task = tokio::spawn({
+            let control = control.clone();
+            let output_dir = dir.path().to_path_buf();
+            async move { Downloader::new(&Config::default()).download_file(1, vec![url], output_dir, control, tx).await }
+        });
+        while let Some(evt) = rx.recv().await {
+            if matches!(evt, DownloadEvent::Progress(..)) {
+                break;
+            }
+        }
+        if pause {
+            control.pause();
+        } else {
+            control.cancel();
+        }
+
+        assert!(task.await.unwrap().is_err());
+        let mut last = None;
+        while let Some(evt) = rx.recv().await {
+            last = Some(evt);
+        }
+        (last.unwrap(), dir)
+    }
+
+    #[tokio::test]
+    async fn test_cancel_removes_partial_files() {
+        let (last, dir) = stop_in_flight_download(false).await;
+        assert!(matches!(last, DownloadEvent::Cancelled(1)));
+        assert!(!dir.path().join("region-latest.osm.pbf.part").exists());
+        assert!(!dir.path().join("region-latest.osm.pbf.part.meta").exists());
+    }
+
+    #[tokio::test]
+    async fn test_pause_keeps_partial_files_for_resume() {
+        let (last, dir) = stop_in_flight_download(true).await;
+        assert!(matches!(last, DownloadEvent::Paused(1)));
+        assert!(dir.path().join("region-latest.osm.pbf.part").exists());
+        assert!(dir.path().join("region-latest.osm.pbf.part.meta").exists());
     }
 }
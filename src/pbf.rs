@@ -0,0 +1,657 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::replication::Element;
+
+/// Largest blob the format allows; anything bigger means a corrupt file.
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+const SUPPORTED_FEATURES: [&str; 2] = ["OsmSchema-V0.6", "DenseNodes"];
+
+/// Replication details Osmosis-style writers, Geofabrik among them, put in the
+/// header block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PbfHeader {
+    /// Seconds since the epoch the data is current to.
+    pub replication_timestamp: Option<i64>,
+    pub replication_sequence: Option<i64>,
+}
+
+/// Reads the header block a PBF file starts with.
+pub fn read_header(path: &Path) -> Result<PbfHeader> {
+    let mut reader = PbfReader::new(BufReader::new(File::open(path)?));
+    match reader.next_blob()? {
+        Some((kind, data)) if kind == "OSMHeader" => parse_header(&data),
+        _ => bail!("{} doesn't start with a PBF header", path.display()),
+    }
+}
+
+/// Streams the elements of an `.osm.pbf` file one primitive block (typically
+/// 8000 elements) at a time, so memory use doesn't grow with the file.
+pub struct PbfReader<R> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: Read> PbfReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, position: 0 }
+    }
+
+    /// Bytes of the file read so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The elements of the next data block, or `None` at the end of the file.
+    pub fn next_block(&mut self) -> Result<Option<Vec<Element>>> {
+        while let Some((kind, data)) = self.next_blob()? {
+            match kind.as_str() {
+                "OSMHeader" => {
+                    parse_header(&data)?;
+                }
+                "OSMData" => return decode_block(&data).map(Some),
+                // Unknown blob types are allowed and skipped.
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// The type and decompressed data of the next blob.
+    fn next_blob(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => self.position += 4,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let header = self.read_exact(u32::from_be_bytes(len) as usize)?;
+        let mut kind = String::new();
+        let mut size = 0;
+        for field in Message::new(&header) {
+            match field? {
+                (1, Value::Bytes(b)) => kind = String::from_utf8_lossy(b).into_owned(),
+                (3, Value::Varint(v)) => size = v as usize,
+                _ => {}
+            }
+        }
+        let blob = self.read_exact(size)?;
+        Ok(Some((kind, decompress(&blob)?)))
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>> {
+        if len > MAX_BLOB_SIZE {
+            bail!("Blob of {} bytes is larger than allowed", len);
+        }
+        let mut buf = vec![0; len];
+        self.reader
+            .read_exact(&mut buf)
+            .context("Truncated PBF file")?;
+        self.position += len as u64;
+        Ok(buf)
+    }
+}
+
+fn decompress(blob: &[u8]) -> Result<Vec<u8>> {
+    let mut raw_size = 0;
+    for field in Message::new(blob) {
+        match field? {
+            (1, Value::Bytes(raw)) => return Ok(raw.to_vec()),
+            (2, Value::Varint(v)) => raw_size = v as usize,
+            (3, Value::Bytes(zlib)) => {
+                if raw_size > MAX_BLOB_SIZE {
+                    bail!("Blob of {} bytes is larger than allowed", raw_size);
+                }
+                // The declared size can't be trusted, so stop just past the limit
+                let mut data = Vec::with_capacity(raw_size);
+                ZlibDecoder::new(zlib)
+                    .take(MAX_BLOB_SIZE as u64 + 1)
+                    .read_to_end(&mut data)?;
+                if data.len() > MAX_BLOB_SIZE {
+                    bail!("Blob inflates to more than {} bytes", MAX_BLOB_SIZE);
+                }
+                if data.len() != raw_size {
+                    bail!("Blob inflates to {} bytes instead of the declared {}", data.len(), raw_size);
+                }
+                return Ok(data);
+            }
+            (4 | 6 | 7, _) => bail!("Only raw and zlib-compressed PBF blobs are supported"),
+            _ => {}
+        }
+    }
+    Err(anyhow!("Blob without data"))
+}
+
+/// Reads a header block, checking that the file needs no features this reader lacks.
+fn parse_header(data: &[u8]) -> Result<PbfHeader> {
+    let mut header = PbfHeader::default();
+    for field in Message::new(data) {
+        match field? {
+            (4, Value::Bytes(feature)) => {
+                let feature = String::from_utf8_lossy(feature);
+                if !SUPPORTED_FEATURES.contains(&feature.as_ref()) {
+                    bail!("PBF file requires unsupported feature '{}'", feature);
+                }
+            }
+            (32, Value::Varint(v)) => header.replication_timestamp = Some(v as i64),
+            (33, Value::Varint(v)) => header.replication_sequence = Some(v as i64),
+            _ => {}
+        }
+    }
+    Ok(header)
+}
+
+/// Coordinate encoding of a primitive block.
+struct Block<'a> {
+    strings: Vec<&'a [u8]>,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+}
+
+impl Block<'_> {
+    fn string(&self, index: u64) -> Result<String> {
+        let s = self
+            .strings
+            .get(index as usize)
+            .ok_or_else(|| anyhow!("String index {} out of range", index))?;
+        Ok(String::from_utf8_lossy(s).into_owned())
+    }
+
+    fn tags(&self, keys: &[u64], vals: &[u64]) -> Result<Vec<(String, String)>> {
+        keys.iter()
+            .zip(vals)
+            .map(|(&k, &v)| Ok((self.string(k)?, self.string(v)?)))
+            .collect()
+    }
+
+    fn lat(&self, lat: i64) -> f64 {
+        (self.lat_offset + self.granularity * lat) as f64 / 1e9
+    }
+
+    fn lon(&self, lon: i64) -> f64 {
+        (self.lon_offset + self.granularity * lon) as f64 / 1e9
+    }
+}
+
+fn decode_block(data: &[u8]) -> Result<Vec<Element>> {
+    let mut block = Block {
+        strings: Vec::new(),
+        granularity: 100,
+        lat_offset: 0,
+        lon_offset: 0,
+    };
+    let mut groups = Vec::new();
+    for field in Message::new(data) {
+        match field? {
+            (1, Value::Bytes(table)) => {
+                for field in Message::new(table) {
+                    if let (1, Value::Bytes(s)) = field? {
+                        block.strings.push(s);
+                    }
+                }
+            }
+            (2, Value::Bytes(group)) => groups.push(group),
+            (17, Value::Varint(v)) => block.granularity = v as i64,
+            (19, Value::Varint(v)) => block.lat_offset = v as i64,
+            (20, Value::Varint(v)) => block.lon_offset = v as i64,
+            _ => {}
+        }
+    }
+
+    let mut elements = Vec::new();
+    for group in groups {
+        for field in Message::new(group) {
+            match field? {
+                (1, Value::Bytes(node)) => elements.push(decode_node(&block, node)?),
+                (2, Value::Bytes(dense)) => decode_dense(&block, dense, &mut elements)?,
+                (3, Value::Bytes(way)) => elements.push(decode_way(&block, way)?),
+                (4, Value::Bytes(relation)) => elements.push(decode_relation(&block, relation)?),
+                _ => {}
+            }
+        }
+    }
+    Ok(elements)
+}
+
+fn element(kind: &str, id: i64) -> Element {
+    Element {
+        kind: kind.to_string(),
+        id,
+        ..Element::default()
+    }
+}
+
+fn decode_node(block: &Block, data: &[u8]) -> Result<Element> {
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    let (mut keys, mut vals) = (Vec::new(), Vec::new());
+    for field in Message::new(data) {
+        match field? {
+            (1, Value::Varint(v)) => id = zigzag(v),
+            (2, value) => value.unpack(&mut keys)?,
+            (3, value) => value.unpack(&mut vals)?,
+            (8, Value::Varint(v)) => lat = zigzag(v),
+            (9, Value::Varint(v)) => lon = zigzag(v),
+            _ => {}
+        }
+    }
+    let mut node = element("node", id);
+    node.tags = block.tags(&keys, &vals)?;
+    node.lat = Some(block.lat(lat));
+    node.lon = Some(block.lon(lon));
+    Ok(node)
+}
+
+fn decode_dense(block: &Block, data: &[u8], elements: &mut Vec<Element>) -> Result<()> {
+    let (mut ids, mut lats, mut lons, mut keys_vals) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for field in Message::new(data) {
+        match field? {
+            (1, value) => value.unpack(&mut ids)?,
+            (8, value) => value.unpack(&mut lats)?,
+            (9, value) => value.unpack(&mut lons)?,
+            (10, value) => value.unpack(&mut keys_vals)?,
+            _ => {}
+        }
+    }
+    if lats.len() != ids.len() || lons.len() != ids.len() {
+        bail!("Dense nodes with {} ids but {} lats and {} lons", ids.len(), lats.len(), lons.len());
+    }
+    // Tags of all nodes in one list: key, value, key, value, 0, key, value, 0, ...
+    let mut tags = keys_vals.into_iter();
+    let (mut id, mut lat, mut lon) = (0i64, 0i64, 0i64);
+    for i in 0..ids.len() {
+        id += zigzag(ids[i]);
+        lat += zigzag(lats[i]);
+        lon += zigzag(lons[i]);
+        let mut node = element("node", id);
+        node.lat = Some(block.lat(lat));
+        node.lon = Some(block.lon(lon));
+        while let Some(key) = tags.next().filter(|&k| k != 0) {
+            let value = tags.next().ok_or_else(|| anyhow!("Dense node tag without a value"))?;
+            node.tags.push((block.string(key)?, block.string(value)?));
+        }
+        elements.push(node);
+    }
+    Ok(())
+}
+
+fn decode_way(block: &Block, data: &[u8]) -> Result<Element> {
+    let mut id = 0;
+    let (mut keys, mut vals, mut refs) = (Vec::new(), Vec::new(), Vec::new());
+    for field in Message::new(data) {
+        match field? {
+            (1, Value::Varint(v)) => id = v as i64,
+            (2, value) => value.unpack(&mut keys)?,
+            (3, value) => value.unpack(&mut vals)?,
+            (8, value) => value.unpack(&mut refs)?,
+            _ => {}
+        }
+    }
+    let mut way = element("way", id);
+    way.tags = block.tags(&keys, &vals)?;
+    way.refs = deltas(&refs);
+    Ok(way)
+}
+
+fn decode_relation(block: &Block, data: &[u8]) -> Result<Element> {
+    let mut id = 0;
+    let (mut keys, mut vals, mut roles, mut members, mut types) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for field in Message::new(data) {
+        match field? {
+            (1, Value::Varint(v)) => id = v as i64,
+            (2, value) => value.unpack(&mut keys)?,
+            (3, value) => value.unpack(&mut vals)?,
+            (8, value) => value.unpack(&mut roles)?,
+            (9, value) => value.unpack(&mut members)?,
+            (10, value) => value.unpack(&mut types)?,
+            _ => {}
+        }
+    }
+    let mut relation = element("relation", id);
+    relation.tags = block.tags(&keys, &vals)?;
+    relation.refs = deltas(&members);
+    relation.ref_roles = roles.iter().map(|&r| block.string(r)).collect::<Result<_>>()?;
+    relation.ref_types = types
+        .iter()
+        .map(|t| match t {
+            0 => Ok("node".to_string()),
+            1 => Ok("way".to_string()),
+            2 => Ok("relation".to_string()),
+            other => Err(anyhow!("Unknown member type {}", other)),
+        })
+        .collect::<Result<_>>()?;
+    if relation.ref_roles.len() != relation.refs.len() || relation.ref_types.len() != relation.refs.len() {
+        bail!("Relation {} has mismatched member lists", id);
+    }
+    Ok(relation)
+}
+
+/// Decodes delta-coded, zigzag-encoded ids.
+fn deltas(values: &[u64]) -> Vec<i64> {
+    let mut current = 0;
+    values
+        .iter()
+        .map(|&v| {
+            current += zigzag(v);
+            current
+        })
+        .collect()
+}
+
+fn zigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// A protobuf field value; only the wire types used by the OSM format.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl Value<'_> {
+    /// Appends a repeated integer field, packed or not.
+    fn unpack(self, out: &mut Vec<u64>) -> Result<()> {
+        match self {
+            Value::Varint(v) => out.push(v),
+            Value::Bytes(mut packed) => {
+                while !packed.is_empty() {
+                    out.push(read_varint(&mut packed)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterates over the `(field number, value)` pairs of a protobuf message.
+struct Message<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// The next field, or `None` for a field of a wire type the format doesn't use.
+    fn read_field(&mut self) -> Result<Option<(u32, Value<'a>)>> {
+        let key = read_varint(&mut self.data)?;
+        let field = (key >> 3) as u32;
+        let value = match key & 7 {
+            0 => Value::Varint(read_varint(&mut self.data)?),
+            2 => {
+                let len = read_varint(&mut self.data)? as usize;
+                if len > self.data.len() {
+                    bail!("Field {} is longer than its message", field);
+                }
+                let (bytes, rest) = self.data.split_at(len);
+                self.data = rest;
+                Value::Bytes(bytes)
+            }
+            wire_type @ (1 | 5) => {
+                let len = if wire_type == 1 { 8 } else { 4 };
+                self.data = self.data.get(len..).ok_or_else(|| anyhow!("Truncated field {}", field))?;
+                return Ok(None);
+            }
+            other => bail!("Unsupported wire type {} in field {}", other, field),
+        };
+        Ok(Some((field, value)))
+    }
+}
+
+impl<'a> Iterator for Message<'a> {
+    type Item = Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.data.is_empty() {
+            match self.read_field() {
+                Ok(Some(field)) => return Some(Ok(field)),
+                Ok(None) => continue,
+                Err(e) => {
+                    // Stop after an error instead of decoding garbage.
+                    self.data = &[];
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Invalid varint"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::replication::parse_osc;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::{Cursor, Write};
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn uint(out: &mut Vec<u8>, field: u32, v: u64) {
+        varint(out, (field as u64) << 3);
+        varint(out, v);
+    }
+
+    fn bytes(out: &mut Vec<u8>, field: u32, data: &[u8]) {
+        varint(out, (field as u64) << 3 | 2);
+        varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    fn packed(out: &mut Vec<u8>, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut data = Vec::new();
+        for v in values {
+            varint(&mut data, v);
+        }
+        bytes(out, field, &data);
+    }
+
+    fn encode_zigzag(v: i64) -> u64 {
+        ((v << 1) ^ (v >> 63)) as u64
+    }
+
+    fn encode_deltas(values: impl IntoIterator<Item = i64>) -> Vec<u64> {
+        let mut last = 0;
+        values
+            .into_iter()
+            .map(|v| {
+                let delta = v - last;
+                last = v;
+                encode_zigzag(delta)
+            })
+            .collect()
+    }
+
+    fn write_blob(out: &mut Vec<u8>, kind: &str, data: &[u8]) {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(data).unwrap();
+        let mut blob = Vec::new();
+        uint(&mut blob, 2, data.len() as u64);
+        bytes(&mut blob, 3, &zlib.finish().unwrap());
+        let mut header = Vec::new();
+        bytes(&mut header, 1, kind.as_bytes());
+        uint(&mut header, 3, blob.len() as u64);
+        out.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&blob);
+    }
+
+    /// Encodes elements as a PBF file with one data block: dense nodes, then ways,
+    /// then relations.
+    pub(crate) fn write_pbf(features: &[&str], elements: &[Element]) -> Vec<u8> {
+        let mut strings = vec![String::new()];
+        let mut sid = |s: &str| {
+            let index = strings.iter().position(|x| x == s).unwrap_or_else(|| {
+                strings.push(s.to_string());
+                strings.len() - 1
+            });
+            index as u64
+        };
+        let of_kind = |kind: &'static str| elements.iter().filter(move |e| e.kind == kind);
+
+        let mut dense = Vec::new();
+        packed(&mut dense, 1, encode_deltas(of_kind("node").map(|n| n.id)));
+        let coordinate = |v: Option<f64>| (v.unwrap() * 1e7).round() as i64;
+        packed(&mut dense, 8, encode_deltas(of_kind("node").map(|n| coordinate(n.lat))));
+        packed(&mut dense, 9, encode_deltas(of_kind("node").map(|n| coordinate(n.lon))));
+        let mut keys_vals = Vec::new();
+        for node in of_kind("node") {
+            for (k, v) in &node.tags {
+                keys_vals.extend([sid(k), sid(v)]);
+            }
+            keys_vals.push(0);
+        }
+        packed(&mut dense, 10, keys_vals);
+        let mut nodes = Vec::new();
+        bytes(&mut nodes, 2, &dense);
+
+        let mut ways = Vec::new();
+        for way in of_kind("way") {
+            let mut w = Vec::new();
+            uint(&mut w, 1, way.id as u64);
+            packed(&mut w, 2, way.tags.iter().map(|(k, _)| sid(k)).collect::<Vec<_>>());
+            packed(&mut w, 3, way.tags.iter().map(|(_, v)| sid(v)).collect::<Vec<_>>());
+            packed(&mut w, 8, encode_deltas(way.refs.iter().copied()));
+            bytes(&mut ways, 3, &w);
+        }
+
+        let mut relations = Vec::new();
+        for relation in of_kind("relation") {
+            let mut r = Vec::new();
+            uint(&mut r, 1, relation.id as u64);
+            packed(&mut r, 2, relation.tags.iter().map(|(k, _)| sid(k)).collect::<Vec<_>>());
+            packed(&mut r, 3, relation.tags.iter().map(|(_, v)| sid(v)).collect::<Vec<_>>());
+            packed(&mut r, 8, relation.ref_roles.iter().map(|role| sid(role)).collect::<Vec<_>>());
+            packed(&mut r, 9, encode_deltas(relation.refs.iter().copied()));
+            let types = relation.ref_types.iter().map(|t| match t.as_str() {
+                "node" => 0,
+                "way" => 1,
+                _ => 2,
+            });
+            packed(&mut r, 10, types);
+            bytes(&mut relations, 4, &r);
+        }
+
+        let mut table = Vec::new();
+        for s in &strings {
+            bytes(&mut table, 1, s.as_bytes());
+        }
+        let mut block = Vec::new();
+        bytes(&mut block, 1, &table);
+        for group in [nodes, ways, relations] {
+            bytes(&mut block, 2, &group);
+        }
+
+        let mut header = Vec::new();
+        for feature in features {
+            bytes(&mut header, 4, feature.as_bytes());
+        }
+        let mut out = Vec::new();
+        write_blob(&mut out, "OSMHeader", &header);
+        write_blob(&mut out, "OSMData", &block);
+        out
+    }
+
+    /// Elements written as an osmChange, which is the easiest way to spell them out.
+    pub(crate) fn elements(xml: &str) -> Vec<Element> {
+        parse_osc(&format!("<osmChange><create>{}</create></osmChange>", xml))
+            .unwrap()
+            .into_iter()
+            .map(|change| change.element)
+            .collect()
+    }
+
+    #[test]
+    fn test_read_dense_nodes_ways_and_relations() {
+        let written = elements(
+            r#"<node id="1" lat="43.7384" lon="7.4246"><tag k="name" v="Monaco"/></node>
+               <node id="5" lat="-33.8688" lon="-151.2093"/>
+               <node id="3" lat="0" lon="0"><tag k="amenity" v="cafe"/><tag k="name" v="Chez l'Ami"/></node>
+               <way id="10"><nd ref="1"/><nd ref="5"/><nd ref="3"/><nd ref="1"/><tag k="building" v="yes"/></way>
+               <way id="2"/>
+               <relation id="20"><member type="way" ref="10" role="outer"/>
+                 <member type="node" ref="3" role=""/><tag k="type" v="multipolygon"/></relation>"#,
+        );
+        let file = write_pbf(&SUPPORTED_FEATURES, &written);
+        let file_len = file.len() as u64;
+        let mut reader = PbfReader::new(Cursor::new(file));
+        let read = reader.next_block().unwrap().unwrap();
+        assert_eq!(read, written);
+        assert_eq!(reader.position(), file_len);
+        assert!(reader.next_block().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reject_unsupported_and_truncated_files() {
+        let written = elements(r#"<node id="1" lat="1" lon="2"/>"#);
+        let file = write_pbf(&["OsmSchema-V0.6", "HistoricalInformation"], &written);
+        let error = PbfReader::new(Cursor::new(file)).next_block().unwrap_err();
+        assert_eq!(error.to_string(), "PBF file requires unsupported feature 'HistoricalInformation'");
+
+        let mut file = write_pbf(&SUPPORTED_FEATURES, &written);
+        file.truncate(file.len() - 3);
+        assert!(PbfReader::new(Cursor::new(file)).next_block().is_err());
+    }
+
+    #[test]
+    fn test_reject_blobs_larger_than_declared() {
+        let blob = |raw_size: usize, data: &[u8]| {
+            let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+            zlib.write_all(data).unwrap();
+            let mut blob = Vec::new();
+            uint(&mut blob, 2, raw_size as u64);
+            bytes(&mut blob, 3, &zlib.finish().unwrap());
+            blob
+        };
+        assert_eq!(decompress(&blob(5, b"hello")).unwrap(), b"hello");
+        assert!(decompress(&blob(5, &[0; 1000])).is_err());
+        let error = decompress(&blob(100, &vec![0; MAX_BLOB_SIZE + 1000])).unwrap_err();
+        assert_eq!(error.to_string(), format!("Blob inflates to more than {} bytes", MAX_BLOB_SIZE));
+    }
+
+    #[test]
+    fn test_read_replication_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("monaco-latest.osm.pbf");
+        let mut header = Vec::new();
+        bytes(&mut header, 4, b"OsmSchema-V0.6");
+        bytes(&mut header, 16, b"osmium/1.16.0");
+        uint(&mut header, 32, 1_714_594_862);
+        uint(&mut header, 33, 4321);
+        let mut file = Vec::new();
+        write_blob(&mut file, "OSMHeader", &header);
+        std::fs::write(&path, &file).unwrap();
+        assert_eq!(
+            read_header(&path).unwrap(),
+            PbfHeader { replication_timestamp: Some(1_714_594_862), replication_sequence: Some(4321) }
+        );
+
+        // Files written without replication details still have a header
+        std::fs::write(&path, write_pbf(&SUPPORTED_FEATURES, &[])).unwrap();
+        assert_eq!(read_header(&path).unwrap(), PbfHeader::default());
+        std::fs::write(&path, b"not a pbf").unwrap();
+        assert!(read_header(&path).is_err());
+    }
+}
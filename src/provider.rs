@@ -0,0 +1,302 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::catalog::Catalog;
+use crate::network::{DownloadFormat, Downloader};
+
+pub const GEOFABRIK_URL: &str = "https://download.geofabrik.de";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// download.geofabrik.de or a mirror of it, catalogued by `index-v1.json`.
+    Geofabrik,
+    /// BBBike city extracts (`<url>/<City>/<City>.osm.pbf`), catalogued by the directory listing.
+    Bbbike,
+    /// A local directory (path or `file://` URL) laid out like the Geofabrik server.
+    Directory,
+}
+
+/// A source of extracts. The first provider whose catalog loads is the one that
+/// is browsed; downloads fall back to the other providers sharing its layout.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Provider {
+    pub kind: ProviderKind,
+    pub url: String,
+}
+
+impl Provider {
+    pub fn geofabrik(url: &str) -> Self {
+        Self {
+            kind: ProviderKind::Geofabrik,
+            url: url.to_string(),
+        }
+    }
+
+    /// Base URL without a trailing slash; directories are turned into `file://` URLs.
+    pub fn base_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        match self.kind {
+            ProviderKind::Directory if !url.starts_with("file://") => format!("file://{}", url),
+            _ => url.to_string(),
+        }
+    }
+
+    fn root(&self) -> PathBuf {
+        PathBuf::from(self.url.strip_prefix("file://").unwrap_or(&self.url))
+    }
+
+    /// Providers with the same layout serve the same files under the same relative paths.
+    fn layout(&self) -> ProviderKind {
+        match self.kind {
+            ProviderKind::Directory => ProviderKind::Geofabrik,
+            kind => kind,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self.kind {
+            ProviderKind::Directory => {
+                if !self.root().is_absolute() {
+                    bail!("directory provider needs an absolute path or file:// URL, got '{}'", self.url);
+                }
+            }
+            _ => match reqwest::Url::parse(&self.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => bail!("{:?} provider needs an http(s) URL, got '{}'", self.kind, self.url),
+            },
+        }
+        Ok(())
+    }
+
+    /// Guesses the URL of an extract from free-text parts, for when no catalog is
+    /// available. BBBike only has cities, so the most specific part is used.
+    pub fn construct_url(
+        &self,
+        continent: &str,
+        country: &str,
+        region: &str,
+        format: &DownloadFormat,
+    ) -> String {
+        let base = self.base_url();
+
+        // Normalize inputs (basic trim and lowercase)
+        let continent = continent.trim().to_lowercase();
+        let country = country.trim().to_lowercase();
+        let region = region.trim().to_lowercase();
+
+        if self.layout() == ProviderKind::Bbbike {
+            let place = [&region, &country, &continent]
+                .into_iter()
+                .find(|s| !s.is_empty())
+                .map_or("", |s| s.as_str());
+            let city: String = place
+                .split_whitespace()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    chars
+                        .next()
+                        .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let ext = match format {
+                DownloadFormat::Pbf => ".osm.pbf",
+                DownloadFormat::Shapefile => ".osm.shp.zip",
+            };
+            return format!("{}/{}/{}{}", base, city, city, ext);
+        }
+
+        let suffix = format.suffix();
+        if region.is_empty() {
+            if country.is_empty() {
+                format!("{}/{}{}", base, continent, suffix)
+            } else {
+                format!("{}/{}/{}{}", base, continent, country, suffix)
+            }
+        } else {
+             format!("{}/{}/{}/{}{}", base, continent, country, region, suffix)
+        }
+    }
+
+    /// The part of `url` below this provider's base URL, if it belongs to it.
+    fn relative_path<'a>(&self, url: &'a str) -> Option<&'a str> {
+        url.strip_prefix(&self.base_url())?.strip_prefix('/')
+    }
+
+    /// Loads this provider's catalog, cached in `cache_dir` for remote providers.
+    pub async fn load_catalog(&self, downloader: &Downloader, cache_dir: &Path) -> Result<Catalog> {
+        let base = self.base_url();
+        let slug: String = base
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        match self.kind {
+            ProviderKind::Geofabrik => {
+                let cache = cache_dir.join(format!("index-{}.json", slug));
+                let index_url = format!("{}/index-v1.json", base);
+                let mut catalog = Catalog::load_or_fetch(downloader, &index_url, &cache, Catalog::from_json).await?;
+                // Mirrors usually serve Geofabrik's index unchanged.
+                catalog.rebase(GEOFABRIK_URL, &base);
+                Ok(catalog)
+            }
+            ProviderKind::Bbbike => {
+                let cache = cache_dir.join(format!("index-{}.html", slug));
+                let listing_url = format!("{}/", base);
+                Catalog::load_or_fetch(downloader, &listing_url, &cache, |html| {
+                    Catalog::from_bbbike_listing(html, &base)
+                })
+                .await
+            }
+            ProviderKind::Directory => Catalog::from_directory(&self.root(), &base),
+        }
+    }
+}
+
+/// Loads the catalog of the first provider that can deliver one.
+pub async fn load_catalog(providers: &[Provider], downloader: &Downloader, cache_dir: &Path) -> Result<Catalog> {
+    let mut errors = Vec::new();
+    for provider in providers {
+        match provider.load_catalog(downloader, cache_dir).await {
+            Ok(catalog) => return Ok(catalog),
+            Err(e) => {
+                warn!("Region index from {} unavailable: {:#}", provider.url, e);
+                errors.push(format!("{}: {:#}", provider.url, e));
+            }
+        }
+    }
+    Err(anyhow!("No region index available ({})", errors.join("; ")))
+}
+
+/// `url` followed by the same file on every other provider with the same layout,
+/// in configuration order. These are tried in turn when a download fails.
+pub fn mirror_urls(providers: &[Provider], url: &str) -> Vec<String> {
+    let mut urls = vec![url.to_string()];
+    let Some((owner, path)) = providers
+        .iter()
+        .find_map(|p| p.relative_path(url).map(|path| (p, path)))
+    else {
+        return urls;
+    };
+    for provider in providers.iter().filter(|p| p.layout() == owner.layout()) {
+        let mirrored = format!("{}/{}", provider.base_url(), path);
+        if !urls.contains(&mirrored) {
+            urls.push(mirrored);
+        }
+    }
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn provider(kind: ProviderKind, url: &str) -> Provider {
+        Provider {
+            kind,
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_construct_url() {
+        let geofabrik = Provider::geofabrik(GEOFABRIK_URL);
+
+        // Case 1: Continent/Country/Region
+        let url = geofabrik.construct_url("Asia", "Indonesia", "Kalimantan", &DownloadFormat::Pbf);
+        assert_eq!(url, "https://download.geofabrik.de/asia/indonesia/kalimantan-latest.osm.pbf");
+
+        // Case 2: Continent/Country
+        let url = geofabrik.construct_url("Europe", "Germany", "", &DownloadFormat::Shapefile);
+        assert_eq!(url, "https://download.geofabrik.de/europe/germany-latest-free.shp.zip");
+
+        // Case 3: Continent only
+        let url = geofabrik.construct_url("Africa", "", "", &DownloadFormat::Pbf);
+        assert_eq!(url, "https://download.geofabrik.de/africa-latest.osm.pbf");
+
+        // Case 4: Trimming and Lowercase
+        let url = geofabrik.construct_url(" Asia ", " Indonesia ", " Kalimantan ", &DownloadFormat::Pbf);
+        assert_eq!(url, "https://download.geofabrik.de/asia/indonesia/kalimantan-latest.osm.pbf");
+
+        // Case 5: Other layouts
+        let local = provider(ProviderKind::Directory, "/srv/osm/");
+        let url = local.construct_url("Africa", "", "", &DownloadFormat::Pbf);
+        assert_eq!(url, "file:///srv/osm/africa-latest.osm.pbf");
+        let bbbike = provider(ProviderKind::Bbbike, "https://download.bbbike.org/osm/bbbike");
+        let url = bbbike.construct_url("North America", "", "San Francisco", &DownloadFormat::Shapefile);
+        assert_eq!(url, "https://download.bbbike.org/osm/bbbike/SanFrancisco/SanFrancisco.osm.shp.zip");
+    }
+
+    #[test]
+    fn test_mirror_urls_follow_config_order() {
+        let providers = vec![
+            provider(ProviderKind::Geofabrik, "https://osm.internal.example/geofabrik/"),
+            Provider::geofabrik(GEOFABRIK_URL),
+            provider(ProviderKind::Bbbike, "https://download.bbbike.org/osm/bbbike"),
+            provider(ProviderKind::Directory, "file:///srv/osm"),
+        ];
+        let urls = mirror_urls(&providers, "https://download.geofabrik.de/asia/indonesia-latest.osm.pbf");
+        assert_eq!(
+            urls,
+            vec![
+                "https://download.geofabrik.de/asia/indonesia-latest.osm.pbf",
+                "https://osm.internal.example/geofabrik/asia/indonesia-latest.osm.pbf",
+                "file:///srv/osm/asia/indonesia-latest.osm.pbf",
+            ]
+        );
+        // URLs no provider knows about are downloaded as they are.
+        assert_eq!(mirror_urls(&providers, "http://other/x.osm.pbf"), vec!["http://other/x.osm.pbf"]);
+    }
+
+    #[tokio::test]
+    async fn test_directory_catalog_and_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "europe-latest.osm.pbf",
+            "europe/georgia-latest.osm.pbf",
+            "europe/georgia-latest-free.shp.zip",
+            "north-america/us/georgia-latest.osm.pbf",
+            "README.txt",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let providers = vec![
+            // Nothing listens on port 9, so the catalog comes from the directory.
+            Provider::geofabrik("http://127.0.0.1:9"),
+            provider(ProviderKind::Directory, dir.path().to_str().unwrap()),
+        ];
+        let cache = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new(&Config::default());
+        let catalog = load_catalog(&providers, &downloader, cache.path()).await.unwrap();
+
+        assert_eq!(catalog.region_count(), 3);
+        let georgia = catalog.resolve("Europe/Georgia").unwrap();
+        assert_eq!(georgia.id, "europe/georgia");
+        assert_eq!(
+            georgia.url(&DownloadFormat::Shapefile).unwrap(),
+            format!("file://{}/europe/georgia-latest-free.shp.zip", dir.path().display())
+        );
+        assert_eq!(catalog.get("us/georgia").unwrap().parent.as_deref(), Some("us"));
+    }
+
+    #[test]
+    fn test_bbbike_listing() {
+        let html = r#"<a href="../">..</a> <a href="Berlin/">Berlin/</a>
+            <a href="SanFrancisco/">SanFrancisco/</a> <a href="README.txt">README</a>"#;
+        let catalog = Catalog::from_bbbike_listing(html, "https://bbbike.example").unwrap();
+        assert_eq!(
+            catalog.resolve("sanfrancisco").unwrap().url(&DownloadFormat::Pbf),
+            Some("https://bbbike.example/SanFrancisco/SanFrancisco.osm.pbf")
+        );
+        assert_eq!(catalog.region_count(), 2);
+    }
+}
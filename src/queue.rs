@@ -0,0 +1,308 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::db::ImportProgress;
+use crate::network::{DownloadFormat, DownloadProgress};
+
+pub type JobId = u64;
+
+pub const DEFAULT_MAX_CONCURRENT: usize = 2;
+/// Upper bound for the concurrency limit, from the config or the `+` key.
+pub const MAX_CONCURRENT: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    Queued,
+    /// Held back by the user; won't be started until resumed.
+    Paused,
+    Active,
+    Verifying,
+    /// Downloaded and being imported into DuckDB.
+    Importing,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed(_) | JobState::Cancelled)
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Paused => "paused",
+            JobState::Active => "downloading",
+            JobState::Verifying => "verifying",
+            JobState::Importing => "importing",
+            JobState::Completed => "done",
+            JobState::Failed(_) => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub name: String,
+    pub url: String,
+    pub format: DownloadFormat,
+    pub state: JobState,
+    pub progress: DownloadProgress,
+    pub path: Option<PathBuf>,
+    /// Progress of the import that follows the download, while it runs.
+    pub import: Option<ImportProgress>,
+    /// When the current transfer was started.
+    pub started: Option<Instant>,
+    /// Table a refresh of a tracked region replaces; other jobs import into one
+    /// named after their region.
+    pub table: Option<String>,
+}
+
+impl Job {
+    /// How long the current transfer has been running.
+    pub fn elapsed(&self) -> Duration {
+        self.started.map_or(Duration::ZERO, |started| started.elapsed())
+    }
+}
+
+/// Download jobs in the order they will be started. At most `max_concurrent`
+/// jobs are active at once; the rest wait as `Queued` (or `Paused`).
+pub struct DownloadQueue {
+    jobs: Vec<Job>,
+    next_id: JobId,
+    pub max_concurrent: usize,
+    /// Cursor in the queue panel.
+    pub selected: usize,
+}
+
+impl Default for DownloadQueue {
+    fn default() -> Self {
+        Self {
+            jobs: Vec::new(),
+            next_id: 1,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            selected: 0,
+        }
+    }
+}
+
+impl DownloadQueue {
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn get_mut(&mut self, id: JobId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    pub fn selected_job(&self) -> Option<&Job> {
+        self.jobs.get(self.selected)
+    }
+
+    /// Adds a job to the end of the queue. The same URL can't be queued twice while
+    /// unfinished, since both jobs would write to the same `.part` file.
+    pub fn push(&mut self, name: String, url: String, format: DownloadFormat) -> Result<JobId, String> {
+        if self.jobs.iter().any(|j| j.url == url && !j.state.is_finished()) {
+            return Err(format!("{} is already queued", name));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.push(Job {
+            id,
+            name,
+            url,
+            format,
+            state: JobState::Queued,
+            progress: DownloadProgress::default(),
+            path: None,
+            import: None,
+            started: None,
+            table: None,
+        });
+        Ok(id)
+    }
+
+    /// Whether a job for `url` is still to finish or was started less than
+    /// `within` ago, so a refresh of it would be premature.
+    pub fn has_recent(&self, url: &str, within: Duration) -> bool {
+        self.jobs.iter().filter(|j| j.url == url).any(|j| {
+            !j.state.is_finished() || j.started.is_some_and(|started| started.elapsed() < within)
+        })
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.jobs
+            .iter()
+            .filter(|j| matches!(j.state, JobState::Active | JobState::Verifying))
+            .count()
+    }
+
+    /// Marks as many queued jobs as the concurrency limit allows as active, in queue
+    /// order, and returns them so the caller can start the transfers.
+    pub fn start_ready(&mut self) -> Vec<Job> {
+        let mut free = self.max_concurrent.saturating_sub(self.active_count());
+        let mut started = Vec::new();
+        for job in &mut self.jobs {
+            if free == 0 {
+                break;
+            }
+            if job.state == JobState::Queued {
+                job.state = JobState::Active;
+                job.progress = DownloadProgress::default();
+                job.started = Some(Instant::now());
+                started.push(job.clone());
+                free -= 1;
+            }
+        }
+        started
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.jobs.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    /// Moves the selected job one place earlier (`-1`) or later (`1`) in the queue.
+    pub fn move_selected(&mut self, offset: isize) {
+        let target = self.selected as isize + offset;
+        if target < 0 || target as usize >= self.jobs.len() {
+            return;
+        }
+        self.jobs.swap(self.selected, target as usize);
+        self.selected = target as usize;
+    }
+
+    /// Holds a queued job back, or releases a paused one.
+    pub fn toggle_pause_selected(&mut self) {
+        if let Some(job) = self.jobs.get_mut(self.selected) {
+            job.state = match job.state {
+                JobState::Queued => JobState::Paused,
+                JobState::Paused => JobState::Queued,
+                ref other => other.clone(),
+            };
+        }
+    }
+
+    /// Cancels the selected job if it hasn't started yet.
+    pub fn cancel_selected(&mut self) -> bool {
+        match self.jobs.get_mut(self.selected) {
+            Some(job) if matches!(job.state, JobState::Queued | JobState::Paused) => {
+                job.state = JobState::Cancelled;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Puts a failed or cancelled job back in the queue.
+    pub fn retry_selected(&mut self) -> bool {
+        match self.jobs.get_mut(self.selected) {
+            Some(job) if matches!(job.state, JobState::Failed(_) | JobState::Cancelled) => {
+                job.state = JobState::Queued;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_max_concurrent(&mut self, max: usize) {
+        self.max_concurrent = max.clamp(1, MAX_CONCURRENT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_with(n: usize) -> DownloadQueue {
+        let mut queue = DownloadQueue::default();
+        for i in 0..n {
+            queue
+                .push(format!("r{}", i), format!("http://x/r{}.osm.pbf", i), DownloadFormat::Pbf)
+                .unwrap();
+        }
+        queue
+    }
+
+    fn states(queue: &DownloadQueue) -> Vec<&str> {
+        queue.jobs().iter().map(|j| j.state.label()).collect()
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let mut queue = queue_with(3);
+        let started: Vec<JobId> = queue.start_ready().iter().map(|j| j.id).collect();
+        assert_eq!(started, vec![1, 2]);
+        assert!(queue.start_ready().is_empty());
+
+        queue.get_mut(1).unwrap().state = JobState::Completed;
+        let started: Vec<JobId> = queue.start_ready().iter().map(|j| j.id).collect();
+        assert_eq!(started, vec![3]);
+
+        queue.set_max_concurrent(0);
+        assert_eq!(queue.max_concurrent, 1);
+        queue.set_max_concurrent(MAX_CONCURRENT + 1);
+        assert_eq!(queue.max_concurrent, MAX_CONCURRENT);
+    }
+
+    #[test]
+    fn test_reorder_pause_cancel_retry() {
+        let mut queue = queue_with(3);
+        queue.set_max_concurrent(1);
+
+        // Move the last job to the front and hold the next one back.
+        queue.selected = 2;
+        queue.move_selected(-1);
+        queue.move_selected(-1);
+        assert_eq!(queue.jobs()[0].id, 3);
+        queue.select_next();
+        queue.toggle_pause_selected();
+        assert_eq!(states(&queue), vec!["queued", "paused", "queued"]);
+
+        assert_eq!(queue.start_ready()[0].id, 3);
+        queue.get_mut(3).unwrap().state = JobState::Failed("boom".to_string());
+        assert_eq!(queue.start_ready()[0].id, 2);
+
+        // Active jobs can't be cancelled from the queue, waiting ones can.
+        queue.selected = 2;
+        assert!(!queue.cancel_selected());
+        queue.selected = 1;
+        assert!(queue.cancel_selected());
+        queue.selected = 0;
+        assert!(queue.retry_selected());
+        assert_eq!(states(&queue), vec!["queued", "cancelled", "downloading"]);
+    }
+
+    #[test]
+    fn test_rejects_duplicate_unfinished_url() {
+        let mut queue = queue_with(1);
+        assert!(queue
+            .push("again".to_string(), "http://x/r0.osm.pbf".to_string(), DownloadFormat::Pbf)
+            .is_err());
+        queue.get_mut(1).unwrap().state = JobState::Completed;
+        assert!(queue
+            .push("again".to_string(), "http://x/r0.osm.pbf".to_string(), DownloadFormat::Pbf)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_recent_jobs_hold_off_refreshes() {
+        let mut queue = queue_with(1);
+        let hour = Duration::from_secs(3600);
+        assert!(queue.has_recent("http://x/r0.osm.pbf", hour), "queued");
+        assert!(!queue.has_recent("http://x/r1.osm.pbf", hour));
+
+        queue.start_ready();
+        queue.get_mut(1).unwrap().state = JobState::Failed("boom".to_string());
+        assert!(queue.has_recent("http://x/r0.osm.pbf", hour));
+        assert!(!queue.has_recent("http://x/r0.osm.pbf", Duration::ZERO));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+
+use crate::catalog::{Catalog, Region};
+
+/// One visible line of the region browser.
+pub struct TreeRow<'a> {
+    pub region: &'a Region,
+    pub depth: usize,
+    pub has_children: bool,
+    pub expanded: bool,
+    /// Whether the region itself matches the filter (rather than just a descendant).
+    pub matched: bool,
+}
+
+/// State of the continent → country → subregion browser in the Download tab.
+/// Rows are derived from the catalog on demand, so only the expanded ids, the
+/// filter and the cursor position are stored.
+#[derive(Default)]
+pub struct RegionTree {
+    pub filter: String,
+    pub selected: usize,
+    expanded: HashSet<String>,
+}
+
+impl RegionTree {
+    /// Visible rows in display order. While a filter is active every region that
+    /// matches it is shown together with its ancestors, all expanded.
+    pub fn rows<'a>(&self, catalog: &'a Catalog) -> Vec<TreeRow<'a>> {
+        let matches = self.matching_ids(catalog);
+        let mut rows = Vec::new();
+        self.push_children(catalog, None, 0, matches.as_ref(), &mut rows);
+        rows
+    }
+
+    fn push_children<'a>(
+        &self,
+        catalog: &'a Catalog,
+        parent: Option<&str>,
+        depth: usize,
+        matches: Option<&(HashSet<&'a str>, HashSet<&'a str>)>,
+        rows: &mut Vec<TreeRow<'a>>,
+    ) {
+        for region in catalog.children(parent) {
+            let (visible, matched) = match matches {
+                Some((visible, matched)) => (
+                    visible.contains(region.id.as_str()),
+                    matched.contains(region.id.as_str()),
+                ),
+                None => (true, false),
+            };
+            if !visible {
+                continue;
+            }
+            let has_children = catalog.has_children(&region.id);
+            let expanded = has_children && (matches.is_some() || self.expanded.contains(&region.id));
+            rows.push(TreeRow {
+                region,
+                depth,
+                has_children,
+                expanded,
+                matched,
+            });
+            if expanded {
+                self.push_children(catalog, Some(&region.id), depth + 1, matches, rows);
+            }
+        }
+    }
+
+    /// `(visible, matched)` ids for the current filter, or `None` without a filter.
+    fn matching_ids<'a>(&self, catalog: &'a Catalog) -> Option<(HashSet<&'a str>, HashSet<&'a str>)> {
+        if self.filter.trim().is_empty() {
+            return None;
+        }
+        let mut visible = HashSet::new();
+        let mut matched = HashSet::new();
+        for region in catalog.regions() {
+            if !fuzzy_match(&self.filter, &region.name) && !fuzzy_match(&self.filter, &region.id) {
+                continue;
+            }
+            matched.insert(region.id.as_str());
+            let mut current = Some(region);
+            while let Some(r) = current {
+                if !visible.insert(r.id.as_str()) {
+                    break;
+                }
+                current = r.parent.as_deref().and_then(|p| catalog.get(p));
+            }
+        }
+        Some((visible, matched))
+    }
+
+    pub fn selected_region<'a>(&self, catalog: &'a Catalog) -> Option<&'a Region> {
+        self.rows(catalog).get(self.selected).map(|row| row.region)
+    }
+
+    pub fn move_up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn move_down(&mut self, catalog: &Catalog) {
+        let len = self.rows(catalog).len();
+        if self.selected + 1 < len {
+            self.selected += 1;
+        }
+    }
+
+    pub fn expand(&mut self, catalog: &Catalog) {
+        if let Some(region) = self.selected_region(catalog) {
+            if catalog.has_children(&region.id) {
+                self.expanded.insert(region.id.clone());
+            }
+        }
+    }
+
+    /// Collapses the selected node, or jumps to its parent if it is already collapsed.
+    pub fn collapse(&mut self, catalog: &Catalog) {
+        let rows = self.rows(catalog);
+        let Some(row) = rows.get(self.selected) else {
+            return;
+        };
+        if row.expanded && self.filter.is_empty() {
+            self.expanded.remove(&row.region.id);
+        } else if let Some(parent) = &row.region.parent {
+            if let Some(index) = rows.iter().position(|r| &r.region.id == parent) {
+                self.selected = index;
+            }
+        }
+    }
+
+    pub fn push_filter(&mut self, c: char, catalog: &Catalog) {
+        self.filter.push(c);
+        self.select_first_match(catalog);
+    }
+
+    pub fn pop_filter(&mut self, catalog: &Catalog) {
+        self.filter.pop();
+        self.select_first_match(catalog);
+    }
+
+    fn select_first_match(&mut self, catalog: &Catalog) {
+        self.selected = self
+            .rows(catalog)
+            .iter()
+            .position(|row| row.matched)
+            .unwrap_or(0);
+    }
+}
+
+/// Case-insensitive subsequence match, so `nrw` finds `Nordrhein-Westfalen`.
+pub fn fuzzy_match(pattern: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    pattern
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .all(|p| text.any(|t| t == p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::tests::INDEX;
+
+    fn ids(tree: &RegionTree, catalog: &Catalog) -> Vec<String> {
+        tree.rows(catalog)
+            .iter()
+            .map(|r| format!("{}{}", "  ".repeat(r.depth), r.region.id))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_and_collapse() {
+        let catalog = Catalog::from_json(INDEX).unwrap();
+        let mut tree = RegionTree::default();
+        assert_eq!(ids(&tree, &catalog), vec!["asia", "europe", "north-america"]);
+
+        tree.expand(&catalog);
+        tree.move_down(&catalog);
+        tree.expand(&catalog);
+        assert_eq!(
+            ids(&tree, &catalog),
+            vec!["asia", "  indonesia", "    kalimantan", "europe", "north-america"]
+        );
+
+        // Left on a leaf jumps to its parent, then collapses it.
+        tree.move_down(&catalog);
+        tree.collapse(&catalog);
+        assert_eq!(tree.selected_region(&catalog).unwrap().id, "indonesia");
+        tree.collapse(&catalog);
+        assert_eq!(ids(&tree, &catalog), vec!["asia", "  indonesia", "europe", "north-america"]);
+    }
+
+    #[test]
+    fn test_filter_shows_matches_with_ancestors() {
+        let catalog = Catalog::from_json(INDEX).unwrap();
+        let mut tree = RegionTree::default();
+        for c in "kali".chars() {
+            tree.push_filter(c, &catalog);
+        }
+        assert_eq!(ids(&tree, &catalog), vec!["asia", "  indonesia", "    kalimantan"]);
+        assert_eq!(tree.selected_region(&catalog).unwrap().id, "kalimantan");
+
+        tree.filter.clear();
+        tree.push_filter('g', &catalog);
+        tree.push_filter('e', &catalog);
+        tree.push_filter('o', &catalog);
+        assert_eq!(
+            ids(&tree, &catalog),
+            vec!["europe", "  georgia", "north-america", "  us", "    us/georgia"]
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert!(fuzzy_match("nrw", "Nordrhein-Westfalen"));
+        assert!(fuzzy_match("United St", "United States of America"));
+        assert!(!fuzzy_match("xyz", "Indonesia"));
+    }
+}
@@ -0,0 +1,453 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::Read;
+use tracing::info;
+
+use crate::db::{Database, DownloadRecord};
+use crate::network::Downloader;
+
+/// Position in a replication stream, as published in `state.txt`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationState {
+    pub sequence: u64,
+    pub timestamp: Option<String>,
+}
+
+impl ReplicationState {
+    /// Parses the Java-properties style `state.txt` (`sequenceNumber=4321`,
+    /// `timestamp=2024-05-01T20\:21\:02Z`).
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut sequence = None;
+        let mut timestamp = None;
+        for line in text.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                match key.trim() {
+                    "sequenceNumber" => sequence = value.trim().parse().ok(),
+                    "timestamp" => timestamp = Some(value.trim().replace("\\:", ":")),
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            sequence: sequence.ok_or_else(|| anyhow!("state.txt has no sequenceNumber"))?,
+            timestamp,
+        })
+    }
+
+    /// The position written into the PBF header of a downloaded extract, if any.
+    pub fn from_header(record: &DownloadRecord) -> Option<Self> {
+        let timestamp = record
+            .osm_timestamp
+            .and_then(|seconds| chrono::DateTime::from_timestamp(seconds, 0))
+            .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        Some(Self { sequence: record.replication_sequence? as u64, timestamp })
+    }
+}
+
+/// The replication directory Geofabrik publishes next to a region's PBF extract
+/// (`europe/monaco-latest.osm.pbf` → `europe/monaco-updates`).
+pub fn updates_url(extract_url: &str) -> Option<String> {
+    extract_url
+        .strip_suffix("-latest.osm.pbf")
+        .map(|base| format!("{}-updates", base))
+}
+
+/// URL of the change file for `sequence`, e.g. `000/004/321.osc.gz` for 4321.
+pub fn change_url(updates_url: &str, sequence: u64) -> String {
+    format!(
+        "{}/{:03}/{:03}/{:03}.osc.gz",
+        updates_url,
+        sequence / 1_000_000,
+        sequence / 1_000 % 1_000,
+        sequence % 1_000
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// A node, way or relation, as read from a PBF file or an osmChange diff.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Element {
+    pub kind: String,
+    pub id: i64,
+    pub tags: Vec<(String, String)>,
+    pub refs: Vec<i64>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub ref_roles: Vec<String>,
+    pub ref_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub action: Action,
+    pub element: Element,
+}
+
+/// What an update run did to a table.
+#[derive(Debug, Default, PartialEq)]
+pub struct UpdateSummary {
+    pub from_sequence: u64,
+    pub to_sequence: u64,
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+impl UpdateSummary {
+    fn add(&mut self, changes: &[Change]) {
+        for change in changes {
+            match change.action {
+                Action::Create => self.created += 1,
+                Action::Modify => self.modified += 1,
+                Action::Delete => self.deleted += 1,
+            }
+        }
+    }
+}
+
+/// Decompresses and parses an `.osc.gz` change file.
+pub fn parse_osc_gz(bytes: &[u8]) -> Result<Vec<Change>> {
+    let mut xml = String::new();
+    GzDecoder::new(bytes)
+        .read_to_string(&mut xml)
+        .context("Invalid gzip data in change file")?;
+    parse_osc(&xml)
+}
+
+/// Parses an osmChange document into changes, in document order.
+pub fn parse_osc(xml: &str) -> Result<Vec<Change>> {
+    let mut reader = Reader::from_str(xml);
+    let mut changes = Vec::new();
+    let mut action = None;
+    let mut current: Option<Element> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                if let Some(element) = open_tag(&e, &mut action, &mut current)? {
+                    current = Some(element);
+                }
+            }
+            Event::Empty(e) => {
+                if let Some(element) = open_tag(&e, &mut action, &mut current)? {
+                    let action = action.ok_or_else(|| anyhow!("Element outside create/modify/delete"))?;
+                    changes.push(Change { action, element });
+                }
+            }
+            Event::End(e) => match e.name().as_ref() {
+                b"node" | b"way" | b"relation" => {
+                    if let (Some(action), Some(element)) = (action, current.take()) {
+                        changes.push(Change { action, element });
+                    }
+                }
+                b"create" | b"modify" | b"delete" => action = None,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(changes)
+}
+
+/// Handles an opening tag; returns a new element for `node`/`way`/`relation`.
+fn open_tag(
+    e: &BytesStart,
+    action: &mut Option<Action>,
+    current: &mut Option<Element>,
+) -> Result<Option<Element>> {
+    let attr = |name: &str| -> Result<Option<String>> {
+        match e.try_get_attribute(name)? {
+            Some(a) => Ok(Some(a.unescape_value()?.into_owned())),
+            None => Ok(None),
+        }
+    };
+    let required = |name: &str| -> Result<String> {
+        attr(name)?.ok_or_else(|| anyhow!("Missing '{}' attribute", name))
+    };
+    match e.name().as_ref() {
+        b"create" => *action = Some(Action::Create),
+        b"modify" => *action = Some(Action::Modify),
+        b"delete" => *action = Some(Action::Delete),
+        tag @ (b"node" | b"way" | b"relation") => {
+            let parse_f64 = |v: Option<String>| v.and_then(|v| v.parse::<f64>().ok());
+            return Ok(Some(Element {
+                kind: String::from_utf8_lossy(tag).into_owned(),
+                id: required("id")?.parse()?,
+                lat: parse_f64(attr("lat")?),
+                lon: parse_f64(attr("lon")?),
+                ..Element::default()
+            }));
+        }
+        b"tag" => {
+            if let Some(element) = current {
+                element.tags.push((required("k")?, required("v")?));
+            }
+        }
+        b"nd" => {
+            if let Some(element) = current {
+                element.refs.push(required("ref")?.parse()?);
+            }
+        }
+        b"member" => {
+            if let Some(element) = current {
+                element.refs.push(required("ref")?.parse()?);
+                element.ref_types.push(required("type")?);
+                element.ref_roles.push(attr("role")?.unwrap_or_default());
+            }
+        }
+        _ => {}
+    }
+    Ok(None)
+}
+
+/// Fetches the current `state.txt` of a replication directory.
+pub async fn fetch_state(downloader: &Downloader, updates_url: &str) -> Result<ReplicationState> {
+    let body = downloader
+        .fetch_bytes(&format!("{}/state.txt", updates_url))
+        .await?;
+    ReplicationState::parse(&String::from_utf8_lossy(&body))
+}
+
+/// The replication directory of `extract_url` and the position in it of the file
+/// downloaded as `record`: the sequence in the file's header, or the directory's
+/// current one for files without it. `None` for extracts without updates.
+pub async fn initial_state(
+    downloader: &Downloader,
+    extract_url: &str,
+    record: &DownloadRecord,
+) -> Result<Option<(String, ReplicationState)>> {
+    let Some(updates) = updates_url(extract_url) else {
+        return Ok(None);
+    };
+    let state = match ReplicationState::from_header(record) {
+        Some(state) => state,
+        None => fetch_state(downloader, &updates).await?,
+    };
+    Ok(Some((updates, state)))
+}
+
+/// Starts tracking `table`, imported from the download `record` of `extract_url`,
+/// at the position `initial_state` finds. Does nothing for extracts without updates.
+pub async fn track(
+    db: &Database,
+    downloader: &Downloader,
+    table: &str,
+    extract_url: &str,
+    record: &DownloadRecord,
+) -> Result<()> {
+    let Some((updates, state)) = initial_state(downloader, extract_url, record).await? else {
+        return Ok(());
+    };
+    db.record_replication(table, &updates, &state)?;
+    info!("Tracking {} at replication sequence {}", table, state.sequence);
+    Ok(())
+}
+
+/// Applies every change file published since the PBF import `table` was
+/// imported or last updated. Each file is applied in its own transaction together with the new
+/// sequence number, so an interrupted run continues where it stopped.
+pub async fn update_table(db: &Database, downloader: &Downloader, table: &str) -> Result<UpdateSummary> {
+    let (updates, current) = db
+        .replication_state(table)?
+        .ok_or_else(|| anyhow!("Table {} has no replication state; re-download the extract to track it", table))?;
+    if !db.is_osm_dataset(table)? {
+        bail!("{} is not a PBF import; only those can be updated", table);
+    }
+    let latest = fetch_state(downloader, &updates).await?;
+
+    let mut summary = UpdateSummary {
+        from_sequence: current,
+        to_sequence: current,
+        ..UpdateSummary::default()
+    };
+    for sequence in current + 1..=latest.sequence {
+        let url = change_url(&updates, sequence);
+        info!("Applying {}", url);
+        let bytes = downloader.fetch_bytes(&url).await?;
+        let changes = parse_osc_gz(&bytes).with_context(|| format!("Invalid change file {}", url))?;
+        let state = ReplicationState {
+            sequence,
+            timestamp: (sequence == latest.sequence).then(|| latest.timestamp.clone()).flatten(),
+        };
+        db.apply_changes(table, &changes, &state)
+            .with_context(|| format!("Failed to apply {}", url))?;
+        summary.add(&changes);
+        summary.to_sequence = sequence;
+    }
+    if summary.to_sequence != summary.from_sequence {
+        db.build_layers(table)
+            .with_context(|| format!("Failed to rebuild the layers of {}", table))?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::tests::import_fixture;
+    use crate::db::DownloadOutcome;
+    use crate::network::DownloadFormat;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    const CHANGE_2: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="test">
+  <create>
+    <node id="3" version="1" lat="43.73" lon="7.42">
+      <tag k="amenity" v="cafe"/>
+      <tag k="name" v="Chez l'Ami"/>
+    </node>
+  </create>
+  <modify>
+    <way id="10" version="2">
+      <nd ref="1"/>
+      <nd ref="3"/>
+      <tag k="highway" v="residential"/>
+    </way>
+  </modify>
+  <delete>
+    <node id="2" version="2"/>
+  </delete>
+</osmChange>"#;
+
+    const CHANGE_3: &str = r#"<osmChange version="0.6">
+  <create>
+    <relation id="20" version="1">
+      <member type="way" ref="10" role="outer"/>
+      <tag k="type" v="multipolygon"/>
+    </relation>
+  </create>
+</osmChange>"#;
+
+    fn write_gz(path: &Path, xml: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(xml.as_bytes()).unwrap();
+        std::fs::write(path, encoder.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_state_and_change_urls() {
+        let state = ReplicationState::parse(
+            "# comment\ntimestamp=2024-05-01T20\\:21\\:02Z\nsequenceNumber=4321\n",
+        )
+        .unwrap();
+        assert_eq!(state.sequence, 4321);
+        assert_eq!(state.timestamp.as_deref(), Some("2024-05-01T20:21:02Z"));
+        assert_eq!(change_url("http://x/monaco-updates", 4321), "http://x/monaco-updates/000/004/321.osc.gz");
+        assert_eq!(
+            updates_url("https://download.geofabrik.de/europe/monaco-latest.osm.pbf").as_deref(),
+            Some("https://download.geofabrik.de/europe/monaco-updates")
+        );
+        assert_eq!(updates_url("https://download.geofabrik.de/europe/monaco-latest-free.shp.zip"), None);
+    }
+
+    #[test]
+    fn test_parse_osc() {
+        let changes = parse_osc(CHANGE_2).unwrap();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].action, Action::Create);
+        assert_eq!(changes[0].element.lat, Some(43.73));
+        assert_eq!(changes[0].element.tags[1], ("name".to_string(), "Chez l'Ami".to_string()));
+        assert_eq!(changes[1].element.refs, vec![1, 3]);
+        assert_eq!((changes[2].action, changes[2].element.id), (Action::Delete, 2));
+
+        let relation = &parse_osc(CHANGE_3).unwrap()[0].element;
+        assert_eq!((relation.ref_types[0].as_str(), relation.ref_roles[0].as_str()), ("way", "outer"));
+    }
+
+    #[tokio::test]
+    async fn test_track_starts_at_the_header_sequence() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let updates = fixtures.path().join("monaco-updates");
+        std::fs::create_dir_all(&updates).unwrap();
+        std::fs::write(updates.join("state.txt"), "sequenceNumber=3\n").unwrap();
+        let url = format!("file://{}/monaco-latest.osm.pbf", fixtures.path().display());
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        let downloader = Downloader::new(&Config::default());
+        let mut record = DownloadRecord::unfinished(
+            &url,
+            "europe/monaco",
+            &DownloadFormat::Pbf,
+            Duration::from_secs(1),
+            DownloadOutcome::Success,
+            None,
+        );
+        track(&db, &downloader, "without_header", &url, &record).await.unwrap();
+        record.replication_sequence = Some(2);
+        track(&db, &downloader, "with_header", &url, &record).await.unwrap();
+
+        let updates_url = format!("file://{}", updates.display());
+        assert_eq!(db.replication_state("without_header").unwrap(), Some((updates_url.clone(), 3)));
+        assert_eq!(db.replication_state("with_header").unwrap(), Some((updates_url, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_update_table_from_fixture_directory() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let updates = fixtures.path().join("monaco-updates");
+        write_gz(&updates.join("000/000/002.osc.gz"), CHANGE_2);
+        write_gz(&updates.join("000/000/003.osc.gz"), CHANGE_3);
+        std::fs::write(updates.join("state.txt"), "sequenceNumber=3\ntimestamp=2024-05-03T00\\:00\\:00Z\n").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.duckdb")).unwrap();
+        import_fixture(
+            &db,
+            r#"<node id="1" lat="43.7" lon="7.4"/><node id="2" lat="43.8" lon="7.5"/>
+               <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="track"/></way>"#,
+        );
+        let updates_url = format!("file://{}", updates.display());
+        let start = ReplicationState { sequence: 1, timestamp: None };
+        db.record_replication("osm", &updates_url, &start).unwrap();
+
+        let downloader = Downloader::new(&Config::default());
+        let summary = update_table(&db, &downloader, "osm").await.unwrap();
+        assert_eq!(
+            summary,
+            UpdateSummary { from_sequence: 1, to_sequence: 3, created: 2, modified: 1, deleted: 1 }
+        );
+
+        let ids = db
+            .query(
+                "SELECT string_agg(id::VARCHAR, ',' ORDER BY kind, id) AS ids FROM (
+                    SELECT 'n' AS kind, id FROM osm.nodes UNION ALL
+                    SELECT 'r', id FROM osm.relations UNION ALL
+                    SELECT 'w', id FROM osm.ways)",
+            )
+            .unwrap().to_string();
+        assert!(ids.contains("1,3,20,10"), "{}", ids);
+        let way = db
+            .query(
+                "SELECT string_agg(wn.node_id::VARCHAR, '-' ORDER BY wn.seq) AS nodes, any_value(w.tags['highway']) AS highway
+                 FROM osm.ways w JOIN osm.way_nodes wn ON wn.way_id = w.id WHERE w.id = 10",
+            )
+            .unwrap().to_string();
+        assert!(way.contains("1-3") && way.contains("residential"), "{}", way);
+        let members = db
+            .query("SELECT member_type || member_id || ':' || role AS m FROM osm.members WHERE relation_id = 20")
+            .unwrap().to_string();
+        assert!(members.contains("way10:outer"), "{}", members);
+        assert_eq!(db.replication_state("osm").unwrap(), Some((updates_url, 3)));
+
+        // Nothing new: a second run is a no-op.
+        let summary = update_table(&db, &downloader, "osm").await.unwrap();
+        assert_eq!((summary.from_sequence, summary.to_sequence), (3, 3));
+    }
+}
@@ -0,0 +1,259 @@
+use std::ops::Range;
+
+use crate::results::{Cell, Column, QueryResult};
+
+/// Rows fetched at a time; the result pane never holds more than one page.
+pub const PAGE_SIZE: usize = 500;
+const MIN_WIDTH: u16 = 3;
+const MAX_WIDTH: u16 = 200;
+/// Columns start no wider than this; `>` widens them further.
+const INITIAL_MAX_WIDTH: u16 = 40;
+
+/// State of the result table in the Database tab. Only the page of rows around
+/// the cursor is kept; when the cursor leaves it, `wanted_page` says which rows
+/// to fetch next.
+pub struct ResultView {
+    pub sql: String,
+    page: QueryResult,
+    /// Rows of the whole result. Statements that can't be paged are fetched in one
+    /// go, so for them this is the length of the page.
+    total: usize,
+    pageable: bool,
+    /// Cursor, as a row of the whole result and a column index.
+    pub row: usize,
+    pub column: usize,
+    /// First row and column on screen and the number of rows that fit, updated
+    /// by `scroll_to_cursor`.
+    pub first_row: usize,
+    pub first_column: usize,
+    screen_rows: usize,
+    widths: Vec<u16>,
+    /// Whether the selected cell is shown in full in a popup.
+    pub inspecting: bool,
+    /// Whether the page wanted by the cursor is being fetched.
+    pub loading: bool,
+}
+
+impl ResultView {
+    /// `total` is the row count of a pageable statement, `None` if `page` holds all rows.
+    pub fn new(sql: String, page: QueryResult, total: Option<usize>) -> Self {
+        let widths = page
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let longest = page
+                    .rows
+                    .iter()
+                    .map(|row| row[i].to_string().chars().count())
+                    .chain([column.name.chars().count(), column.type_name.chars().count()])
+                    .max()
+                    .unwrap_or(0);
+                (longest as u16).clamp(MIN_WIDTH, INITIAL_MAX_WIDTH)
+            })
+            .collect();
+        Self {
+            sql,
+            total: total.unwrap_or(page.rows.len()),
+            pageable: total.is_some(),
+            page,
+            row: 0,
+            column: 0,
+            first_row: 0,
+            first_column: 0,
+            screen_rows: 1,
+            widths,
+            inspecting: false,
+            loading: false,
+        }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.page.columns
+    }
+
+    pub fn total_rows(&self) -> usize {
+        self.total
+    }
+
+    pub fn width(&self, column: usize) -> u16 {
+        self.widths[column]
+    }
+
+    /// The row at `row` of the whole result, if it is in the current page.
+    pub fn row_cells(&self, row: usize) -> Option<&[Cell]> {
+        let index = row.checked_sub(self.page.offset)?;
+        self.page.rows.get(index).map(Vec::as_slice)
+    }
+
+    pub fn selected_cell(&self) -> Option<(&Column, &Cell)> {
+        let cell = self.row_cells(self.row)?.get(self.column)?;
+        Some((&self.page.columns[self.column], cell))
+    }
+
+    /// Offset of the page to fetch when the cursor has left the current one,
+    /// chosen so the cursor ends up in its middle.
+    pub fn wanted_page(&self) -> Option<usize> {
+        let loaded = self.page.offset..self.page.offset + self.page.rows.len();
+        (self.pageable && self.total > 0 && !loaded.contains(&self.row))
+            .then(|| self.row.saturating_sub(PAGE_SIZE / 2))
+    }
+
+    pub fn set_page(&mut self, page: QueryResult) {
+        self.page = page;
+    }
+
+    pub fn elapsed_ms(&self) -> f64 {
+        self.page.elapsed.as_secs_f64() * 1000.0
+    }
+
+    pub fn move_up(&mut self, rows: usize) {
+        self.row = self.row.saturating_sub(rows);
+    }
+
+    pub fn move_down(&mut self, rows: usize) {
+        self.row = (self.row + rows).min(self.total.saturating_sub(1));
+    }
+
+    pub fn page_up(&mut self) {
+        self.move_up(self.screen_rows);
+    }
+
+    pub fn page_down(&mut self) {
+        self.move_down(self.screen_rows);
+    }
+
+    pub fn home(&mut self) {
+        self.row = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.row = self.total.saturating_sub(1);
+    }
+
+    pub fn move_left(&mut self) {
+        self.column = self.column.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        if self.column + 1 < self.widths.len() {
+            self.column += 1;
+        }
+    }
+
+    /// Grows (positive `by`) or shrinks the selected column.
+    pub fn resize(&mut self, by: i16) {
+        if let Some(width) = self.widths.get_mut(self.column) {
+            *width = width.saturating_add_signed(by).clamp(MIN_WIDTH, MAX_WIDTH);
+        }
+    }
+
+    /// Scrolls just enough for the cursor to be on a screen of `height` rows and
+    /// `width` columns.
+    pub fn scroll_to_cursor(&mut self, height: usize, width: u16) {
+        let height = height.max(1);
+        self.screen_rows = height;
+        if self.row < self.first_row {
+            self.first_row = self.row;
+        } else if self.row >= self.first_row + height {
+            self.first_row = self.row + 1 - height;
+        }
+        if self.column < self.first_column {
+            self.first_column = self.column;
+        }
+        while self.first_column < self.column && self.visible_columns(width).end <= self.column {
+            self.first_column += 1;
+        }
+    }
+
+    /// Columns that fit in `width` from `first_column` on, with a space between
+    /// them; always at least one so very wide columns are still shown, cut off.
+    pub fn visible_columns(&self, width: u16) -> Range<usize> {
+        let mut used = 0;
+        let mut end = self.first_column;
+        for column_width in &self.widths[self.first_column.min(self.widths.len())..] {
+            if end > self.first_column && used + column_width > width {
+                break;
+            }
+            used += column_width + 1;
+            end += 1;
+        }
+        self.first_column..end
+    }
+
+    pub fn visible_rows(&self, height: usize) -> Range<usize> {
+        self.first_row..(self.first_row + height).min(self.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn page(offset: usize, rows: usize) -> QueryResult {
+        QueryResult {
+            columns: ["id", "name", "tags"]
+                .iter()
+                .map(|name| Column {
+                    name: name.to_string(),
+                    type_name: "VARCHAR".to_string(),
+                })
+                .collect(),
+            rows: (offset..offset + rows)
+                .map(|i| vec![Cell::Int(i as i128), Cell::Text(format!("row {}", i)), Cell::Text("x".repeat(60))])
+                .collect(),
+            offset,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_pages_follow_the_cursor() {
+        let mut view = ResultView::new("SELECT".to_string(), page(0, PAGE_SIZE), Some(1200));
+        view.move_down(PAGE_SIZE - 1);
+        assert_eq!(view.wanted_page(), None);
+        view.move_down(1);
+        assert_eq!(view.wanted_page(), Some(PAGE_SIZE - PAGE_SIZE / 2));
+        assert!(view.selected_cell().is_none());
+
+        view.set_page(page(250, PAGE_SIZE));
+        assert_eq!(view.selected_cell().unwrap().1, &Cell::Int(500));
+        view.end();
+        assert_eq!(view.row, 1199);
+        assert_eq!(view.wanted_page(), Some(949));
+
+        // Everything of an unpageable statement is in its only page
+        let mut view = ResultView::new("PRAGMA".to_string(), page(0, 3), None);
+        view.move_down(10);
+        assert_eq!((view.row, view.total_rows(), view.wanted_page()), (2, 3, None));
+    }
+
+    #[test]
+    fn test_scroll_and_resize() {
+        let mut view = ResultView::new("SELECT".to_string(), page(0, 100), Some(100));
+        assert_eq!((view.width(0), view.width(1), view.width(2)), (7, 7, 40));
+        assert_eq!(view.visible_columns(20), 0..2);
+
+        view.move_right();
+        view.move_right();
+        view.scroll_to_cursor(10, 20);
+        assert_eq!(view.visible_columns(20), 2..3);
+
+        view.move_left();
+        view.scroll_to_cursor(10, 20);
+        assert_eq!(view.first_column, 1);
+
+        view.resize(-10);
+        assert_eq!(view.width(1), MIN_WIDTH);
+        view.resize(3);
+        assert_eq!(view.width(1), MIN_WIDTH + 3);
+
+        view.move_down(25);
+        view.scroll_to_cursor(10, 20);
+        assert_eq!(view.visible_rows(10), 16..26);
+        view.move_up(20);
+        view.scroll_to_cursor(10, 20);
+        assert_eq!(view.visible_rows(10), 5..15);
+    }
+}
@@ -0,0 +1,163 @@
+use duckdb::types::Value;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// DuckDB type name, e.g. `BIGINT` or `GEOMETRY`.
+    pub type_name: String,
+}
+
+/// A value of a query result, reduced to what the result views need to tell
+/// apart: numbers are right-aligned, everything else is shown as text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Text(String),
+}
+
+impl Cell {
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Cell::Int(_) | Cell::Float(_))
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cell::Null => write!(f, "NULL"),
+            Cell::Bool(b) => write!(f, "{}", b),
+            Cell::Int(i) => write!(f, "{}", i),
+            Cell::Float(x) => write!(f, "{}", x),
+            Cell::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<Value> for Cell {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Cell::Null,
+            Value::Boolean(b) => Cell::Bool(b),
+            Value::TinyInt(i) => Cell::Int(i.into()),
+            Value::SmallInt(i) => Cell::Int(i.into()),
+            Value::Int(i) => Cell::Int(i.into()),
+            Value::BigInt(i) => Cell::Int(i.into()),
+            Value::HugeInt(i) => Cell::Int(i),
+            Value::UTinyInt(i) => Cell::Int(i.into()),
+            Value::USmallInt(i) => Cell::Int(i.into()),
+            Value::UInt(i) => Cell::Int(i.into()),
+            Value::UBigInt(i) => Cell::Int(i.into()),
+            Value::Float(x) => Cell::Float(x.into()),
+            Value::Double(x) => Cell::Float(x),
+            other => Cell::Text(text(&other)),
+        }
+    }
+}
+
+/// How DuckDB's own CLI would print a value that isn't a plain number.
+fn text(value: &Value) -> String {
+    let list = |values: &[Value]| values.iter().map(text).collect::<Vec<_>>().join(", ");
+    match value {
+        Value::Text(s) | Value::Enum(s) => s.clone(),
+        Value::Decimal(d) => d.to_string(),
+        Value::Timestamp(unit, v) => chrono::DateTime::from_timestamp_micros(unit.to_micros(*v))
+            .map_or_else(|| v.to_string(), |t| t.naive_utc().to_string()),
+        Value::Date32(days) => chrono::NaiveDate::from_num_days_from_ce_opt(719_163 + days)
+            .map_or_else(|| days.to_string(), |d| d.to_string()),
+        Value::Time64(unit, v) => {
+            let micros = unit.to_micros(*v);
+            chrono::NaiveTime::from_num_seconds_from_midnight_opt(
+                (micros / 1_000_000) as u32,
+                (micros % 1_000_000) as u32 * 1000,
+            )
+            .map_or_else(|| v.to_string(), |t| t.to_string())
+        }
+        Value::Interval { months, days, nanos } => {
+            format!("{} months {} days {}s", months, days, *nanos as f64 / 1e9)
+        }
+        Value::Blob(bytes) => format!("<{} bytes>", bytes.len()),
+        Value::List(values) | Value::Array(values) => format!("[{}]", list(values)),
+        Value::Map(map) => {
+            let entries: Vec<String> = map.iter().map(|(k, v)| format!("{}={}", text(k), text(v))).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Value::Struct(fields) => {
+            let entries: Vec<String> = fields.iter().map(|(k, v)| format!("'{}': {}", k, text(v))).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Value::Union(value) => text(value),
+        Value::Null => "NULL".to_string(),
+        number => Cell::from(number.clone()).to_string(),
+    }
+}
+
+/// Rows of a query, or of one page of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<Cell>>,
+    /// Position of the first of `rows` in the whole result.
+    pub offset: usize,
+    pub elapsed: Duration,
+}
+
+/// A plain text table, as printed by the `query` command.
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.to_string().replace('\n', " ")).collect())
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([column.name.chars().count()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let line = |cells: Vec<String>| cells.join(" | ").trim_end().to_string();
+        let header = self
+            .columns
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| format!("{:width$}", column.name, width = width))
+            .collect();
+        writeln!(f, "{}", line(header))?;
+        let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+        writeln!(f, "{}", rule.join("-+-"))?;
+        for (row, values) in self.rows.iter().zip(&rows) {
+            let cells = row
+                .iter()
+                .zip(values)
+                .zip(&widths)
+                .map(|((cell, value), width)| {
+                    if cell.is_numeric() {
+                        format!("{:>width$}", value, width = width)
+                    } else {
+                        format!("{:width$}", value, width = width)
+                    }
+                })
+                .collect();
+            writeln!(f, "{}", line(cells))?;
+        }
+        writeln!(
+            f,
+            "Rows: {} | Columns: {} | Time: {:.2} ms",
+            self.rows.len(),
+            self.columns.len(),
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
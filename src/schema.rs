@@ -0,0 +1,246 @@
+use anyhow::{bail, Context, Result};
+use duckdb::Connection;
+use tracing::info;
+
+/// A step from one version of the app's own tables to the next.
+struct Migration {
+    description: &'static str,
+    sql: &'static str,
+}
+
+/// Every schema the app has had, oldest first; migration `i` takes a database to
+/// version `i + 1`. Released migrations must never change, only new ones be added.
+/// Databases from before `schema_version` existed are at some unknown version, so
+/// the migrations up to 5 only create what isn't there yet.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "downloads",
+        sql: "CREATE TABLE IF NOT EXISTS downloads (
+                id INTEGER PRIMARY KEY,
+                url VARCHAR,
+                local_path VARCHAR,
+                downloaded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+              );",
+    },
+    Migration {
+        description: "MD5 of downloads",
+        sql: "ALTER TABLE downloads ADD COLUMN IF NOT EXISTS md5 VARCHAR;",
+    },
+    Migration {
+        description: "replication state of imported tables",
+        sql: "CREATE TABLE IF NOT EXISTS replication (
+                table_name VARCHAR PRIMARY KEY,
+                updates_url VARCHAR,
+                sequence BIGINT,
+                state_timestamp VARCHAR,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+              );",
+    },
+    Migration {
+        description: "registry of imported tables",
+        sql: "CREATE TABLE IF NOT EXISTS datasets (
+                table_name VARCHAR PRIMARY KEY,
+                download_id INTEGER,
+                source_path VARCHAR,
+                imported_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+              );",
+    },
+    Migration {
+        description: "provenance of downloads",
+        sql: "ALTER TABLE downloads ADD COLUMN IF NOT EXISTS region VARCHAR;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS format VARCHAR;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS size_bytes BIGINT;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS md5_verified BOOLEAN;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS etag VARCHAR;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS last_modified VARCHAR;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS osm_timestamp TIMESTAMP;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS replication_sequence BIGINT;
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS duration_ms BIGINT;
+              -- Rows from before outcomes were recorded are all successful downloads
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS outcome VARCHAR DEFAULT 'success';
+              ALTER TABLE downloads ADD COLUMN IF NOT EXISTS error VARCHAR;",
+    },
+    Migration {
+        description: "tracked regions",
+        sql: "CREATE TABLE tracked_regions (
+                region VARCHAR,
+                format VARCHAR,
+                url VARCHAR,
+                table_name VARCHAR,
+                interval_hours INTEGER,
+                tracked_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                last_checked TIMESTAMP,
+                PRIMARY KEY (region, format)
+              );",
+    },
+];
+
+/// Version of the tables this build of the app creates.
+pub const VERSION: usize = MIGRATIONS.len();
+
+/// Brings the app's own tables up to `VERSION` and returns the version the
+/// database was at. Each migration runs in a transaction of its own, so an
+/// interrupted upgrade resumes from the last one that finished.
+pub fn migrate(conn: &mut Connection) -> Result<usize> {
+    migrate_to(conn, VERSION)
+}
+
+fn migrate_to(conn: &mut Connection, target: usize) -> Result<usize> {
+    // One row per applied migration, so the table doubles as a history
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description VARCHAR,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+    let current = version(conn)?;
+    if current > VERSION {
+        bail!(
+            "The database is at schema version {}, but this version of osm-downloader only knows up to {}; \
+             please upgrade it",
+            current,
+            VERSION
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().take(target).skip(current) {
+        let version = i + 1;
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .with_context(|| format!("Migration to schema version {} ({}) failed", version, migration.description))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?, ?)",
+            duckdb::params![version as i64, migration.description],
+        )?;
+        tx.commit()?;
+        info!("Database migrated to schema version {}: {}", version, migration.description);
+    }
+    Ok(current)
+}
+
+/// Schema version of the database, 0 if it predates versioning or is new.
+fn version(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, DownloadRecord};
+    use std::path::Path;
+
+    /// What `Database::new` created before the schema was versioned, after the
+    /// versions 1 to 4 of the app's tables.
+    const UNVERSIONED: [&str; 4] = [
+        "CREATE TABLE IF NOT EXISTS downloads (
+            id INTEGER PRIMARY KEY,
+            url VARCHAR,
+            local_path VARCHAR,
+            downloaded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO downloads (id, url, local_path) VALUES (1, 'manual_download', '/data/monaco-latest.osm.pbf');",
+        "ALTER TABLE downloads ADD COLUMN IF NOT EXISTS md5 VARCHAR;
+        INSERT INTO downloads (id, url, local_path, md5)
+        VALUES (2, 'https://download.geofabrik.de/europe/andorra-latest.osm.pbf', '/data/andorra-latest.osm.pbf',
+                'd41d8cd98f00b204e9800998ecf8427e');",
+        "CREATE TABLE IF NOT EXISTS replication (
+            table_name VARCHAR PRIMARY KEY,
+            updates_url VARCHAR,
+            sequence BIGINT,
+            state_timestamp VARCHAR,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO replication (table_name, updates_url, sequence)
+        VALUES ('europe_andorra_pbf', 'https://download.geofabrik.de/europe/andorra-updates', 4321);",
+        "CREATE TABLE IF NOT EXISTS datasets (
+            table_name VARCHAR PRIMARY KEY,
+            download_id INTEGER,
+            source_path VARCHAR,
+            imported_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO datasets (table_name, download_id, source_path)
+        VALUES ('europe_andorra_pbf', 2, '/data/andorra-latest.osm.pbf');",
+    ];
+
+    /// A database file as left by an app whose tables were at `version`.
+    fn unversioned_fixture(dir: &Path, version: usize) -> std::path::PathBuf {
+        let path = dir.join(format!("v{}.duckdb", version));
+        let conn = Connection::open(&path).unwrap();
+        for sql in &UNVERSIONED[..version] {
+            conn.execute_batch(sql).unwrap();
+        }
+        path
+    }
+
+    fn schema_version(db: &Database) -> String {
+        db.query("SELECT MAX(version) FROM schema_version").unwrap().rows[0][0].to_string()
+    }
+
+    fn columns(db: &Database, table: &str) -> Vec<String> {
+        let sql = format!(
+            "SELECT column_name FROM information_schema.columns WHERE table_name = '{}' ORDER BY ordinal_position",
+            table
+        );
+        db.query(&sql).unwrap().rows.iter().map(|row| row[0].to_string()).collect()
+    }
+
+    #[test]
+    fn test_upgrade_unversioned_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        for old in 1..=UNVERSIONED.len() {
+            let path = unversioned_fixture(dir.path(), old);
+            let db = Database::new(&path).unwrap();
+            assert_eq!(schema_version(&db), VERSION.to_string(), "upgrading from version {}", old);
+            assert_eq!(columns(&db, "downloads").len(), 16);
+            assert_eq!(columns(&db, "datasets"), ["table_name", "download_id", "source_path", "imported_at"]);
+
+            // Old rows are kept and count as successful downloads
+            let rows = db.query("SELECT id, url, outcome FROM downloads ORDER BY id").unwrap().rows;
+            assert_eq!(rows.len(), old.min(2));
+            assert_eq!(rows[0][1].to_string(), "manual_download");
+            assert!(rows.iter().all(|row| row[2].to_string() == "success"));
+            if old >= 3 {
+                assert_eq!(db.replication_state("europe_andorra_pbf").unwrap().map(|(_, sequence)| sequence), Some(4321));
+            }
+            let id = db.record_download(&DownloadRecord { url: "https://a/b".to_string(), ..Default::default() });
+            assert_eq!(id.unwrap(), old.min(2) as i64 + 1);
+        }
+    }
+
+    #[test]
+    fn test_migrations_run_once_and_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("versioned.duckdb");
+        let mut conn = Connection::open(&path).unwrap();
+        assert_eq!(migrate_to(&mut conn, 2).unwrap(), 0);
+        assert_eq!(version(&conn).unwrap(), 2);
+        conn.execute_batch("INSERT INTO downloads (id, url, md5) VALUES (1, 'https://a/b', 'abc')").unwrap();
+        drop(conn);
+
+        let db = Database::new(&path).unwrap();
+        assert_eq!(schema_version(&db), VERSION.to_string());
+        let history = db.query("SELECT version, description FROM schema_version ORDER BY version").unwrap().rows;
+        let history: Vec<String> = history.iter().map(|row| format!("{} {}", row[0], row[1])).collect();
+        assert_eq!(
+            history,
+            [
+                "1 downloads",
+                "2 MD5 of downloads",
+                "3 replication state of imported tables",
+                "4 registry of imported tables",
+                "5 provenance of downloads",
+                "6 tracked regions"
+            ]
+        );
+        drop(db);
+
+        // Reopening changes nothing; a database from a newer app is refused
+        let mut conn = Connection::open(&path).unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), VERSION);
+        conn.execute_batch(&format!("INSERT INTO schema_version (version) VALUES ({})", VERSION + 1))
+            .unwrap();
+        let error = migrate(&mut conn).unwrap_err();
+        assert!(error.to_string().contains("only knows up to"), "{}", error);
+    }
+}
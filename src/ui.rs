@@ -1,13 +1,23 @@
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, Gauge, Paragraph, Tabs, Wrap},
+    text::{Line, Span, Text},
+    widgets::{
+        Block, Borders, Cell as TableCell, Clear, Gauge, List, ListItem, ListState, Paragraph, Row, Table, TableState,
+        Tabs, Wrap,
+    },
     Frame,
 };
 
-use crate::app::{App, ActiveTab, FocusField};
+use crate::app::{App, ActiveTab, DatabaseFocus, FocusField};
+use crate::db::{ImportPhase, ImportProgress};
+use crate::files::Checksum;
 use crate::network::DownloadFormat;
+use crate::queue::{Job, JobState};
+use crate::result_view::ResultView;
+use crate::results::Cell;
+use crate::worker::WorkerStatus;
+use std::time::Duration;
 
 pub fn draw(f: &mut Frame, app: &mut App) {
     let chunks = Layout::default()
@@ -24,18 +34,25 @@ pub fn draw(f: &mut Frame, app: &mut App) {
     match app.active_tab {
         ActiveTab::Download => draw_download_tab(f, app, chunks[1]),
         ActiveTab::Database => draw_database_tab(f, app, chunks[1]),
+        ActiveTab::Files => draw_files_tab(f, app, chunks[1]),
     }
 
     draw_footer(f, app, chunks[2]);
 }
 
 fn draw_header_tabs(f: &mut Frame, app: &App, area: Rect) {
-    let titles = vec!["Download", "Database / Query"];
+    let titles = vec!["Download", "Database / Query", "Files"];
+    let title = if app.has_spatial() {
+        "OSM Downloader".to_string()
+    } else {
+        "OSM Downloader (spatial extension unavailable: no geometries or shapefile imports)".to_string()
+    };
     let tabs = Tabs::new(titles)
-        .block(Block::default().borders(Borders::ALL).title("OSM Downloader"))
+        .block(Block::default().borders(Borders::ALL).title(title))
         .select(match app.active_tab {
             ActiveTab::Download => 0,
             ActiveTab::Database => 1,
+            ActiveTab::Files => 2,
         })
         .style(Style::default().fg(Color::White))
         .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
@@ -43,6 +60,13 @@ fn draw_header_tabs(f: &mut Frame, app: &App, area: Rect) {
 }
 
 fn draw_download_tab(f: &mut Frame, app: &mut App, area: Rect) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(area);
+
+    draw_region_tree(f, app, columns[0]);
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -50,11 +74,12 @@ fn draw_download_tab(f: &mut Frame, app: &mut App, area: Rect) {
             Constraint::Length(3), // Country
             Constraint::Length(3), // Region
             Constraint::Length(3), // Format
-            Constraint::Length(3), // Progress
-            Constraint::Min(0),    // Instructions/Space
+            Constraint::Min(5),    // Queue
+            Constraint::Length(3), // Progress of the selected job
+            Constraint::Length(3), // Instructions
         ])
         .margin(1)
-        .split(area);
+        .split(columns[1]);
 
     // Inputs
     let active_style = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
@@ -91,28 +116,217 @@ fn draw_download_tab(f: &mut Frame, app: &mut App, area: Rect) {
     f.render_widget(&app.input_region, chunks[2]);
 
     // Format Selection
+    let shapefile = if app.has_spatial() { "Shapefile (.shp.zip)" } else { "Shapefile (.shp.zip, download only)" };
     let format_text = match app.download_format {
-        DownloadFormat::Pbf => "(*) OSM PBF (.osm.pbf)   ( ) Shapefile (.shp.zip)",
-        DownloadFormat::Shapefile => "( ) OSM PBF (.osm.pbf)   (*) Shapefile (.shp.zip)",
+        DownloadFormat::Pbf => format!("(*) OSM PBF (.osm.pbf)   ( ) {}", shapefile),
+        DownloadFormat::Shapefile => format!("( ) OSM PBF (.osm.pbf)   (*) {}", shapefile),
     };
     let format_p = Paragraph::new(format_text)
         .block(Block::default().borders(Borders::ALL).title("Format (Press Space to Toggle)"))
         .style(if app.focus_field == FocusField::Format { active_style } else { inactive_style });
     f.render_widget(format_p, chunks[3]);
 
-    // Progress Bar
-    let label = format!("{:.1}% - {}", app.download_progress, app.download_status_text);
+    // Queue
+    let items: Vec<ListItem> = app
+        .queue
+        .jobs()
+        .iter()
+        .map(|job| ListItem::new(job_line(job, app.tracked_region(job).is_some())))
+        .collect();
+    let queue_title = format!(
+        "Queue ({} active, max {})",
+        app.queue.active_count(),
+        app.queue.max_concurrent
+    );
+    let queue_list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(queue_title)
+                .style(if app.focus_field == FocusField::Queue { active_style } else { inactive_style }),
+        )
+        .highlight_style(Style::default().fg(Color::Black).bg(Color::Yellow));
+    let mut queue_state = ListState::default().with_selected(
+        (app.focus_field == FocusField::Queue && !app.queue.jobs().is_empty()).then_some(app.queue.selected),
+    );
+    f.render_stateful_widget(queue_list, chunks[4], &mut queue_state);
+
+    // Progress Bar of the selected job
+    let (ratio, label) = match app.queue.selected_job() {
+        Some(job @ Job { import: Some(import), .. }) => (
+            import.ratio(),
+            format!("#{} importing: {} - {} (c: Cancel)", job.id, import_details(import), import.phase.label()),
+        ),
+        Some(job) => {
+            let status = match &job.state {
+                JobState::Failed(e) => format!("failed: {}", e),
+                state => state.label().to_string(),
+            };
+            (job.progress.percent / 100.0, format!("#{} {:.1}% - {}", job.id, job.progress.percent, status))
+        }
+        None => (0.0, "Ready".to_string()),
+    };
     let gauge = Gauge::default()
         .block(Block::default().borders(Borders::ALL).title("Progress"))
         .gauge_style(Style::default().fg(Color::Green))
-        .ratio(app.download_progress / 100.0)
+        .ratio(ratio.clamp(0.0, 1.0))
         .label(label);
-    f.render_widget(gauge, chunks[4]);
+    f.render_widget(gauge, chunks[5]);
 
     // Help text
-    let help_text = "Tab: Switch Field | Enter: Download | Ctrl+b: Switch Tabs | q: Quit";
+    let help_text = "Tab: Switch Field | Enter: Queue Download | Ctrl+b: Switch Tabs | q: Quit (Format/Queue)\n\
+        Regions: ↑/↓ Move | →/← Expand/Collapse | Type to Filter | Esc: Clear Filter\n\
+        Queue: ↑/↓ Select | [/]: Reorder | p: Hold/Release | c: Cancel (also imports) | r: Retry | t: Track/Untrack | +/-: Concurrency";
     let help = Paragraph::new(help_text).style(Style::default().fg(Color::Gray));
-    f.render_widget(help, chunks[5]);
+    f.render_widget(help, chunks[6]);
+}
+
+/// One job in the queue; `tracked` marks regions that are refreshed in the background.
+fn job_line(job: &Job, tracked: bool) -> Line<'_> {
+    const BAR_WIDTH: usize = 10;
+    let title = format!("#{} {}{} ({:?}) ", job.id, job.name, if tracked { " ⟳" } else { "" }, job.format);
+    let filled = ((job.progress.percent / 100.0) * BAR_WIDTH as f64).round() as usize;
+    let bar = format!("[{}{}]", "#".repeat(filled.min(BAR_WIDTH)), ".".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)));
+    if let Some(import) = &job.import {
+        return Line::from(vec![
+            Span::raw(title),
+            Span::raw(format!("{} ", import_details(import))),
+            Span::styled(import.phase.label().to_string(), Style::default().fg(Color::Magenta)),
+        ]);
+    }
+    let mut details = format!("{:>5.1}%", job.progress.percent);
+    if matches!(job.state, JobState::Active) && job.progress.bytes_per_sec > 0.0 {
+        details.push_str(&format!(" {}/s", format_bytes(job.progress.bytes_per_sec as u64)));
+        if let Some(eta) = job.progress.eta() {
+            details.push_str(&format!(" ETA {}", format_duration(eta)));
+        }
+    }
+    let state_style = match job.state {
+        JobState::Completed => Style::default().fg(Color::Green),
+        JobState::Failed(_) => Style::default().fg(Color::Red),
+        JobState::Active | JobState::Verifying => Style::default().fg(Color::Cyan),
+        _ => Style::default().fg(Color::Gray),
+    };
+    Line::from(vec![
+        Span::raw(title),
+        Span::raw(bar),
+        Span::raw(format!(" {} ", details)),
+        Span::styled(job.state.label(), state_style),
+    ])
+}
+
+fn import_details(import: &ImportProgress) -> String {
+    match (import.phase, import.total) {
+        (ImportPhase::Reading, total) if total > 0 => format!("{:.1}%, {} rows", import.ratio() * 100.0, import.rows),
+        _ => format!("{} rows", import.rows),
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn draw_region_tree(f: &mut Frame, app: &App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),    // Tree
+            Constraint::Length(4), // Details of the highlighted region
+        ])
+        .margin(1)
+        .split(area);
+
+    let active_style = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+    let focused = app.focus_field == FocusField::Tree;
+    let title = if app.region_tree.filter.is_empty() {
+        "Regions".to_string()
+    } else {
+        format!("Regions (filter: {})", app.region_tree.filter)
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .style(if focused { active_style } else { Style::default() });
+
+    let Some(catalog) = &app.catalog else {
+        let p = Paragraph::new("Loading region index...").block(block);
+        f.render_widget(p, chunks[0]);
+        return;
+    };
+
+    let rows = app.region_tree.rows(catalog);
+    let items: Vec<ListItem> = rows
+        .iter()
+        .map(|row| {
+            let marker = match (row.has_children, row.expanded) {
+                (true, true) => "▾ ",
+                (true, false) => "▸ ",
+                (false, _) => "  ",
+            };
+            let style = if row.matched {
+                Style::default().fg(Color::Cyan)
+            } else {
+                Style::default().fg(Color::White)
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw("  ".repeat(row.depth)),
+                Span::raw(marker),
+                Span::styled(row.region.name.as_str(), style),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().fg(Color::Black).bg(Color::Yellow));
+    let mut state = ListState::default().with_selected(Some(app.region_tree.selected));
+    f.render_stateful_widget(list, chunks[0], &mut state);
+
+    // Formats and sizes of the highlighted region
+    let details = match rows.get(app.region_tree.selected) {
+        Some(row) => {
+            let format_line = |label: &str, url: &Option<String>| match url {
+                Some(url) => format!("{}: {}", label, match app.remote_sizes.get(url) {
+                    Some(Some(bytes)) => format_bytes(*bytes),
+                    _ => "…".to_string(),
+                }),
+                None => format!("{}: n/a", label),
+            };
+            format!(
+                "{} ({})\n{} | {}",
+                row.region.name,
+                row.region.id,
+                format_line("PBF", &row.region.urls.pbf),
+                format_line("Shapefile", &row.region.urls.shp),
+            )
+        }
+        None => "No matching regions.".to_string(),
+    };
+    let details = Paragraph::new(details)
+        .block(Block::default().borders(Borders::TOP))
+        .style(Style::default().fg(Color::Gray));
+    f.render_widget(details, chunks[1]);
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
 }
 
 fn draw_database_tab(f: &mut Frame, app: &mut App, area: Rect) {
@@ -121,19 +335,252 @@ fn draw_database_tab(f: &mut Frame, app: &mut App, area: Rect) {
         .constraints([
             Constraint::Length(10), // Input
             Constraint::Min(0),     // Output
+            Constraint::Length(1),  // Instructions
         ])
         .margin(1)
         .split(area);
 
     let active_style = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
-    app.sql_input.set_style(active_style);
-    app.sql_input.set_block(Block::default().borders(Borders::ALL).title("SQL Query (Press Ctrl+e or Ctrl+Shift+Enter to Execute)").style(active_style));
+    let inactive_style = Style::default().fg(Color::White);
+    let editing = app.database_focus == DatabaseFocus::Editor;
+    let input_style = if editing { active_style } else { inactive_style };
+    app.sql_input.set_style(input_style);
+    app.sql_input.set_block(Block::default().borders(Borders::ALL).title("SQL Query (Press Ctrl+e or Ctrl+Shift+Enter to Execute)").style(input_style));
     f.render_widget(&app.sql_input, chunks[0]);
 
-    let output = Paragraph::new(app.sql_output.as_str())
-        .block(Block::default().borders(Borders::ALL).title("Result"))
+    match app.result_view.as_mut() {
+        Some(view) => draw_result_table(f, view, chunks[1], !editing),
+        None => {
+            let title = match &app.db_status {
+                WorkerStatus { running: Some(task), queued } if app.pending_query.is_some() => {
+                    format!("Result (database busy: {}, {} queued)", task, queued)
+                }
+                _ => "Result".to_string(),
+            };
+            let output = Paragraph::new(app.sql_output.as_str())
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .wrap(Wrap { trim: false });
+            f.render_widget(output, chunks[1]);
+        }
+    }
+
+    let help_text = "Tab: Editor/Results | Ctrl+s: Export | Results: ↑/↓/PgUp/PgDn/Home/End Rows | ←/→ Columns | </>: Resize | Enter: Inspect | Esc: Back";
+    let help = Paragraph::new(help_text).style(Style::default().fg(Color::Gray));
+    f.render_widget(help, chunks[2]);
+
+    if let Some(view) = app.result_view.as_ref().filter(|v| v.inspecting) {
+        draw_cell_popup(f, view, chunks[1]);
+    }
+
+    if let Some(input) = app.export_input.as_mut() {
+        let popup = Rect {
+            x: chunks[1].x + chunks[1].width / 8,
+            y: chunks[1].y,
+            width: chunks[1].width * 3 / 4,
+            height: 3,
+        };
+        input.set_block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Export to (.csv, .parquet, .geojson, .fgb, .gpkg; Enter: Export, Esc: Cancel)")
+                .style(active_style),
+        );
+        f.render_widget(Clear, popup);
+        f.render_widget(&*input, popup);
+    }
+}
+
+fn draw_result_table(f: &mut Frame, view: &mut ResultView, area: Rect, focused: bool) {
+    let style = if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    let block = Block::default().borders(Borders::ALL).style(style);
+    let inner = block.inner(area);
+    // Two header lines: column names and types
+    let height = inner.height.saturating_sub(2) as usize;
+    view.scroll_to_cursor(height, inner.width);
+
+    let rows = view.visible_rows(height);
+    let title = if view.total_rows() == 0 {
+        format!("Result: no rows | {} columns | {:.2} ms", view.columns().len(), view.elapsed_ms())
+    } else {
+        format!(
+            "Result: row {} of {} | column {} of {} | {:.2} ms",
+            view.row + 1,
+            view.total_rows(),
+            view.column + 1,
+            view.columns().len(),
+            view.elapsed_ms()
+        )
+    };
+    let title = if view.loading { format!("{} | loading rows...", title) } else { title };
+    let columns = view.visible_columns(inner.width);
+
+    let header = Row::new(columns.clone().map(|i| {
+        let column = &view.columns()[i];
+        TableCell::from(Text::from(vec![
+            Line::styled(column.name.as_str(), Style::default().fg(Color::White).add_modifier(Modifier::BOLD)),
+            Line::styled(column.type_name.as_str(), Style::default().fg(Color::DarkGray)),
+        ]))
+    }))
+    .height(2);
+    let body: Vec<Row> = rows
+        .map(|r| match view.row_cells(r) {
+            Some(cells) => Row::new(columns.clone().map(|i| {
+                let value = cells[i].to_string().replace('\n', " ");
+                let line = if cells[i].is_numeric() {
+                    Line::from(value).right_aligned()
+                } else {
+                    Line::from(value)
+                };
+                let style = match (r == view.row, i == view.column) {
+                    (true, true) => Style::default().fg(Color::Black).bg(Color::Yellow),
+                    (true, false) => Style::default().fg(Color::White).bg(Color::DarkGray),
+                    _ if matches!(cells[i], Cell::Null) => Style::default().fg(Color::DarkGray),
+                    _ => Style::default().fg(Color::White),
+                };
+                TableCell::from(line).style(style)
+            })),
+            // Outside the loaded page while the next one is fetched
+            None => Row::new(vec![TableCell::from("…")]),
+        })
+        .collect();
+    let widths: Vec<Constraint> = columns.map(|i| Constraint::Length(view.width(i))).collect();
+    let table = Table::new(body, widths)
+        .header(header)
+        .column_spacing(1)
+        .block(block.title(title));
+    f.render_widget(table, area);
+}
+
+fn draw_files_tab(f: &mut Frame, app: &mut App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),    // Files
+            Constraint::Length(3), // Selected file
+            Constraint::Length(1), // Instructions
+        ])
+        .margin(1)
+        .split(area);
+
+    let list = &app.files;
+    let mut title = format!(
+        "Downloaded files in {} ({}, {})",
+        app.download_dir.display(),
+        list.files.len(),
+        format_bytes(list.total_size())
+    );
+    if list.loading {
+        title.push_str(" | loading...");
+    }
+    let header = Row::new(["File", "Region", "Format", "Size", "Downloaded", "Checksum", "Table"])
+        .style(Style::default().fg(Color::White).add_modifier(Modifier::BOLD));
+    let rows: Vec<Row> = list
+        .files
+        .iter()
+        .map(|file| {
+            let checksum_style = match file.checksum {
+                Checksum::Verified | Checksum::Intact => Style::default().fg(Color::Green),
+                Checksum::Corrupt => Style::default().fg(Color::Red),
+                _ => Style::default().fg(Color::Gray),
+            };
+            let downloaded = chrono::DateTime::<chrono::Local>::from(file.modified).format("%Y-%m-%d %H:%M");
+            Row::new(vec![
+                TableCell::from(file.name()),
+                TableCell::from(file.region().unwrap_or("-").to_string()),
+                TableCell::from(file.format().unwrap_or("-").to_string()),
+                TableCell::from(Line::from(format_bytes(file.size)).right_aligned()),
+                TableCell::from(downloaded.to_string()),
+                TableCell::from(file.checksum.label().to_string()).style(checksum_style),
+                TableCell::from(file.table.clone().unwrap_or_else(|| "-".to_string())),
+            ])
+        })
+        .collect();
+    let widths = [
+        Constraint::Min(24),
+        Constraint::Length(24),
+        Constraint::Length(6),
+        Constraint::Length(10),
+        Constraint::Length(16),
+        Constraint::Length(10),
+        Constraint::Min(16),
+    ];
+    let table = Table::new(rows, widths)
+        .header(header)
+        .column_spacing(1)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .row_highlight_style(Style::default().fg(Color::Black).bg(Color::Yellow));
+    let mut state = TableState::default().with_selected((!list.files.is_empty()).then_some(list.selected));
+    f.render_stateful_widget(table, chunks[0], &mut state);
+
+    let details = match list.selected_file() {
+        Some(file) => {
+            let source = match &file.download {
+                Some((_, record)) => record.url.clone(),
+                None => "no download recorded".to_string(),
+            };
+            format!("{}\nfrom {}", file.path.display(), source)
+        }
+        None => "No downloaded files.".to_string(),
+    };
+    let details = Paragraph::new(details)
+        .block(Block::default().borders(Borders::TOP))
+        .style(Style::default().fg(Color::Gray));
+    f.render_widget(details, chunks[1]);
+
+    let help_text = "↑/↓ Select | i: Re-import | v: Verify | d: Delete | o: Reveal | x: Clean up by age/quota | F5: Reload | q: Quit";
+    let help = Paragraph::new(help_text).style(Style::default().fg(Color::Gray));
+    f.render_widget(help, chunks[2]);
+
+    let popup = Rect {
+        x: chunks[0].x + chunks[0].width / 8,
+        y: chunks[0].y,
+        width: chunks[0].width * 3 / 4,
+        height: 3,
+    };
+    let active_style = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+    if let Some(input) = app.clean_input.as_mut() {
+        input.set_block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Delete files older than (30d) and/or beyond a quota (20G); Enter: Review, Esc: Cancel")
+                .style(active_style),
+        );
+        f.render_widget(Clear, popup);
+        f.render_widget(&*input, popup);
+    } else if let Some(pending) = &app.pending_deletion {
+        let question = Paragraph::new(pending.question.as_str())
+            .block(Block::default().borders(Borders::ALL).title("y: Delete, any other key: Keep").style(active_style));
+        f.render_widget(Clear, popup);
+        f.render_widget(question, popup);
+    }
+}
+
+/// The selected cell in full, over the result table.
+fn draw_cell_popup(f: &mut Frame, view: &ResultView, area: Rect) {
+    let Some((column, cell)) = view.selected_cell() else {
+        return;
+    };
+    let popup = Rect {
+        x: area.x + area.width / 8,
+        y: area.y + area.height / 8,
+        width: area.width * 3 / 4,
+        height: area.height * 3 / 4,
+    };
+    let text = Paragraph::new(cell.to_string())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("{} ({}), row {} (Esc/Enter: Close)", column.name, column.type_name, view.row + 1))
+                .style(Style::default().fg(Color::Yellow)),
+        )
+        .style(Style::default().fg(Color::White))
         .wrap(Wrap { trim: false });
-    f.render_widget(output, chunks[1]);
+    f.render_widget(Clear, popup);
+    f.render_widget(text, popup);
 }
 
 fn draw_footer(f: &mut Frame, app: &App, area: Rect) {
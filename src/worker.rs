@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Result};
+use duckdb::InterruptHandle;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::db::Database;
+
+struct Task {
+    id: u64,
+    label: String,
+    cancelled: Arc<AtomicBool>,
+    started: oneshot::Sender<()>,
+    work: Box<dyn FnOnce(&mut Database) + Send>,
+}
+
+#[derive(Default)]
+struct Shared {
+    running: Option<(u64, String)>,
+    queued: usize,
+}
+
+/// What the database worker is busy with, for the UI.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkerStatus {
+    /// Label of the running task, e.g. `import of europe/monaco`.
+    pub running: Option<String>,
+    /// Tasks waiting for it.
+    pub queued: usize,
+}
+
+/// Owns the database on a thread of its own and runs all the database work of the
+/// TUI there, one task at a time in the order it was submitted. Queries wait for
+/// a running import instead of failing, and a slow query doesn't freeze the UI.
+/// Clones are handles to the same worker, which stops once all are dropped.
+#[derive(Clone)]
+pub struct DbWorker {
+    tasks: mpsc::Sender<Task>,
+    shared: Arc<Mutex<Shared>>,
+    interrupt: Arc<InterruptHandle>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl DbWorker {
+    pub fn spawn(mut db: Database) -> Self {
+        let (tasks, queue) = mpsc::channel::<Task>();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let interrupt = db.interrupt_handle();
+        let worker_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("duckdb".to_string())
+            .spawn(move || {
+                for task in queue {
+                    {
+                        let mut shared = worker_shared.lock().unwrap();
+                        shared.queued -= 1;
+                        if task.cancelled.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        shared.running = Some((task.id, task.label));
+                    }
+                    let _ = task.started.send(());
+                    (task.work)(&mut db);
+                    worker_shared.lock().unwrap().running = None;
+                }
+            })
+            .expect("failed to start the database thread");
+        Self {
+            tasks,
+            shared,
+            interrupt,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Queues `work` behind everything submitted before it. The result comes back
+    /// through the returned request, which can also cancel it.
+    pub fn submit<R: Send + 'static>(
+        &self,
+        label: impl Into<String>,
+        work: impl FnOnce(&mut Database) -> R + Send + 'static,
+    ) -> Request<R> {
+        let (reply_tx, reply) = oneshot::channel();
+        let (started_tx, started) = oneshot::channel();
+        let handle = TaskHandle {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            worker: self.clone(),
+        };
+        let task = Task {
+            id: handle.id,
+            label: label.into(),
+            cancelled: handle.cancelled.clone(),
+            started: started_tx,
+            work: Box::new(move |db| {
+                let _ = reply_tx.send(work(db));
+            }),
+        };
+        self.shared.lock().unwrap().queued += 1;
+        if self.tasks.send(task).is_err() {
+            // The worker is gone; waiting for the request reports it
+            self.shared.lock().unwrap().queued -= 1;
+        }
+        Request { handle, started, reply }
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        let shared = self.shared.lock().unwrap();
+        WorkerStatus {
+            running: shared.running.as_ref().map(|(_, label)| label.clone()),
+            queued: shared.queued,
+        }
+    }
+
+    /// Interrupts whatever statement the worker runs, for `ImportControl`.
+    pub fn interrupt_handle(&self) -> Arc<InterruptHandle> {
+        self.interrupt.clone()
+    }
+}
+
+/// Cancels a submitted task: a queued one is skipped, a running one interrupted.
+#[derive(Clone)]
+pub struct TaskHandle {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    worker: DbWorker,
+}
+
+impl TaskHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Holding the lock keeps the worker from starting the next task, which the
+        // interrupt would otherwise hit.
+        let shared = self.worker.shared.lock().unwrap();
+        if matches!(shared.running, Some((id, _)) if id == self.id) {
+            self.worker.interrupt.interrupt();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a request returned no result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stopped {
+    Cancelled,
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stopped::Cancelled => write!(f, "Cancelled"),
+            Stopped::TimedOut(limit) => write!(f, "Interrupted after {} s", limit.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+/// A task submitted to the worker.
+pub struct Request<R> {
+    pub handle: TaskHandle,
+    started: oneshot::Receiver<()>,
+    reply: oneshot::Receiver<R>,
+}
+
+impl<R> Request<R> {
+    /// Waits for the result. `timeout` counts from when the task starts, not while
+    /// it waits in the queue; when it passes the task is interrupted. Cancelled
+    /// and interrupted tasks end in a `Stopped` error.
+    pub async fn wait(self, timeout: Option<Duration>) -> Result<R> {
+        let Request { handle, started, mut reply } = self;
+        let stopped = || anyhow!("The database worker stopped");
+        if started.await.is_err() {
+            return Err(if handle.is_cancelled() { Stopped::Cancelled.into() } else { stopped() });
+        }
+        let result = match timeout {
+            Some(limit) => match tokio::time::timeout(limit, &mut reply).await {
+                Ok(result) => result,
+                Err(_) => {
+                    handle.cancel();
+                    return Err(Stopped::TimedOut(limit).into());
+                }
+            },
+            None => reply.await,
+        };
+        let value = result.map_err(|_| stopped())?;
+        if handle.is_cancelled() {
+            return Err(Stopped::Cancelled.into());
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn worker() -> DbWorker {
+        DbWorker::spawn(Database::new(Path::new(":memory:")).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tasks_run_in_order_and_report_status() {
+        let worker = worker();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let first = worker.submit("import of monaco", move |db| {
+            blocked.recv().unwrap();
+            db.execute_batch("CREATE TABLE t AS SELECT 1 AS id").unwrap();
+        });
+        let second = worker.submit("query", |db| db.query("SELECT count(*) FROM t").map(|r| r.rows));
+        while worker.status().running.is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            worker.status(),
+            WorkerStatus { running: Some("import of monaco".to_string()), queued: 1 }
+        );
+
+        release.send(()).unwrap();
+        first.wait(None).await.unwrap();
+        let rows = second.wait(None).await.unwrap().unwrap();
+        assert_eq!(rows, vec![vec![crate::results::Cell::Int(1)]]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_timeout() {
+        let worker = worker();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let first = worker.submit("blocker", move |_| blocked.recv().unwrap());
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let queued = worker.submit("query", move |_| flag.store(true, Ordering::SeqCst));
+        queued.handle.cancel();
+        release.send(()).unwrap();
+        first.wait(None).await.unwrap();
+        let error = queued.wait(None).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<Stopped>(), Some(&Stopped::Cancelled));
+        assert!(!ran.load(Ordering::SeqCst), "cancelled tasks are skipped");
+
+        // A runaway query is interrupted and the worker carries on
+        let slow = worker.submit("query", |db| db.query("SELECT sum(i) FROM range(1000000000000) t(i)"));
+        let error = slow.wait(Some(Duration::from_millis(200))).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<Stopped>(), Some(&Stopped::TimedOut(Duration::from_millis(200))));
+        let next = worker.submit("query", |db| db.query("SELECT 42").map(|r| r.rows));
+        assert_eq!(next.wait(None).await.unwrap().unwrap(), vec![vec![crate::results::Cell::Int(42)]]);
+    }
+}